@@ -0,0 +1,189 @@
+//! Browsing the contents of snapshots, fetching only the trees and files being looked at.
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use shared::types::BlobHash;
+use tokio::sync::Mutex;
+
+use crate::{
+    backup::{
+        diff,
+        diff::DiffEntry,
+        filesystem::{packfile, Tree, TreeKind},
+        restore::SelectiveFetcher,
+        restore_orchestrator::{FolderUse, RestoreOrchestrator},
+        search,
+        search::{SearchMatch, SearchedSnapshot},
+        tree_source::CachedTrees,
+        RESTORE_ORCHESTRATOR,
+    },
+    log,
+    net_server::requests,
+    ui::ws_status_message::DirectoryEntry,
+};
+
+/// The data fetched while browsing is kept in the restore folder, so looking at the same
+/// directories again doesn't need another round of requests to peers.
+static SESSION: Mutex<Option<SelectiveFetcher>> = Mutex::const_new(None);
+
+/// Ends fetching when dropped. Holding it keeps restores and other users of the restore folder
+/// from starting, so the received packfiles stay in place while they are being read.
+pub struct FetchGuard;
+
+impl Drop for FetchGuard {
+    fn drop(&mut self) {
+        RESTORE_ORCHESTRATOR.get().unwrap().end_fetch();
+    }
+}
+
+/// List a directory inside a snapshot, the path is relative to the backup path.
+pub async fn list_directory(snapshot_hash: BlobHash, path: &Path) -> anyhow::Result<Vec<DirectoryEntry>> {
+    let mut session = SESSION.lock().await;
+    let fetcher = begin_fetch(&mut session).await?;
+
+    let result = async {
+        let tree = fetcher.resolve_path(snapshot_hash, path).await?;
+        if tree.kind != TreeKind::Dir {
+            bail!("{path:?} is not a directory");
+        }
+
+        fetcher.fetch_blobs(&tree.children).await?;
+
+        let mut entries = Vec::new();
+        for hash in &tree.children {
+            match fetcher.fetch_full_tree(hash).await {
+                Ok(child_tree) => entries.push(DirectoryEntry {
+                    name: child_tree.name,
+                    kind: child_tree.kind,
+                    size: child_tree.metadata.size,
+                    mtime: child_tree.metadata.mtime,
+                }),
+                Err(e) => log!("[browse] cannot fetch a tree in {:?}: {}", path, e),
+            }
+        }
+
+        // directories first, like in most file managers
+        entries.sort_by(|a, b| (a.kind != TreeKind::Dir, &a.name).cmp(&(b.kind != TreeKind::Dir, &b.name)));
+        Ok(entries)
+    }
+    .await;
+
+    RESTORE_ORCHESTRATOR.get().unwrap().end_fetch();
+    result
+}
+
+/// Fetch the contents of a single file inside a snapshot. Returns the tree of the file, and a
+/// packfile manager that has all of its chunks available as long as the guard is held.
+pub async fn open_file(
+    snapshot_hash: BlobHash,
+    path: &Path,
+) -> anyhow::Result<(Tree, packfile::Manager, FetchGuard)> {
+    let mut session = SESSION.lock().await;
+    let fetcher = begin_fetch(&mut session).await?;
+    let guard = FetchGuard;
+
+    let tree = async {
+        let tree = fetcher.resolve_path(snapshot_hash, path).await?;
+        if tree.kind != TreeKind::File {
+            bail!("{path:?} is not a file");
+        }
+
+        fetcher.fetch_blobs(&tree.children).await?;
+
+        // a partial file is of no use, check all data is there before sending it
+        let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+        for chunk_hash in &tree.children {
+            let received = match fetcher.packer.find_packfile(chunk_hash).await {
+                Some(packfile_id) => orchestrator.is_packfile_received(packfile_id).await,
+                None => false,
+            };
+
+            if !received {
+                bail!("the data of {path:?} could not be received from peers");
+            }
+        }
+
+        Ok(tree)
+    }
+    .await?;
+
+    Ok((tree, fetcher.packer.clone(), guard))
+}
+
+/// Compare two snapshots, fetching only the trees that differ between them.
+pub async fn diff_snapshots(
+    old_snapshot: BlobHash,
+    new_snapshot: BlobHash,
+) -> anyhow::Result<Vec<DiffEntry>> {
+    let mut session = SESSION.lock().await;
+    let fetcher = begin_fetch(&mut session).await?;
+
+    let result = diff::diff_trees(&mut CachedTrees::new(fetcher), old_snapshot, new_snapshot).await;
+
+    RESTORE_ORCHESTRATOR.get().unwrap().end_fetch();
+    result
+}
+
+/// Search all snapshots for files and directories whose name matches the glob pattern. Returns the
+/// matches and whether there were too many of them to return all.
+pub async fn search_snapshots(pattern: &str) -> anyhow::Result<(Vec<SearchMatch>, bool)> {
+    let mut snapshots: Vec<SearchedSnapshot> = requests::snapshot_list()
+        .await?
+        .into_iter()
+        .map(|s| SearchedSnapshot { hash: s.snapshot_hash, timestamp: s.timestamp })
+        .collect();
+    snapshots.sort_by_key(|s| -s.timestamp);
+
+    let mut session = SESSION.lock().await;
+    let fetcher = begin_fetch(&mut session).await?;
+
+    let result = search::search_trees(&mut CachedTrees::new(fetcher), &snapshots, pattern).await;
+
+    RESTORE_ORCHESTRATOR.get().unwrap().end_fetch();
+    result
+}
+
+/// Parse a hex encoded snapshot hash.
+pub fn parse_snapshot_hash(hash: &str) -> anyhow::Result<BlobHash> {
+    hex::decode(hash)?
+        .try_into()
+        .map_err(|_| anyhow!("invalid snapshot hash {hash}"))
+}
+
+/// Discard the data fetched while browsing, must be called before a restore or an export uses the
+/// restore folder.
+pub async fn reset() {
+    *SESSION.lock().await = None;
+}
+
+/// Mark the restore orchestrator as fetching, starting a new browsing session if there isn't one.
+/// Fetching has to be ended by the caller if this succeeds.
+async fn begin_fetch(session: &mut Option<SelectiveFetcher>) -> anyhow::Result<&mut SelectiveFetcher> {
+    let orchestrator = match session {
+        Some(_) => {
+            let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+            orchestrator.begin_fetch()?;
+            orchestrator
+        }
+        None => RestoreOrchestrator::start_in_restore_folder(FolderUse::Browse, "browsing snapshots").await?,
+    };
+
+    if session.is_none() {
+        // the index is loaded only once per session
+        let fetcher = async {
+            let peers = requests::backup_restore().await?.peers;
+            SelectiveFetcher::new(peers).await
+        };
+
+        match fetcher.await {
+            Ok(fetcher) => *session = Some(fetcher),
+            Err(e) => {
+                orchestrator.end_fetch();
+                return Err(e);
+            }
+        }
+    }
+
+    session.as_mut().ok_or(anyhow!("browsing session not started"))
+}
@@ -0,0 +1,391 @@
+//! Checks that all data referenced by snapshots is stored with peers and intact.
+//!
+//! A quick check walks every tree reachable from the snapshots and looks up every referenced blob
+//! in the index. A deep check also fetches all packfiles with file contents from peers, and
+//! decrypts, decompresses and verifies the hash of every blob.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Formatter},
+    io::ErrorKind,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail};
+use shared::types::{BlobHash, PackfileId};
+
+use crate::{
+    backup::{
+        filesystem::{Blob, BlobKind, Tree, TreeKind},
+        restore::SelectiveFetcher,
+        restore_orchestrator::{FolderUse, RestoreOrchestrator},
+        RESTORE_ORCHESTRATOR,
+    },
+    defaults::{CHECK_FETCH_BATCH_SIZE, CHECK_REPORT_LOG_LIMIT},
+    log,
+    net_server::requests,
+};
+
+/// What is wrong with a blob.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The blob is not in the index, it was never stored or the index is incomplete.
+    Missing,
+    /// The packfile containing the blob could not be received from any peer.
+    Unavailable,
+    /// The blob could not be read, or its contents don't match its hash.
+    Corrupt(String),
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing => write!(f, "missing from the index"),
+            Self::Unavailable => write!(f, "not available from any peer"),
+            Self::Corrupt(reason) => write!(f, "corrupt: {reason}"),
+        }
+    }
+}
+
+/// A file or directory in a snapshot that is affected by a damaged blob. For a missing tree, this
+/// is the directory containing it, as the name of the tree itself is not known.
+#[derive(Clone, Debug)]
+pub struct AffectedPath {
+    pub snapshot: BlobHash,
+    pub path: PathBuf,
+}
+
+/// A blob that is missing or damaged.
+#[derive(Clone, Debug)]
+pub struct BlobProblem {
+    pub hash: BlobHash,
+    pub kind: BlobKind,
+    pub problem: Problem,
+    pub affected: Vec<AffectedPath>,
+}
+
+/// The result of a repository check.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub deep: bool,
+    pub snapshots: usize,
+    /// Number of distinct trees read.
+    pub trees: usize,
+    /// Number of distinct file chunks referenced by the trees.
+    pub chunks: usize,
+    /// Number of file chunks that were read and verified, only done by a deep check.
+    pub verified_chunks: usize,
+    pub problems: Vec<BlobProblem>,
+}
+
+impl CheckReport {
+    /// Returns a short human readable summary of the check.
+    pub fn summary(&self) -> String {
+        let verified = if self.deep { format!(", {} verified", self.verified_chunks) } else { String::new() };
+        format!(
+            "checked {} snapshots with {} trees and {} file chunks{verified}, {} problems found",
+            self.snapshots,
+            self.trees,
+            self.chunks,
+            self.problems.len()
+        )
+    }
+}
+
+/// A place where a tree waiting to be checked is referenced from.
+struct Reference {
+    snapshot: BlobHash,
+    /// The path of the directory containing the tree, or of the directory itself for siblings.
+    path: PathBuf,
+    /// Whether the tree holds more children of the directory at `path`.
+    sibling: bool,
+}
+
+impl Reference {
+    fn affected(self) -> AffectedPath {
+        AffectedPath { snapshot: self.snapshot, path: self.path }
+    }
+}
+
+/// Check all snapshots known to the server, fetching the needed data from peers. The result is
+/// logged, along with the affected files of the first problems found.
+pub async fn run(deep: bool) -> anyhow::Result<CheckReport> {
+    let orchestrator =
+        RestoreOrchestrator::start_in_restore_folder(FolderUse::Task, "checking the repository").await?;
+
+    let result = run_check(deep).await;
+    RestoreOrchestrator::remove_restore_folder().await?;
+
+    match result {
+        Ok(report) => {
+            log_problems(&report);
+            orchestrator.set_finished(report.problems.is_empty(), format!("Check: {}.", report.summary()));
+            Ok(report)
+        }
+        Err(e) => {
+            orchestrator.set_finished(false, format!("Check failed: {e}"));
+            Err(anyhow!("check failed: {e}"))
+        }
+    }
+}
+
+async fn run_check(deep: bool) -> anyhow::Result<CheckReport> {
+    let snapshots: Vec<BlobHash> = requests::snapshot_list()
+        .await?
+        .into_iter()
+        .map(|s| s.snapshot_hash)
+        .collect();
+    if snapshots.is_empty() {
+        bail!("no snapshots found");
+    }
+
+    let peers = requests::backup_restore().await?.peers;
+    let mut checker = Checker {
+        fetcher: SelectiveFetcher::new(peers).await?,
+        report: CheckReport {
+            deep,
+            snapshots: snapshots.len(),
+            ..Default::default()
+        },
+        chunks: HashMap::new(),
+    };
+
+    log!("[check] checking the trees of {} snapshots", snapshots.len());
+    checker.check_trees(&snapshots).await?;
+
+    log!("[check] checking {} file chunks", checker.chunks.len());
+    checker.check_chunks().await?;
+
+    if !checker.fetcher.unavailable.is_empty() {
+        log!("[check] {} peers could not provide their data", checker.fetcher.unavailable.len());
+    }
+
+    Ok(checker.report)
+}
+
+struct Checker {
+    fetcher: SelectiveFetcher,
+    report: CheckReport,
+    /// File chunks referenced by the trees, along with the files containing them.
+    chunks: HashMap<BlobHash, Vec<AffectedPath>>,
+}
+
+impl Checker {
+    /// Walk the trees of all snapshots one level at a time. Shared trees are only fetched and counted
+    /// once, but they are walked for every reference, so that the affected paths are known in every
+    /// snapshot. The file chunks found are collected for checking later.
+    async fn check_trees(&mut self, snapshots: &[BlobHash]) -> anyhow::Result<()> {
+        let mut visited = HashSet::new();
+        let mut level: HashMap<BlobHash, Vec<Reference>> = HashMap::new();
+        for hash in snapshots {
+            level.entry(*hash).or_default().push(Reference {
+                snapshot: *hash,
+                path: PathBuf::new(),
+                sibling: false,
+            });
+        }
+
+        while !level.is_empty() {
+            // trees that are not in the index are reported below, there is nothing to fetch
+            let mut needed = Vec::new();
+            for hash in level.keys() {
+                if !visited.contains(hash) && self.fetcher.packer.find_packfile(hash).await.is_some() {
+                    needed.push(*hash);
+                }
+            }
+            self.fetcher.fetch_blobs(&needed).await?;
+
+            let mut next_level = HashMap::new();
+            for (hash, references) in level {
+                let first_visit = visited.insert(hash);
+                match self.read_tree(&hash).await {
+                    Ok(tree) => {
+                        if first_visit {
+                            self.report.trees += 1;
+                        }
+                        self.walk_tree(&tree, references, &mut next_level);
+                    }
+                    Err(problem) => {
+                        let affected = references.into_iter().map(Reference::affected).collect();
+                        self.add_tree_problem(hash, problem, affected, first_visit);
+                    }
+                }
+            }
+
+            level = next_level;
+        }
+
+        Ok(())
+    }
+
+    /// Queue the subtrees of a tree for the next level and collect its file chunks, for every place
+    /// the tree is referenced from.
+    fn walk_tree(
+        &mut self,
+        tree: &Tree,
+        references: Vec<Reference>,
+        next_level: &mut HashMap<BlobHash, Vec<Reference>>,
+    ) {
+        for reference in references {
+            let path = if reference.sibling { reference.path } else { reference.path.join(&tree.name) };
+            if let Some(hash) = tree.next_sibling {
+                next_level.entry(hash).or_default().push(Reference {
+                    snapshot: reference.snapshot,
+                    path: path.clone(),
+                    sibling: true,
+                });
+            }
+
+            // all children of dir type tree are trees, all children of file type tree are chunks
+            for hash in &tree.children {
+                match tree.kind {
+                    TreeKind::Dir => next_level.entry(*hash).or_default().push(Reference {
+                        snapshot: reference.snapshot,
+                        path: path.clone(),
+                        sibling: false,
+                    }),
+                    TreeKind::File => self
+                        .chunks
+                        .entry(*hash)
+                        .or_default()
+                        .push(AffectedPath { snapshot: reference.snapshot, path: path.clone() }),
+                }
+            }
+        }
+    }
+
+    /// Look up all file chunks in the index. A deep check also fetches the packfiles containing
+    /// them in batches, verifies every chunk and deletes each batch before fetching the next one.
+    async fn check_chunks(&mut self) -> anyhow::Result<()> {
+        self.report.chunks = self.chunks.len();
+
+        let mut packfiles: HashMap<PackfileId, Vec<BlobHash>> = HashMap::new();
+        let mut missing = Vec::new();
+        for hash in self.chunks.keys() {
+            match self.fetcher.packer.find_packfile(hash).await {
+                Some(packfile_id) => packfiles.entry(packfile_id).or_default().push(*hash),
+                None => missing.push(*hash),
+            }
+        }
+
+        for hash in missing {
+            self.add_chunk_problem(hash, Problem::Missing);
+        }
+
+        if !self.report.deep {
+            return Ok(());
+        }
+
+        let packfiles: Vec<(PackfileId, Vec<BlobHash>)> = packfiles.into_iter().collect();
+        for (i, batch) in packfiles.chunks(CHECK_FETCH_BATCH_SIZE).enumerate() {
+            log!(
+                "[check] verifying packfiles {} to {} of {}",
+                i * CHECK_FETCH_BATCH_SIZE + 1,
+                i * CHECK_FETCH_BATCH_SIZE + batch.len(),
+                packfiles.len()
+            );
+
+            let blobs: Vec<BlobHash> = batch.iter().flat_map(|(_, hashes)| hashes.iter().copied()).collect();
+            self.fetcher.fetch_blobs(&blobs).await?;
+
+            for (packfile_id, hashes) in batch {
+                for hash in hashes {
+                    match self.read_blob(hash, BlobKind::FileChunk).await {
+                        Ok(_) => self.report.verified_chunks += 1,
+                        Err(problem) => self.add_chunk_problem(*hash, problem),
+                    }
+                }
+
+                let path = self.fetcher.packer.get_packfile_path(*packfile_id, false).await?;
+                match tokio::fs::remove_file(path).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read and verify a tree.
+    async fn read_tree(&mut self, hash: &BlobHash) -> Result<Tree, Problem> {
+        let blob = self.read_blob(hash, BlobKind::Tree).await?;
+        bincode::deserialize(&blob.data).map_err(|e| Problem::Corrupt(format!("invalid tree: {e}")))
+    }
+
+    /// Read a blob from a received or cached packfile, and verify its kind and hash.
+    async fn read_blob(&mut self, hash: &BlobHash, kind: BlobKind) -> Result<Blob, Problem> {
+        let packer = &mut self.fetcher.packer;
+        let Some(packfile_id) = packer.find_packfile(hash).await else {
+            return Err(Problem::Missing);
+        };
+
+        let received = RESTORE_ORCHESTRATOR
+            .get()
+            .unwrap()
+            .is_packfile_received(packfile_id)
+            .await;
+        if !received && !matches!(packer.get_cached_packfile_path(packfile_id).await, Ok(Some(_))) {
+            return Err(Problem::Unavailable);
+        }
+
+        let blob = match packer.get_blob(hash).await {
+            Ok(Some(blob)) => blob,
+            Ok(None) => return Err(Problem::Missing),
+            Err(e) => return Err(Problem::Corrupt(e.to_string())),
+        };
+
+        if blob.kind != kind {
+            return Err(Problem::Corrupt(format!("expected {kind:?}, found {:?}", blob.kind)));
+        }
+
+        if blake3::hash(&blob.data).as_bytes() != hash {
+            return Err(Problem::Corrupt(String::from("contents don't match the hash")));
+        }
+
+        Ok(blob)
+    }
+
+    /// Report a tree that can't be read. A tree referenced at different depths is reached again on a
+    /// later level, its problem is then only extended with the newly affected paths.
+    fn add_tree_problem(
+        &mut self,
+        hash: BlobHash,
+        problem: Problem,
+        affected: Vec<AffectedPath>,
+        first_visit: bool,
+    ) {
+        let existing =
+            if first_visit { None } else { self.report.problems.iter_mut().find(|p| p.hash == hash) };
+        match existing {
+            Some(existing) => existing.affected.extend(affected),
+            None => self.add_problem(hash, BlobKind::Tree, problem, affected),
+        }
+    }
+
+    fn add_chunk_problem(&mut self, hash: BlobHash, problem: Problem) {
+        let affected = self.chunks.get(&hash).cloned().unwrap_or_default();
+        self.add_problem(hash, BlobKind::FileChunk, problem, affected);
+    }
+
+    fn add_problem(&mut self, hash: BlobHash, kind: BlobKind, problem: Problem, affected: Vec<AffectedPath>) {
+        self.report
+            .problems
+            .push(BlobProblem { hash, kind, problem, affected });
+    }
+}
+
+/// Log the problems found by a check, along with the files they affect.
+fn log_problems(report: &CheckReport) {
+    for problem in report.problems.iter().take(CHECK_REPORT_LOG_LIMIT) {
+        log!("[check] {:?} {} is {}", problem.kind, hex::encode(problem.hash), problem.problem);
+        for affected in &problem.affected {
+            log!("[check]   affects {:?} in snapshot {}", affected.path, hex::encode(affected.snapshot));
+        }
+    }
+
+    if report.problems.len() > CHECK_REPORT_LOG_LIMIT {
+        log!("[check] ...and {} more problems", report.problems.len() - CHECK_REPORT_LOG_LIMIT);
+    }
+}
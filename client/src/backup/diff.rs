@@ -0,0 +1,260 @@
+//! Compares two snapshots, reporting the files and directories that were added, removed or modified.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use shared::types::BlobHash;
+
+use crate::backup::{
+    filesystem::{Tree, TreeKind},
+    tree_source::TreeSource,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum DiffChange {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// A single changed file or directory, directories are only reported when added or removed.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DiffEntry {
+    pub path: PathBuf,
+    pub change: DiffChange,
+    pub kind: TreeKind,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+}
+
+impl DiffEntry {
+    /// Returns the change of size in bytes, zero for directories.
+    pub fn size_delta(&self) -> i128 {
+        i128::from(self.new_size.unwrap_or_default()) - i128::from(self.old_size.unwrap_or_default())
+    }
+}
+
+/// A directory to compare, or to report all entries of as added or removed.
+enum Pending {
+    Compare { path: PathBuf, old: Vec<BlobHash>, new: Vec<BlobHash> },
+    Report { path: PathBuf, children: Vec<BlobHash>, change: DiffChange },
+}
+
+/// Compare two snapshots given by their root tree hashes. Trees are content addressed, so entries
+/// with the same hash in both snapshots are identical and are skipped without being fetched.
+pub async fn diff_trees(
+    source: &mut impl TreeSource,
+    old_root: BlobHash,
+    new_root: BlobHash,
+) -> anyhow::Result<Vec<DiffEntry>> {
+    if old_root == new_root {
+        return Ok(Vec::new());
+    }
+
+    source.prefetch(&[old_root, new_root]).await?;
+    let old_tree = source.tree(&old_root).await?;
+    let new_tree = source.tree(&new_root).await?;
+
+    let mut entries = Vec::new();
+    let mut level = vec![Pending::Compare {
+        path: PathBuf::new(),
+        old: old_tree.children,
+        new: new_tree.children,
+    }];
+
+    // the trees are walked one level at a time, so each level needs only one round of fetching
+    while !level.is_empty() {
+        let mut needed = Vec::new();
+        for pending in &mut level {
+            match pending {
+                Pending::Compare { old, new, .. } => {
+                    let common: HashSet<BlobHash> =
+                        old.iter().filter(|hash| new.contains(hash)).copied().collect();
+                    old.retain(|hash| !common.contains(hash));
+                    new.retain(|hash| !common.contains(hash));
+                    needed.extend(old.iter().chain(new.iter()).copied());
+                }
+                Pending::Report { children, .. } => needed.extend(children.iter().copied()),
+            }
+        }
+        source.prefetch(&needed).await?;
+
+        let mut next_level = Vec::new();
+        for pending in level {
+            match pending {
+                Pending::Compare { path, old, new } => {
+                    compare_dir(source, &path, &old, &new, &mut entries, &mut next_level).await?;
+                }
+                Pending::Report { path, children, change } => {
+                    for hash in &children {
+                        let tree = source.tree(hash).await?;
+                        report(&mut entries, &mut next_level, path.join(&tree.name), tree, change);
+                    }
+                }
+            }
+        }
+
+        level = next_level;
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Compare the differing children of a directory in both snapshots, matching them by name.
+async fn compare_dir(
+    source: &mut impl TreeSource,
+    path: &Path,
+    old: &[BlobHash],
+    new: &[BlobHash],
+    entries: &mut Vec<DiffEntry>,
+    next_level: &mut Vec<Pending>,
+) -> anyhow::Result<()> {
+    let mut old_trees = HashMap::new();
+    for hash in old {
+        let tree = source.tree(hash).await?;
+        old_trees.insert(tree.name.clone(), tree);
+    }
+
+    for hash in new {
+        let new_tree = source.tree(hash).await?;
+        let child_path = path.join(&new_tree.name);
+        match old_trees.remove(&new_tree.name) {
+            Some(old_tree) if old_tree.kind == TreeKind::Dir && new_tree.kind == TreeKind::Dir => {
+                next_level.push(Pending::Compare {
+                    path: child_path,
+                    old: old_tree.children,
+                    new: new_tree.children,
+                });
+            }
+            Some(old_tree) if old_tree.kind == TreeKind::File && new_tree.kind == TreeKind::File => {
+                entries.push(DiffEntry {
+                    path: child_path,
+                    change: DiffChange::Modified,
+                    kind: TreeKind::File,
+                    old_size: old_tree.metadata.size,
+                    new_size: new_tree.metadata.size,
+                });
+            }
+            // replaced by an entry of a different kind
+            Some(old_tree) => {
+                report(entries, next_level, child_path.clone(), old_tree, DiffChange::Removed);
+                report(entries, next_level, child_path, new_tree, DiffChange::Added);
+            }
+            None => report(entries, next_level, child_path, new_tree, DiffChange::Added),
+        }
+    }
+
+    for (name, old_tree) in old_trees {
+        report(entries, next_level, path.join(name), old_tree, DiffChange::Removed);
+    }
+
+    Ok(())
+}
+
+/// Report an added or removed entry, the contents of a directory are reported on the next level.
+fn report(
+    entries: &mut Vec<DiffEntry>,
+    next_level: &mut Vec<Pending>,
+    path: PathBuf,
+    tree: Tree,
+    change: DiffChange,
+) {
+    let size = match tree.kind {
+        TreeKind::File => tree.metadata.size,
+        TreeKind::Dir => None,
+    };
+    let (old_size, new_size) = if change == DiffChange::Added { (None, size) } else { (size, None) };
+
+    entries.push(DiffEntry {
+        path: path.clone(),
+        change,
+        kind: tree.kind,
+        old_size,
+        new_size,
+    });
+
+    if tree.kind == TreeKind::Dir {
+        next_level.push(Pending::Report { path, children: tree.children, change });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::filesystem::TreeMetadata;
+
+    /// Keeps trees in memory, recording which ones were requested.
+    #[derive(Default)]
+    struct MemorySource {
+        trees: HashMap<BlobHash, Tree>,
+        requested: HashSet<BlobHash>,
+    }
+
+    impl MemorySource {
+        fn add(
+            &mut self,
+            name: &str,
+            kind: TreeKind,
+            size: Option<u64>,
+            children: Vec<BlobHash>,
+        ) -> BlobHash {
+            let tree = Tree {
+                kind,
+                name: name.to_string(),
+                metadata: TreeMetadata { size, mtime: None, ctime: None },
+                children,
+                next_sibling: None,
+            };
+            let hash = *blake3::hash(&bincode::serialize(&tree).unwrap()).as_bytes();
+            self.trees.insert(hash, tree);
+            hash
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl TreeSource for MemorySource {
+        async fn prefetch(&mut self, hashes: &[BlobHash]) -> anyhow::Result<()> {
+            self.requested.extend(hashes.iter().copied());
+            Ok(())
+        }
+
+        async fn tree(&mut self, hash: &BlobHash) -> anyhow::Result<Tree> {
+            Ok(self.trees[hash].clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn diff_skips_identical_subtrees() {
+        let mut source = MemorySource::default();
+
+        let unchanged_file = source.add("a.txt", TreeKind::File, Some(10), vec![]);
+        let unchanged_dir = source.add("same", TreeKind::Dir, None, vec![unchanged_file]);
+        let old_file = source.add("b.txt", TreeKind::File, Some(100), vec![]);
+        let removed_file = source.add("c.txt", TreeKind::File, Some(5), vec![]);
+        let old_root = source.add("", TreeKind::Dir, None, vec![unchanged_dir, old_file, removed_file]);
+
+        let new_file = source.add("b.txt", TreeKind::File, Some(150), vec![]);
+        let added_file = source.add("d.txt", TreeKind::File, Some(7), vec![]);
+        let added_dir = source.add("new", TreeKind::Dir, None, vec![added_file]);
+        let new_root = source.add("", TreeKind::Dir, None, vec![unchanged_dir, new_file, added_dir]);
+
+        let entries = diff_trees(&mut source, old_root, new_root).await.unwrap();
+        let changes: Vec<(&str, DiffChange, i128)> = entries
+            .iter()
+            .map(|e| (e.path.to_str().unwrap(), e.change, e.size_delta()))
+            .collect();
+
+        assert_eq!(changes, vec![
+            ("b.txt", DiffChange::Modified, 50),
+            ("c.txt", DiffChange::Removed, -5),
+            ("new", DiffChange::Added, 0),
+            ("new/d.txt", DiffChange::Added, 7),
+        ]);
+        assert!(!source.requested.contains(&unchanged_dir));
+        assert!(diff_trees(&mut source, old_root, old_root).await.unwrap().is_empty());
+    }
+}
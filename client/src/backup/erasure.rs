@@ -0,0 +1,232 @@
+//! Splits packfiles into Reed-Solomon shards, so that each shard can be stored by a different peer
+//! and the packfile can be reconstructed from any `data_shards` of them.
+
+use std::{collections::HashSet, fs, io::ErrorKind, path::Path};
+
+use anyhow::{anyhow, bail};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+use shared::types::PackfileId;
+
+use crate::{
+    backup::filesystem::file_utils::{find_shards, get_packfile_path, parse_shard_path},
+    defaults::{MAX_ERASURE_CODING_SHARDS, SHARD_FOLDER},
+};
+
+/// The number of data and parity shards each packfile is split into.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct ErasureCoding {
+    pub data_shards: u8,
+    pub parity_shards: u8,
+}
+
+impl ErasureCoding {
+    /// The number of shards, and therefore distinct peers, needed to store a packfile.
+    pub fn total_shards(self) -> u8 {
+        self.data_shards + self.parity_shards
+    }
+
+    /// Checks that the shard counts are usable.
+    pub fn validate(self) -> anyhow::Result<()> {
+        if self.data_shards == 0 || self.parity_shards == 0 {
+            bail!("erasure coding needs at least one data shard and one parity shard");
+        }
+
+        if u16::from(self.data_shards) + u16::from(self.parity_shards) > u16::from(MAX_ERASURE_CODING_SHARDS)
+        {
+            bail!("erasure coding can use at most {MAX_ERASURE_CODING_SHARDS} shards in total");
+        }
+
+        Ok(())
+    }
+}
+
+/// Stored at the beginning of every shard, so a packfile can be reconstructed from the shards alone.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct ShardHeader {
+    data_shards: u8,
+    parity_shards: u8,
+    index: u8,
+    packfile_size: u64,
+}
+
+/// Split packfile data into data and parity shards, each prefixed with a header.
+pub fn encode(data: &[u8], coding: ErasureCoding) -> anyhow::Result<Vec<Vec<u8>>> {
+    coding.validate()?;
+    let codec = ReedSolomon::new(coding.data_shards.into(), coding.parity_shards.into())?;
+
+    // all shards need to have the same size, the last data shard is padded with zeroes
+    let shard_size = data.len().div_ceil(coding.data_shards.into()).max(1);
+    let mut shards: Vec<Vec<u8>> = (0..coding.total_shards())
+        .map(|i| {
+            let start = (shard_size * usize::from(i)).min(data.len());
+            let end = (start + shard_size).min(data.len());
+            let mut shard = data[start..end].to_vec();
+            shard.resize(shard_size, 0);
+            shard
+        })
+        .collect();
+
+    codec.encode(&mut shards)?;
+
+    shards
+        .into_iter()
+        .zip(0..)
+        .map(|(shard, index)| {
+            let header = ShardHeader {
+                data_shards: coding.data_shards,
+                parity_shards: coding.parity_shards,
+                index,
+                packfile_size: u64::try_from(data.len())?,
+            };
+
+            let mut result = bincode::serialize(&header)?;
+            result.extend(shard);
+            Ok(result)
+        })
+        .collect()
+}
+
+/// Reconstruct packfile data from shards produced by `encode`, in any order. At least `data_shards`
+/// distinct shards of the same packfile are needed.
+pub fn decode(shards: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+    let (first, _) = parse_shard(shards.first().ok_or(anyhow!("no shards to decode"))?)?;
+    let coding = ErasureCoding {
+        data_shards: first.data_shards,
+        parity_shards: first.parity_shards,
+    };
+    coding.validate()?;
+
+    let mut slots: Vec<Option<Vec<u8>>> = vec![None; coding.total_shards().into()];
+    for shard in shards {
+        let (header, data) = parse_shard(shard)?;
+        if header.data_shards != first.data_shards
+            || header.parity_shards != first.parity_shards
+            || header.packfile_size != first.packfile_size
+        {
+            bail!("shards belong to different packfiles");
+        }
+
+        *slots
+            .get_mut(usize::from(header.index))
+            .ok_or(anyhow!("invalid shard index {}", header.index))? = Some(data.to_vec());
+    }
+
+    let received = slots.iter().filter(|s| s.is_some()).count();
+    if received < coding.data_shards.into() {
+        bail!("only {received} of {} needed shards are available", coding.data_shards);
+    }
+
+    ReedSolomon::new(coding.data_shards.into(), coding.parity_shards.into())?.reconstruct_data(&mut slots)?;
+
+    let mut data: Vec<u8> = slots
+        .into_iter()
+        .take(coding.data_shards.into())
+        .flat_map(Option::unwrap_or_default)
+        .collect();
+    data.truncate(usize::try_from(first.packfile_size)?);
+
+    Ok(data)
+}
+
+/// Returns the number of shards needed to reconstruct the packfile a shard belongs to.
+pub fn shards_needed(shard: &[u8]) -> anyhow::Result<u8> {
+    Ok(parse_shard(shard)?.0.data_shards)
+}
+
+/// Split a shard into its header and data.
+fn parse_shard(shard: &[u8]) -> anyhow::Result<(ShardHeader, &[u8])> {
+    let header: ShardHeader = bincode::deserialize(shard)?;
+    let header_size = usize::try_from(bincode::serialized_size(&header)?)?;
+
+    Ok((header, &shard[header_size..]))
+}
+
+/// Try to reconstruct a packfile from the shards received into a folder. Once successful, the
+/// packfile is written next to the other packfiles and the shards are deleted. Returns whether the
+/// packfile is available.
+pub fn try_reconstruct(folder: &Path, id: PackfileId) -> anyhow::Result<bool> {
+    let packfile_path = get_packfile_path(folder, id, true)?;
+    if packfile_path.try_exists()? {
+        return Ok(true);
+    }
+
+    let shard_paths = find_shards(folder, id)?;
+    let shards = shard_paths
+        .iter()
+        .map(|(_, path)| fs::read(path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match shards.first() {
+        Some(shard) if shards.len() >= shards_needed(shard)?.into() => {}
+        _ => return Ok(false),
+    }
+
+    fs::write(&packfile_path, decode(&shards)?)?;
+
+    // another receiver could be reconstructing the same packfile at the same time
+    for (_, path) in shard_paths {
+        match fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Find the packfiles in a folder that have some shards, but could not be reconstructed yet.
+pub fn incomplete_packfiles(folder: &Path) -> anyhow::Result<HashSet<PackfileId>> {
+    let mut packfiles = HashSet::new();
+    let shard_folder = folder.join(SHARD_FOLDER);
+    if !shard_folder.try_exists()? {
+        return Ok(packfiles);
+    }
+
+    for entry in shard_folder.read_dir()? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            for shard in entry.path().read_dir()? {
+                packfiles.insert(parse_shard_path(&shard?.path())?.0);
+            }
+        }
+    }
+
+    Ok(packfiles)
+}
+
+/// Describe why a packfile isn't available in a folder, for error messages.
+pub fn describe_missing(folder: &Path, id: PackfileId) -> anyhow::Result<String> {
+    let shard_paths = find_shards(folder, id)?;
+    let needed = match shard_paths.first() {
+        Some((_, path)) => shards_needed(&fs::read(path)?)?,
+        None => return Ok(format!("packfile {} was not received", hex::encode(id))),
+    };
+
+    Ok(format!(
+        "packfile {} could not be reconstructed, received {} of {} needed shards",
+        hex::encode(id),
+        shard_paths.len(),
+        needed
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn erasure_coding_roundtrip() {
+        let data: Vec<u8> = (0..10_001u32).map(|i| u8::try_from(i % 251).unwrap()).collect();
+        let coding = ErasureCoding { data_shards: 3, parity_shards: 2 };
+
+        let shards = encode(&data, coding).unwrap();
+        assert_eq!(shards.len(), 5);
+
+        // any three shards are enough
+        assert_eq!(decode(&shards[2..]).unwrap(), data);
+        assert_eq!(decode(&[shards[4].clone(), shards[0].clone(), shards[3].clone()]).unwrap(), data);
+        assert!(decode(&shards[..2]).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! Exports a snapshot as a tar or zip archive, written to a file or to the standard output.
+
+use std::{
+    fs::File,
+    io,
+    io::{BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail};
+use cast::From;
+use human_bytes::human_bytes;
+use shared::{server_message::BackupRestoreInfo, types::BlobHash};
+use tokio::sync::mpsc::{self, Receiver};
+use zip::{write::FileOptions, CompressionMethod, DateTime, ZipWriter};
+
+use crate::{
+    backup::{
+        filesystem::{dir_unpacker::UnpackEvent, Tree},
+        restore::{fetch_snapshot, SelectiveFetcher},
+        restore_orchestrator::{FolderUse, RestoreOrchestrator},
+        RESTORE_ORCHESTRATOR,
+    },
+    defaults::EXPORT_QUEUE_SIZE,
+    log,
+    net_server::requests,
+    CONFIG,
+};
+
+/// Permissions of the exported files and directories, they are not stored in the snapshot.
+const FILE_MODE: u32 = 0o644;
+const DIR_MODE: u32 = 0o755;
+
+/// The archive format of an export.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    #[default]
+    Tar,
+    Zip,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tar" => Ok(Self::Tar),
+            "zip" => Ok(Self::Zip),
+            _ => bail!("unknown export format {s:?}, expected tar or zip"),
+        }
+    }
+}
+
+/// Options selecting what to export and where.
+#[derive(Clone, Debug, Default)]
+pub struct ExportOptions {
+    /// The snapshot to export, the latest one is used if not set.
+    pub snapshot: Option<BlobHash>,
+    /// A file or directory inside the snapshot to export, the whole snapshot is exported if not set.
+    pub path: Option<PathBuf>,
+    pub format: ExportFormat,
+    /// The file to write the archive to, the standard output is used if not set.
+    pub output: Option<PathBuf>,
+}
+
+/// A single item passed from the export to the archive writer. The contents of a file follow it as
+/// chunks, exactly of the file size in total.
+enum ArchiveItem {
+    Dir { path: PathBuf, mtime: u64 },
+    File { path: PathBuf, size: u64, mtime: u64 },
+    Chunk(Vec<u8>),
+}
+
+/// Fetch a snapshot from peers and write it as an archive, with the file sizes and modification
+/// times preserved.
+pub async fn export_snapshot(options: ExportOptions) -> anyhow::Result<()> {
+    let orchestrator = RestoreOrchestrator::start_in_restore_folder(FolderUse::Task, "exporting").await?;
+
+    let result = run_export(&options).await;
+    RestoreOrchestrator::remove_restore_folder().await?;
+
+    match &result {
+        Ok(()) => orchestrator.set_finished(true, "Export completed successfully!"),
+        Err(e) => orchestrator.set_finished(false, format!("Export failed: {e}")),
+    }
+
+    result
+}
+
+async fn run_export(options: &ExportOptions) -> anyhow::Result<()> {
+    let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+
+    let snapshot_path = match &options.path {
+        Some(path) if path.is_absolute() => {
+            bail!("export path {path:?} has to be relative to the backup path")
+        }
+        Some(path) if !path.as_os_str().is_empty() => Some(path.as_path()),
+        _ => None,
+    };
+
+    let BackupRestoreInfo { snapshot_hash: latest_snapshot, peers } = requests::backup_restore().await?;
+    let snapshot_hash = options.snapshot.unwrap_or(latest_snapshot);
+
+    // the trees and file contents are fetched the same way as for a restore, but all packfiles are
+    // kept until the archive is written
+    log!("[export] exporting snapshot {}", hex::encode(snapshot_hash));
+    let mut fetcher = SelectiveFetcher::new(peers).await?;
+    let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+    fetch_snapshot(&mut fetcher, &event_sender, snapshot_hash, snapshot_path).await?;
+    drop(event_sender);
+
+    let (item_sender, item_receiver) = mpsc::channel(EXPORT_QUEUE_SIZE);
+    let format = options.format;
+    let output = options.output.clone();
+    let writer = tokio::task::spawn_blocking(move || write_archive(format, output.as_deref(), item_receiver));
+
+    let mut packer = fetcher.packer;
+    let mut skipped = 0;
+    let mut exported_size = 0;
+    // the writer only stops on an error, which is returned below
+    'events: while let Some(event) = event_receiver.recv().await {
+        let item = match event {
+            UnpackEvent::Dir(path, tree) => ArchiveItem::Dir { path, mtime: tree_mtime(&tree) },
+            UnpackEvent::File(path, tree) => {
+                // the archive can't contain a partial file, check all data is there before writing it
+                let mut available = true;
+                for chunk_hash in &tree.children {
+                    available &= match packer.find_packfile(chunk_hash).await {
+                        Some(packfile_id) => orchestrator.is_packfile_received(packfile_id).await,
+                        None => false,
+                    };
+                }
+
+                if !available {
+                    log!("[export] cannot export {:?}, its data is missing", path);
+                    skipped += 1;
+                    continue;
+                }
+
+                let size = tree.metadata.size.unwrap_or_default();
+                exported_size += size;
+
+                let item = ArchiveItem::File { path, size, mtime: tree_mtime(&tree) };
+                if item_sender.send(item).await.is_err() {
+                    break;
+                }
+
+                for chunk_hash in &tree.children {
+                    let chunk = packer
+                        .get_blob(chunk_hash)
+                        .await?
+                        .ok_or(anyhow!("chunk {} was not found", hex::encode(chunk_hash)))?;
+                    if item_sender.send(ArchiveItem::Chunk(chunk.data)).await.is_err() {
+                        break 'events;
+                    }
+                }
+                continue;
+            }
+            UnpackEvent::Unrestored(entry) => {
+                log!("[export] cannot export {:?}, its data is missing", entry.path);
+                skipped += 1;
+                continue;
+            }
+            UnpackEvent::Packfile(_) | UnpackEvent::PlanComplete => continue,
+        };
+
+        if item_sender.send(item).await.is_err() {
+            break;
+        }
+    }
+
+    drop(item_sender);
+    writer.await??;
+
+    if skipped > 0 {
+        bail!("{skipped} files or directories could not be exported, as their data is missing");
+    }
+
+    log!("[export] exported {} of data", human_bytes(f64::cast(exported_size)));
+    Ok(())
+}
+
+/// Returns the modification time of a tree, in seconds since the epoch.
+fn tree_mtime(tree: &Tree) -> u64 {
+    tree.metadata.mtime.unwrap_or_default()
+}
+
+/// Write the received items into an archive of the given format. Runs on a blocking thread, as the
+/// archive libraries only support synchronous IO.
+fn write_archive(
+    format: ExportFormat,
+    output: Option<&Path>,
+    mut items: Receiver<ArchiveItem>,
+) -> anyhow::Result<()> {
+    match (format, output) {
+        (ExportFormat::Tar, Some(output)) => write_tar(BufWriter::new(File::create(output)?), &mut items),
+        (ExportFormat::Tar, None) => write_tar(BufWriter::new(io::stdout().lock()), &mut items),
+        (ExportFormat::Zip, Some(output)) => write_zip(BufWriter::new(File::create(output)?), &mut items),
+        (ExportFormat::Zip, None) => {
+            // zip archives are written with seeking back to the headers, so it has to be written into
+            // a temporary file first
+            let path = CONFIG
+                .get()
+                .unwrap()
+                .get_restored_packfiles_folder()?
+                .join("export.zip");
+            let mut file = File::options()
+                .create(true)
+                .truncate(true)
+                .read(true)
+                .write(true)
+                .open(&path)?;
+            write_zip(BufWriter::new(&mut file), &mut items)?;
+
+            file.rewind()?;
+            let mut stdout = io::stdout().lock();
+            io::copy(&mut file, &mut stdout)?;
+            stdout.flush()?;
+            Ok(())
+        }
+    }
+}
+
+fn write_tar(writer: impl Write, items: &mut Receiver<ArchiveItem>) -> anyhow::Result<()> {
+    let mut builder = tar::Builder::new(writer);
+
+    while let Some(item) = items.blocking_recv() {
+        let mut header = tar::Header::new_gnu();
+        match item {
+            ArchiveItem::Dir { path, mtime } => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(DIR_MODE);
+                header.set_mtime(mtime);
+                header.set_size(0);
+                builder.append_data(&mut header, path, io::empty())?;
+            }
+            ArchiveItem::File { path, size, mtime } => {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(FILE_MODE);
+                header.set_mtime(mtime);
+                header.set_size(size);
+                builder.append_data(&mut header, path, ChunkReader::new(items, size))?;
+            }
+            ArchiveItem::Chunk(_) => bail!("unexpected file contents in the export"),
+        }
+    }
+
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+fn write_zip(writer: impl Write + Seek, items: &mut Receiver<ArchiveItem>) -> anyhow::Result<()> {
+    let mut zip = ZipWriter::new(writer);
+
+    while let Some(item) = items.blocking_recv() {
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        match item {
+            ArchiveItem::Dir { path, mtime } => {
+                let options = options.unix_permissions(DIR_MODE).last_modified_time(zip_time(mtime));
+                zip.add_directory(zip_path(&path)?, options)?;
+            }
+            ArchiveItem::File { path, size, mtime } => {
+                let options = options
+                    .unix_permissions(FILE_MODE)
+                    .last_modified_time(zip_time(mtime))
+                    .large_file(size >= u64::from(u32::MAX));
+                zip.start_file(zip_path(&path)?, options)?;
+                io::copy(&mut ChunkReader::new(items, size), &mut zip)?;
+            }
+            ArchiveItem::Chunk(_) => bail!("unexpected file contents in the export"),
+        }
+    }
+
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+/// Returns the path of a zip entry, which always uses forward slashes.
+fn zip_path(path: &Path) -> anyhow::Result<String> {
+    let components: Option<Vec<&str>> = path.iter().map(|c| c.to_str()).collect();
+    Ok(components
+        .ok_or(anyhow!("path {path:?} is not valid unicode"))?
+        .join("/"))
+}
+
+/// Converts a timestamp into a zip time, which can't represent dates outside of years 1980-2107.
+fn zip_time(timestamp: u64) -> DateTime {
+    let days = i64::try_from(timestamp / 86_400).unwrap_or(i64::MAX);
+    let seconds = timestamp % 86_400;
+
+    // convert days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    let time = (|| {
+        DateTime::from_date_and_time(
+            u16::try_from(year).ok()?,
+            u8::try_from(month).ok()?,
+            u8::try_from(day).ok()?,
+            u8::try_from(seconds / 3600).ok()?,
+            u8::try_from(seconds % 3600 / 60).ok()?,
+            u8::try_from(seconds % 60).ok()?,
+        )
+        .ok()
+    })();
+
+    time.unwrap_or_default()
+}
+
+/// Reads the contents of a single file from the chunks following it.
+struct ChunkReader<'a> {
+    items: &'a mut Receiver<ArchiveItem>,
+    chunk: Vec<u8>,
+    position: usize,
+    remaining: u64,
+}
+
+impl<'a> ChunkReader<'a> {
+    fn new(items: &'a mut Receiver<ArchiveItem>, size: u64) -> Self {
+        Self {
+            items,
+            chunk: Vec::new(),
+            position: 0,
+            remaining: size,
+        }
+    }
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+
+        if self.position == self.chunk.len() {
+            match self.items.blocking_recv() {
+                Some(ArchiveItem::Chunk(chunk)) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file contents are incomplete"))
+                }
+            }
+        }
+
+        let available = &self.chunk[self.position..];
+        if available.len() as u64 > self.remaining {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "file contents don't match its size"));
+        }
+
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.position += length;
+        self.remaining -= length as u64;
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zip_time_parts(time: DateTime) -> (u16, u8, u8, u8, u8, u8) {
+        (time.year(), time.month(), time.day(), time.hour(), time.minute(), time.second())
+    }
+
+    #[test]
+    fn zip_time_from_timestamp() {
+        assert_eq!(zip_time_parts(zip_time(0)), zip_time_parts(DateTime::default()));
+        assert_eq!(zip_time_parts(zip_time(1_700_000_000)), (2023, 11, 14, 22, 13, 20));
+        assert_eq!(zip_time_parts(zip_time(951_782_400)), (2000, 2, 29, 0, 0, 0));
+    }
+}
@@ -6,7 +6,7 @@ use std::{
     fmt::{Debug, Formatter},
     fs,
     fs::File,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
@@ -24,6 +24,7 @@ use crate::{
         BACKUP_ORCHESTRATOR,
     },
     block_if_paused,
+    config::Config,
     defaults::{BLOB_DESIRED_TARGET_SIZE, BLOB_MAX_UNCOMPRESSED_SIZE, BLOB_MINIMUM_TARGET_SIZE},
     UI,
 };
@@ -45,7 +46,9 @@ struct FsNode {
 /// Returns the hash of the blob that represents the root of the tree (a snapshot ID),
 /// used to restore the exact state of the directory at the time of backup.
 pub async fn pack(backup_root: PathBuf, pack_folder: PathBuf) -> anyhow::Result<BlobHash> {
-    let packer = packfile::Manager::new(pack_folder).await?;
+    // trees are kept locally after sending, so snapshots can be browsed without asking peers
+    let metadata_cache = Config::get_metadata_cache_folder()?;
+    let packer = packfile::Manager::with_metadata_cache(pack_folder, metadata_cache).await?;
 
     let mut processing_queue = VecDeque::<FsNodePtr>::new();
 
@@ -153,7 +156,7 @@ async fn pack_files_in_directory(
             name: path.file_name().unwrap_or("".as_ref()).to_string_lossy().into(),
             metadata: get_metadata(&path)?,
             // move child directory hashes from temporary FsNode structure to our actual tree
-            children: Vec::from(node.as_ref().unwrap().children.lock().unwrap().as_mut()),
+            children: Vec::from(node.as_ref().unwrap().children.lock().unwrap().as_slice()),
             next_sibling: None,
         };
 
@@ -281,6 +284,29 @@ async fn process_file(path: PathBuf, packer: packfile::Manager) -> anyhow::Resul
     Ok(hash)
 }
 
+/// Compute the hashes of the chunks a file is split into when it's backed up, without storing them.
+/// The result matches the children of the file tree if the contents didn't change.
+pub fn hash_file_chunks(path: &Path) -> anyhow::Result<Vec<BlobHash>> {
+    if fs::metadata(path)?.len() <= BLOB_DESIRED_TARGET_SIZE as u64 {
+        return Ok(vec![blake3::hash(&fs::read(path)?).into()]);
+    }
+
+    let file = File::open(path)?;
+    // safety: the file is only read, a concurrent modification can only make the hashes not match
+    let mmap = unsafe { Mmap::map(&file)? };
+
+    let chunker = FastCDC::new(
+        &mmap,
+        cast::u32(BLOB_MINIMUM_TARGET_SIZE).unwrap(),
+        cast::u32(BLOB_DESIRED_TARGET_SIZE).unwrap(),
+        cast::u32(BLOB_MAX_UNCOMPRESSED_SIZE).unwrap(),
+    );
+
+    Ok(chunker
+        .map(|chunk| blake3::hash(&mmap[chunk.offset..(chunk.offset + chunk.length)]).into())
+        .collect())
+}
+
 /// Add a file blob to the packfile manager, and return the hash of the blob.
 async fn add_file_blob(packer: &packfile::Manager, data: &[u8]) -> anyhow::Result<BlobHash> {
     let hash = blake3::hash(data).into();
@@ -1,121 +1,458 @@
 //! Implements unpacking of packfiles into a directory.
 
-use std::{collections::VecDeque, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
 
 use anyhow::bail;
 use filetime::{set_file_mtime, FileTime};
 use futures_util::future::join_all;
-use shared::types::BlobHash;
-use tokio::{fs, fs::File, io::AsyncWriteExt};
-
-use crate::backup::filesystem::{packfile, BlobKind, Tree, TreeKind};
-
-/// Unpack a tree of a given hash from packfiles into a directory.
-pub async fn unpack(
-    packfile_dir: impl Into<PathBuf>,
-    destination_dir: impl Into<PathBuf> + Clone,
-    root_hash: BlobHash,
-) -> anyhow::Result<()> {
-    let packer = packfile::Manager::new(packfile_dir.into()).await?;
-
-    let destination_dir = destination_dir.into();
-    fs::create_dir_all(destination_dir.clone()).await?;
-
-    let root_tree = fetch_full_tree(packer.clone(), &root_hash).await?;
-
-    let mut dir_queue: VecDeque<(Tree, PathBuf)> = VecDeque::new();
-    dir_queue.push_front((root_tree, PathBuf::new()));
-
-    while let Some((parent_tree, path)) = dir_queue.pop_front() {
-        // all children of dir type tree are trees, all children of file type tree are chunks
-        match parent_tree.kind {
-            TreeKind::Dir => {
-                let mut futures = Vec::new();
-
-                for hash in &parent_tree.children {
-                    let child_tree = fetch_full_tree(packer.clone(), hash).await?;
-                    let rel_path = path.join(child_tree.name.clone());
-                    let abs_path = destination_dir.join(&rel_path);
-
-                    match child_tree.kind {
-                        TreeKind::File => {
-                            futures.push(tokio::spawn(restore_file(
-                                packer.clone(),
-                                Box::new(child_tree),
-                                abs_path,
-                            )));
-                        }
-                        TreeKind::Dir => {
-                            fs::create_dir_all(&abs_path).await?;
-                            set_path_mtime(&abs_path, &child_tree)?;
-                            dir_queue.push_back((child_tree, rel_path));
-                        }
+use serde::{Deserialize, Serialize};
+use shared::types::{BlobHash, PackfileId};
+use tokio::{
+    fs,
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc::UnboundedReceiver,
+    task::spawn_blocking,
+};
+
+use crate::{
+    backup::{
+        filesystem::{dir_packer::hash_file_chunks, packfile, BlobKind, Tree},
+        RESTORE_ORCHESTRATOR,
+    },
+    defaults::RESTORE_PARTIAL_SUFFIX,
+    CONFIG,
+};
+
+/// Decides what happens when a restored file already exists at the destination.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverwritePolicy {
+    /// Keep the existing file, don't restore it.
+    #[default]
+    Skip,
+    /// Always replace the existing file.
+    Overwrite,
+    /// Replace the existing file only if its contents differ from the backup.
+    OverwriteIfDifferent,
+    /// Keep the existing file, and restore to a new file with a numbered suffix.
+    KeepBoth,
+}
+
+/// A file or directory that could not be restored.
+#[derive(Debug, Clone)]
+pub struct UnrestoredEntry {
+    /// The path relative to the destination. If a tree of a directory entry is missing, its name is
+    /// not known, so the path of the parent directory is used instead.
+    pub path: PathBuf,
+    /// The blobs that were not available, can be empty if restoring failed for other reasons.
+    pub missing_blobs: Vec<BlobHash>,
+}
+
+/// An event driving the [`StreamingUnpacker`].
+#[derive(Debug)]
+pub enum UnpackEvent {
+    /// A packfile has been received and can be read.
+    Packfile(PackfileId),
+    /// A directory to create, with its path relative to the destination.
+    Dir(PathBuf, Tree),
+    /// A file to restore once all its blobs are available, with its path relative to the destination.
+    File(PathBuf, Tree),
+    /// An entry that can't be restored, for example because its tree is missing.
+    Unrestored(UnrestoredEntry),
+    /// All files to restore are known, packfiles not needed by any of them can be deleted.
+    PlanComplete,
+}
+
+/// Restores files while the packfiles are still being received. A file is written as soon as all
+/// packfiles containing its blobs are available, and a packfile is deleted once no file waiting to
+/// be restored needs it, so the whole backup never has to be stored on disk at once.
+pub struct StreamingUnpacker {
+    packer: packfile::Manager,
+    destination_dir: PathBuf,
+    overwrite: OverwritePolicy,
+    /// Files waiting for their packfiles.
+    pending: HashMap<usize, PendingFile>,
+    next_file_id: usize,
+    /// The pending files waiting for each packfile.
+    waiting: HashMap<PackfileId, Vec<usize>>,
+    /// The number of pending files containing blobs from each packfile.
+    references: HashMap<PackfileId, usize>,
+    /// Packfiles that have been received, they may have been deleted since.
+    available: HashSet<PackfileId>,
+    /// Packfiles are only deleted after all files are known, until then any of them can be needed.
+    plan_complete: bool,
+    /// Files written by an interrupted restore that is being resumed, with the paths they were
+    /// written to, which differ if both the existing and the restored file were kept.
+    restored_files: HashMap<PathBuf, PathBuf>,
+    unrestored: Vec<UnrestoredEntry>,
+}
+
+struct PendingFile {
+    path: PathBuf,
+    tree: Tree,
+    packfiles: HashSet<PackfileId>,
+    missing: HashSet<PackfileId>,
+}
+
+impl StreamingUnpacker {
+    /// Create an unpacker restoring into a directory. If only a subtree of a snapshot is restored,
+    /// it's restored to the same relative path it had in the backup. Files already written by an
+    /// interrupted restore are skipped if they haven't changed since.
+    pub async fn new(
+        packer: packfile::Manager,
+        destination_dir: PathBuf,
+        subtree_path: Option<&Path>,
+        overwrite: OverwritePolicy,
+        restored_files: HashMap<PathBuf, PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let parent = match subtree_path.and_then(Path::parent) {
+            Some(parent) => destination_dir.join(parent),
+            None => destination_dir.clone(),
+        };
+        fs::create_dir_all(parent).await?;
+
+        Ok(Self {
+            packer,
+            destination_dir,
+            overwrite,
+            pending: HashMap::new(),
+            next_file_id: 0,
+            waiting: HashMap::new(),
+            references: HashMap::new(),
+            available: HashSet::new(),
+            plan_complete: false,
+            restored_files,
+            unrestored: Vec::new(),
+        })
+    }
+
+    /// Process events until all senders are dropped. Returns the files and directories that could
+    /// not be restored, including files whose packfiles never arrived.
+    pub async fn run(
+        mut self,
+        mut events: UnboundedReceiver<UnpackEvent>,
+    ) -> anyhow::Result<Vec<UnrestoredEntry>> {
+        while let Some(event) = events.recv().await {
+            match event {
+                UnpackEvent::Packfile(packfile_id) => self.packfile_available(packfile_id).await?,
+                UnpackEvent::Dir(path, tree) => {
+                    let abs_path = self.destination_dir.join(path);
+                    fs::create_dir_all(&abs_path).await?;
+                    set_path_mtime(&abs_path, &tree)?;
+                }
+                UnpackEvent::File(path, tree) => self.add_file(path, tree).await?,
+                UnpackEvent::Unrestored(entry) => self.unrestored.push(entry),
+                UnpackEvent::PlanComplete => {
+                    self.plan_complete = true;
+                    RESTORE_ORCHESTRATOR.get().unwrap().set_unpack_total_known().await;
+                    for packfile_id in self.available.clone() {
+                        self.evict_if_unused(packfile_id).await?;
                     }
                 }
+            }
+        }
 
-                // parallelize by directories for now, waiting for all files can use a lot of memory
-                let results = join_all(futures).await;
-                for result in results {
-                    match result {
-                        Ok(Ok(_)) => {}
-                        Ok(Err(e)) => println!("error restoring file: {e:?}"),
-                        Err(e) => println!("error occurred when restoring backups: {e:?}"),
+        for file in self.pending.into_values() {
+            let mut missing_blobs = Vec::new();
+            for blob_hash in &file.tree.children {
+                if let Some(packfile_id) = self.packer.find_packfile(blob_hash).await {
+                    if file.missing.contains(&packfile_id) {
+                        missing_blobs.push(*blob_hash);
                     }
                 }
             }
-            // files are processed directly
-            TreeKind::File => {}
+
+            self.unrestored
+                .push(UnrestoredEntry { path: file.path, missing_blobs });
         }
+
+        Ok(self.unrestored)
     }
 
-    Ok(())
+    /// Add a file to restore, it's restored right away if all its packfiles are available.
+    async fn add_file(&mut self, path: PathBuf, tree: Tree) -> anyhow::Result<()> {
+        let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+        let size = tree.metadata.size.unwrap_or_default();
+        orchestrator.add_unpack_total(size).await;
+
+        let restored = match self.restored_files.get(&path) {
+            Some(written_path) => is_file_restored(&self.destination_dir.join(written_path), &tree).await?,
+            None => false,
+        };
+        if restored {
+            println!("skipping already restored file {}", path.display());
+            orchestrator.add_unpack_done(size).await;
+            return Ok(());
+        }
+
+        let mut packfiles = HashSet::new();
+        let mut unknown_blobs = Vec::new();
+        for blob_hash in &tree.children {
+            match self.packer.find_packfile(blob_hash).await {
+                Some(packfile_id) => {
+                    packfiles.insert(packfile_id);
+                }
+                None => unknown_blobs.push(*blob_hash),
+            }
+        }
+
+        if !unknown_blobs.is_empty() {
+            self.unrestored
+                .push(UnrestoredEntry { path, missing_blobs: unknown_blobs });
+            return Ok(());
+        }
+
+        let file_id = self.next_file_id;
+        self.next_file_id += 1;
+
+        let missing: HashSet<PackfileId> = packfiles.difference(&self.available).copied().collect();
+        for packfile_id in &packfiles {
+            *self.references.entry(*packfile_id).or_default() += 1;
+        }
+        for packfile_id in &missing {
+            self.waiting.entry(*packfile_id).or_default().push(file_id);
+        }
+
+        let ready = missing.is_empty();
+        self.pending
+            .insert(file_id, PendingFile { path, tree, packfiles, missing });
+        if ready {
+            self.restore_files(vec![file_id]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Restore the files that were waiting only for the received packfile.
+    async fn packfile_available(&mut self, packfile_id: PackfileId) -> anyhow::Result<()> {
+        self.available.insert(packfile_id);
+
+        let mut ready = Vec::new();
+        for file_id in self.waiting.remove(&packfile_id).unwrap_or_default() {
+            if let Some(file) = self.pending.get_mut(&file_id) {
+                file.missing.remove(&packfile_id);
+                if file.missing.is_empty() {
+                    ready.push(file_id);
+                }
+            }
+        }
+
+        self.restore_files(ready).await?;
+        self.evict_if_unused(packfile_id).await
+    }
+
+    /// Restore pending files in parallel, then release the packfiles they were using.
+    async fn restore_files(&mut self, file_ids: Vec<usize>) -> anyhow::Result<()> {
+        let mut files = Vec::new();
+        let mut futures = Vec::new();
+        for file_id in file_ids {
+            if let Some(file) = self.pending.remove(&file_id) {
+                let abs_path = self.destination_dir.join(&file.path);
+                let size = file.tree.metadata.size.unwrap_or_default();
+                let future = restore_file(self.packer.clone(), Box::new(file.tree), abs_path, self.overwrite);
+                futures.push(tokio::spawn(future));
+                files.push((file.path, file.packfiles, size));
+            }
+        }
+
+        let results = join_all(futures).await;
+        for ((path, packfiles, size), result) in files.into_iter().zip(results) {
+            match result {
+                Ok(Ok(written_path)) => {
+                    let written_path = written_path.strip_prefix(&self.destination_dir).unwrap_or(&path);
+                    CONFIG.get().unwrap().add_restored_file(&path, written_path).await?;
+                    RESTORE_ORCHESTRATOR.get().unwrap().add_unpack_done(size).await;
+                }
+                Ok(Err(e)) => {
+                    println!("error restoring file: {e:?}");
+                    self.unrestored
+                        .push(UnrestoredEntry { path, missing_blobs: Vec::new() });
+                }
+                Err(e) => {
+                    println!("error occurred when restoring backups: {e:?}");
+                    self.unrestored
+                        .push(UnrestoredEntry { path, missing_blobs: Vec::new() });
+                }
+            }
+
+            for packfile_id in packfiles {
+                if let Some(count) = self.references.get_mut(&packfile_id) {
+                    *count = count.saturating_sub(1);
+                }
+                self.evict_if_unused(packfile_id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Delete a received packfile if no pending file needs it anymore.
+    async fn evict_if_unused(&mut self, packfile_id: PackfileId) -> anyhow::Result<()> {
+        if !self.plan_complete
+            || !self.available.contains(&packfile_id)
+            || self.references.get(&packfile_id).is_some_and(|count| *count > 0)
+        {
+            return Ok(());
+        }
+
+        self.references.remove(&packfile_id);
+        match fs::remove_file(self.packer.get_packfile_path(packfile_id, false).await?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
 }
 
-/// Restore a single file from a tree.
+/// Restore a single file from a tree. Returns the path the file was written to, or the path of the
+/// existing file if it was skipped.
 async fn restore_file(
     mut packer: packfile::Manager,
     child_tree: Box<Tree>,
     path: PathBuf,
-) -> anyhow::Result<()> {
+    overwrite: OverwritePolicy,
+) -> anyhow::Result<PathBuf> {
+    let path = match fs::symlink_metadata(&path).await {
+        Ok(_) => match overwrite {
+            OverwritePolicy::Skip => {
+                println!("skipping existing file {}", path.display());
+                return Ok(path);
+            }
+            OverwritePolicy::Overwrite => path,
+            OverwritePolicy::OverwriteIfDifferent => {
+                if !is_file_different(packer.clone(), &child_tree, &path).await? {
+                    println!("skipping unchanged file {}", path.display());
+                    return Ok(path);
+                }
+                path
+            }
+            OverwritePolicy::KeepBoth => find_free_path(&path).await?,
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => path,
+        Err(e) => return Err(e.into()),
+    };
+
     println!("restoring file {path:?}");
 
-    let mut file = File::create(path.clone()).await?;
+    // an interrupted restore must not leave a partially written file behind, it would be mistaken
+    // for an existing file when resuming
+    let mut partial_name = path.file_name().unwrap_or_default().to_os_string();
+    partial_name.push(RESTORE_PARTIAL_SUFFIX);
+    let partial_path = path.with_file_name(partial_name);
+
+    let mut file = File::create(&partial_path).await?;
+    let mut written = 0;
     for blob_hash in &child_tree.children {
         match packer.get_blob(blob_hash).await? {
-            Some(blob) => file.write_all(&blob.data).await?,
+            Some(blob) => {
+                file.write_all(&blob.data).await?;
+                written += blob.data.len() as u64;
+            }
             None => bail!("Blob {} not found", hex::encode(blob_hash)),
         }
     }
+    file.sync_all().await?;
+    drop(file);
+
+    if let Some(size) = child_tree.metadata.size.filter(|size| *size != written) {
+        fs::remove_file(&partial_path).await?;
+        bail!("restored file {path:?} has {written} bytes instead of {size}");
+    }
 
+    fs::rename(&partial_path, &path).await?;
     set_path_mtime(&path, &child_tree)?;
-    Ok(())
+    Ok(path)
 }
 
-/// Set the mtime of a file to the mtime stored in the tree metadata.
-fn set_path_mtime(path: &PathBuf, tree: &Tree) -> anyhow::Result<()> {
-    if let Some(time) = tree.metadata.mtime.map(|t| FileTime::from_unix_time(t as i64, 0)) {
-        set_file_mtime(path, time)?;
+/// Check whether a file written by an interrupted restore is still unchanged. The size and
+/// modification time are checked first, then the contents are verified against the hashes of the
+/// file chunks, so the packfiles don't need to be available.
+async fn is_file_restored(path: &Path, tree: &Tree) -> anyhow::Result<bool> {
+    let metadata = match fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mtime = FileTime::from_last_modification_time(&metadata).unix_seconds();
+    if !metadata.is_file()
+        || tree.metadata.size.is_some_and(|size| size != metadata.len())
+        || tree
+            .metadata
+            .mtime
+            .is_some_and(|t| i64::try_from(t).is_ok_and(|t| t != mtime))
+    {
+        return Ok(false);
     }
 
-    Ok(())
+    let path = path.to_path_buf();
+    let hashes = spawn_blocking(move || hash_file_chunks(&path)).await??;
+    Ok(hashes == tree.children)
 }
 
-/// Fetch a tree and all its siblings into a single tree.
-async fn fetch_full_tree(packer: packfile::Manager, hash: &BlobHash) -> anyhow::Result<Tree> {
-    let mut root_tree = fetch_tree(packer.clone(), hash).await?;
+/// Check whether an existing file differs from the file stored in a tree, comparing the contents.
+async fn is_file_different(mut packer: packfile::Manager, tree: &Tree, path: &Path) -> anyhow::Result<bool> {
+    let metadata = fs::metadata(path).await?;
+    if !metadata.is_file() || tree.metadata.size.is_some_and(|size| size != metadata.len()) {
+        return Ok(true);
+    }
 
-    let mut next_hash = root_tree.next_sibling;
-    while let Some(hash) = &next_hash {
-        let mut partial_tree = fetch_tree(packer.clone(), hash).await?;
-        root_tree.children.append(&mut partial_tree.children);
-        next_hash = partial_tree.next_sibling;
+    let mut file = File::open(path).await?;
+    let mut buf = Vec::new();
+    for blob_hash in &tree.children {
+        let blob = match packer.get_blob(blob_hash).await? {
+            Some(blob) => blob,
+            None => bail!("Blob {} not found", hex::encode(blob_hash)),
+        };
+
+        buf.resize(blob.data.len(), 0);
+        match file.read_exact(&mut buf).await {
+            Ok(_) if buf == blob.data => {}
+            Ok(_) => return Ok(true),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(true),
+            Err(e) => return Err(e.into()),
+        }
     }
 
-    Ok(root_tree)
+    // the existing file could still be longer
+    Ok(file.read(&mut [0]).await? != 0)
+}
+
+/// Find a path that doesn't exist yet by adding a numbered suffix to the file name,
+/// for example `report (1).pdf`.
+async fn find_free_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    for n in 1.. {
+        let candidate = path.with_file_name(format!("{stem} ({n}){extension}"));
+        if !fs::try_exists(&candidate).await? {
+            return Ok(candidate);
+        }
+    }
+
+    unreachable!()
+}
+
+/// Set the mtime of a file to the mtime stored in the tree metadata.
+fn set_path_mtime(path: &PathBuf, tree: &Tree) -> anyhow::Result<()> {
+    if let Some(time) = tree.metadata.mtime.map(|t| FileTime::from_unix_time(t as i64, 0)) {
+        set_file_mtime(path, time)?;
+    }
+
+    Ok(())
 }
 
 /// Fetch a single tree from a packfile.
-async fn fetch_tree(mut packer: packfile::Manager, hash: &BlobHash) -> anyhow::Result<Tree> {
+pub async fn fetch_tree(mut packer: packfile::Manager, hash: &BlobHash) -> anyhow::Result<Tree> {
     let tree_blob = match packer.get_blob(hash).await? {
         Some(t) => t,
         None => bail!(format!("Chunk {} was not found", hex::encode(hash))),
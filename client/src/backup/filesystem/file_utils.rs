@@ -8,7 +8,7 @@ use std::{
 use anyhow::anyhow;
 use shared::types::PackfileId;
 
-use crate::defaults::{INDEX_FOLDER, PACKFILE_FOLDER};
+use crate::defaults::{INDEX_FOLDER, PACKFILE_FOLDER, SHARD_FOLDER};
 
 /// Parse a packfile path from a file name into a native packfile ID.
 pub fn parse_packfile_path_into_id(path: &Path) -> anyhow::Result<PackfileId> {
@@ -55,3 +55,59 @@ pub fn get_packfile_path(backup_folder: &Path, id: PackfileId, create_dirs: bool
 pub fn get_index_path(backup_folder: &Path, id: u32) -> PathBuf {
     backup_folder.join(INDEX_FOLDER).join(format!("{id:0>10}"))
 }
+
+/// Get the path to a shard of a packfile, optionally creating parent directories if they don't exist.
+pub fn get_shard_path(
+    backup_folder: &Path,
+    id: PackfileId,
+    index: u8,
+    create_dirs: bool,
+) -> anyhow::Result<PathBuf> {
+    let mut path = backup_folder.join(SHARD_FOLDER);
+    let hex = hex::encode(id);
+
+    // shards are grouped into folders the same way as packfiles
+    path.push(&hex[..2]);
+    if create_dirs {
+        fs::create_dir_all(&path)?;
+    }
+
+    path.push(format!("{hex}.{index}"));
+    Ok(path)
+}
+
+/// Parse a shard path from a file name into a native packfile ID and the shard index.
+pub fn parse_shard_path(path: &Path) -> anyhow::Result<(PackfileId, u8)> {
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow!("can't get shard filename"))?
+        .to_string_lossy()
+        .to_string();
+
+    let (packfile, index) = file_name.split_once('.').ok_or(anyhow!("invalid shard filename"))?;
+    let packfile_id: PackfileId = hex::decode(packfile)?
+        .try_into()
+        .map_err(|_| anyhow!("invalid shard filename"))?;
+
+    Ok((packfile_id, index.parse()?))
+}
+
+/// Find all shards of a packfile in a folder, along with their indexes.
+pub fn find_shards(backup_folder: &Path, id: PackfileId) -> anyhow::Result<Vec<(u8, PathBuf)>> {
+    let folder = match get_shard_path(backup_folder, id, 0, false)?.parent() {
+        Some(folder) if folder.try_exists()? => folder.to_path_buf(),
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut shards = Vec::new();
+    for entry in folder.read_dir()? {
+        let path = entry?.path();
+        if let Ok((packfile_id, index)) = parse_shard_path(&path) {
+            if packfile_id == id {
+                shards.push((index, path));
+            }
+        }
+    }
+
+    Ok(shards)
+}
@@ -11,7 +11,7 @@ use serde::{Deserialize, Serialize};
 use shared::types::{BlobHash, BlobNonce};
 
 /// Represents the type of the blob, either a file chunk or a tree.
-#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
 pub enum BlobKind {
     FileChunk,
     Tree,
@@ -60,20 +60,20 @@ pub struct BlobEncrypted {
 
 /// Represents metadata of a file/directory.
 #[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug, Default)]
-struct TreeMetadata {
-    size: Option<u64>,
-    mtime: Option<u64>,
-    ctime: Option<u64>,
+pub struct TreeMetadata {
+    pub size: Option<u64>,
+    pub mtime: Option<u64>,
+    pub ctime: Option<u64>,
 }
 
 /// Represents a directory tree, for encoding into a blob or in-memory.
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
-struct Tree {
-    kind: TreeKind,
-    name: String,
-    metadata: TreeMetadata,
-    children: Vec<BlobHash>,
-    next_sibling: Option<BlobHash>,
+pub struct Tree {
+    pub kind: TreeKind,
+    pub name: String,
+    pub metadata: TreeMetadata,
+    pub children: Vec<BlobHash>,
+    pub next_sibling: Option<BlobHash>,
 }
 
 #[derive(Debug, thiserror::Error)]
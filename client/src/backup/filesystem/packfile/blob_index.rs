@@ -1,6 +1,9 @@
 //! Contains the index implementation, which is used for quickly finding packfiles.
 
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
 
 use aes_gcm::{AeadInPlace, Aes256Gcm, KeyInit, Nonce};
 use bincode::Options;
@@ -147,6 +150,16 @@ impl BlobIndex {
         }
     }
 
+    /// Returns all known packfiles along with the blobs they contain.
+    pub fn packfile_blobs(&self) -> HashMap<PackfileId, Vec<BlobHash>> {
+        let mut packfiles: HashMap<PackfileId, Vec<BlobHash>> = HashMap::new();
+        for (blob_hash, packfile_id) in self.items.iter().chain(self.items_buf.iter()) {
+            packfiles.entry(*packfile_id).or_default().push(*blob_hash);
+        }
+
+        packfiles
+    }
+
     /// Adds a mapping from blob hash to packfile hash to the index, flushing to disk if over threshold.
     pub async fn push(
         &mut self,
@@ -163,10 +176,44 @@ impl BlobIndex {
         Ok(())
     }
 
-    /// Loads all index files from disk.
+    /// Loads all index files from disk. Files are applied in the order of their numbers, so if a
+    /// blob appears in multiple files (for example after it has been repacked), the entry from the
+    /// newest file wins.
     async fn load(&mut self) -> Result<(), PackfileError> {
+        let mut items = Vec::new();
+        for (file_num, path) in self.list_files().await? {
+            let mut file = File::open(path).await?;
+            let mut buf: Vec<u8> = Vec::default();
+            file.read_to_end(&mut buf).await?;
+
+            let key = KEYS.get().unwrap().derive_backup_key(KEY_DERIVATION_CONSTANT);
+            let cipher = Aes256Gcm::new(&key.into());
+            let nonce_bytes = self.counter_to_nonce(file_num);
+            let nonce = Nonce::from_slice(&nonce_bytes);
+
+            // associated data could be used
+            cipher.decrypt_in_place(nonce, b"", &mut buf)?;
+
+            let mut file_items: Entry = bincode::options().with_varint_encoding().deserialize(&buf)?;
+            items.append(&mut file_items);
+        }
+
+        // sort all the entries so we're able to use binary search, the sort is stable so duplicate
+        // entries stay in file order and only the last one of them is kept
+        items.sort_by_key(|&(a, _)| a);
+        items.reverse();
+        items.dedup_by_key(|&mut (a, _)| a);
+        items.reverse();
+        self.items = items;
+
+        Ok(())
+    }
+
+    /// Returns the numbers and paths of all index files on disk, ordered by number.
+    async fn list_files(&self) -> Result<Vec<(u32, PathBuf)>, PackfileError> {
         let mut index_files = ReadDirStream::new(fs::read_dir(&self.output_path).await?);
 
+        let mut files = Vec::new();
         while let Some(entry) = index_files.next().await {
             let entry = entry?;
             // ignore files that don't match our pattern
@@ -176,32 +223,64 @@ impl BlobIndex {
                 .map_err(PackfileError::InvalidString)?)
             .parse::<u32>();
             if let Ok(file_num) = file_num {
-                let mut file = File::open(entry.path()).await?;
-                let mut buf: Vec<u8> = Vec::default();
-                file.read_to_end(&mut buf).await?;
+                files.push((file_num, entry.path()));
+            }
+        }
 
-                let key = KEYS.get().unwrap().derive_backup_key(KEY_DERIVATION_CONSTANT);
-                let cipher = Aes256Gcm::new(&key.into());
-                let nonce_bytes = self.counter_to_nonce(file_num);
-                let nonce = Nonce::from_slice(&nonce_bytes);
+        files.sort_unstable_by_key(|&(num, _)| num);
+        Ok(files)
+    }
 
-                // associated data could be used
-                cipher.decrypt_in_place(nonce, b"", &mut buf)?;
+    /// Unconditionally flushes the index to disk.
+    pub async fn flush(&mut self) -> Result<(), PackfileError> {
+        self.write_file(&self.items_buf).await?;
+        self.last_file_num += 1;
+        self.items_buf.clear();
+        self.dirty = false;
 
-                let mut items: Entry = bincode::options().with_varint_encoding().deserialize(&buf)?;
-                self.items.append(&mut items);
-            }
+        Ok(())
+    }
+
+    /// Removes all entries pointing to the given packfiles from the index. The remaining entries are
+    /// first written to new index files, and only then the old files are deleted, so an interrupted
+    /// compaction leaves behind duplicate entries, but never loses any. Returns the numbers of the
+    /// index files that were removed.
+    pub async fn remove_packfiles(
+        &mut self,
+        packfiles: &HashSet<PackfileId>,
+    ) -> Result<Vec<u32>, PackfileError> {
+        if !self.items_buf.is_empty() {
+            self.flush().await?;
         }
 
-        // sort all the entries so we're able to use binary search
-        self.items.sort_unstable_by_key(|&(a, _)| a);
+        let old_files = self.list_files().await?;
+        self.load().await?;
 
-        Ok(())
+        let (removed, kept): (Entry, Entry) = self
+            .items
+            .drain(..)
+            .partition(|(_, packfile)| packfiles.contains(packfile));
+
+        for chunk in kept.chunks(MAX_FILE_ENTRIES) {
+            self.write_file(chunk).await?;
+            self.last_file_num += 1;
+        }
+
+        for (_, path) in &old_files {
+            fs::remove_file(path).await?;
+        }
+
+        for (blob_hash, _) in &removed {
+            self.blobs_queued.remove(blob_hash);
+        }
+
+        self.items = kept;
+        Ok(old_files.into_iter().map(|(num, _)| num).collect())
     }
 
-    /// Unconditionally flushes the index to disk.
-    pub async fn flush(&mut self) -> Result<(), PackfileError> {
-        let mut buf = bincode::options().with_varint_encoding().serialize(&self.items_buf)?;
+    /// Encrypts the entries and writes them to a new index file, numbered after the last one.
+    async fn write_file(&self, items: &[(BlobHash, PackfileId)]) -> Result<(), PackfileError> {
+        let mut buf = bincode::options().with_varint_encoding().serialize(items)?;
         let new_file_num = self
             .last_file_num
             .checked_add(1)
@@ -220,10 +299,7 @@ impl BlobIndex {
         let file_path = self.output_path.join(file_name);
         let mut file = File::create(file_path.clone()).await?;
         file.write_all(&buf).await?;
-
-        self.last_file_num = new_file_num;
-        self.items_buf.clear();
-        self.dirty = false;
+        file.sync_all().await?;
 
         Ok(())
     }
@@ -2,10 +2,11 @@
 
 pub mod blob_index;
 pub mod pack;
+pub mod repack;
 pub mod unpack;
 
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     path::PathBuf,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
@@ -14,10 +15,11 @@ use std::{
 };
 
 use fs_extra::dir::get_size;
+use shared::types::{BlobHash, PackfileId};
 use tokio::sync::Mutex;
 
 use crate::{
-    backup::filesystem::{packfile::blob_index::BlobIndex, BlobEncrypted, PackfileError},
+    backup::filesystem::{packfile::blob_index::BlobIndex, BlobEncrypted, BlobKind, PackfileError},
     defaults::{INDEX_FOLDER, PACKFILE_FOLDER},
 };
 
@@ -43,6 +45,11 @@ const KEY_DERIVATION_CONSTANT_HEADER: &[u8] = b"header";
 /// a folder named "pack" in the output folder, and the index is stored in a folder named "index"
 /// in the output folder.
 ///
+/// Tree blobs are never mixed with file chunks, they are written into separate metadata packfiles.
+/// If a metadata cache folder is set, metadata packfiles are also kept there after the packfiles in
+/// the output folder are sent to peers and deleted, so the snapshot structure can be read without
+/// fetching anything. Packfiles that are not found in the output folder are looked up in the cache.
+///
 /// The format of a packfile is as follows:
 /// - header length [8 bytes]
 /// - encrypted header (encoded with bincode)
@@ -69,14 +76,18 @@ pub struct Manager {
 }
 
 struct PackfileHandlerInner {
-    // Blobs in queue to be written to packfile.
+    // File chunk blobs in queue to be written to packfile.
     blobs: Mutex<VecDeque<BlobEncrypted>>,
+    /// Tree blobs in queue to be written to a metadata packfile.
+    tree_blobs: Mutex<VecDeque<BlobEncrypted>>,
     /// Keeps track if data has been successfully flushed to disk.
     dirty: AtomicBool,
     /// Index struct managing blob => packfile mapping.
     index: Mutex<BlobIndex>,
     /// The path to the output folder.
     output_path: PathBuf,
+    /// The path to the folder where metadata packfiles are kept locally, if any.
+    metadata_cache_path: Option<PathBuf>,
     /// Current size of local packfiles on disk.
     packfiles_size: AtomicU64,
     /// Maximum size of local packfiles on disk.
@@ -93,6 +104,22 @@ impl Drop for PackfileHandlerInner {
 
 impl Manager {
     pub async fn new(output_path: PathBuf) -> Result<Self, PackfileError> {
+        Self::new_inner(output_path, None).await
+    }
+
+    /// Create a manager that also keeps metadata packfiles in the given cache folder, and reads
+    /// packfiles from it.
+    pub async fn with_metadata_cache(
+        output_path: PathBuf,
+        cache_path: PathBuf,
+    ) -> Result<Self, PackfileError> {
+        Self::new_inner(output_path, Some(cache_path)).await
+    }
+
+    async fn new_inner(
+        output_path: PathBuf,
+        metadata_cache_path: Option<PathBuf>,
+    ) -> Result<Self, PackfileError> {
         let packfile_path = output_path.join(PACKFILE_FOLDER);
         let index_path = output_path.join(INDEX_FOLDER);
 
@@ -101,7 +128,9 @@ impl Manager {
         Ok(Self {
             inner: Arc::new(PackfileHandlerInner {
                 blobs: Mutex::new(VecDeque::new()),
+                tree_blobs: Mutex::new(VecDeque::new()),
                 output_path: packfile_path,
+                metadata_cache_path,
                 index: Mutex::new(BlobIndex::new(index_path).await?),
                 dirty: AtomicBool::new(false),
                 packfiles_size: AtomicU64::new(packfiles_size),
@@ -109,4 +138,22 @@ impl Manager {
             }),
         })
     }
+
+    /// Returns all packfiles known by the index, along with the blobs they contain.
+    pub async fn packfile_blobs(&self) -> HashMap<PackfileId, Vec<BlobHash>> {
+        self.inner.index.lock().await.packfile_blobs()
+    }
+
+    /// Returns the packfile containing the blob, if it is known by the index.
+    pub async fn find_packfile(&self, blob_hash: &BlobHash) -> Option<PackfileId> {
+        self.inner.index.lock().await.find_packfile(blob_hash)
+    }
+
+    /// Returns the queue for blobs of the given kind.
+    fn queue(&self, kind: BlobKind) -> &Mutex<VecDeque<BlobEncrypted>> {
+        match kind {
+            BlobKind::FileChunk => &self.inner.blobs,
+            BlobKind::Tree => &self.inner.tree_blobs,
+        }
+    }
 }
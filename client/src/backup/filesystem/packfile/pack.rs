@@ -1,7 +1,7 @@
 //! Contains the packing logic of the packfile.
 
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::atomic::{Ordering, Ordering::Relaxed},
 };
 
@@ -16,11 +16,12 @@ use zstd::bulk::Compressor;
 
 use crate::{
     backup::filesystem::{
+        file_utils::get_packfile_path,
         packfile::{
             Manager, KEY_DERIVATION_CONSTANT_HEADER, PACKFILE_MAX_BLOBS, PACKFILE_MAX_SIZE,
             PACKFILE_TARGET_SIZE, ZSTD_COMPRESSION_LEVEL,
         },
-        Blob, BlobEncrypted, CompressionKind, PackfileError, PackfileHeaderBlob,
+        Blob, BlobEncrypted, BlobKind, CompressionKind, PackfileError, PackfileHeaderBlob,
     },
     defaults::BLOB_MAX_UNCOMPRESSED_SIZE,
     KEYS,
@@ -41,7 +42,7 @@ impl Manager {
         let (blob_data, nonce_bytes) = Self::compress_encrypt_blob(&blob)?;
 
         {
-            self.inner.blobs.lock().await.push_back(BlobEncrypted {
+            self.queue(blob.kind).lock().await.push_back(BlobEncrypted {
                 hash: blob.hash,
                 kind: blob.kind,
                 data: blob_data,
@@ -51,7 +52,7 @@ impl Manager {
             self.inner.dirty.store(true, Ordering::Relaxed);
         }
 
-        self.trigger_write_if_desired().await
+        self.trigger_write_if_desired(blob.kind).await
     }
 
     /// Compresses and encrypts blob data.
@@ -80,7 +81,8 @@ impl Manager {
 
     /// Writes all queued blobs to disk.
     pub async fn flush(&self) -> Result<(), PackfileError> {
-        self.write_packfiles(false).await?;
+        self.write_packfiles(BlobKind::Tree, false).await?;
+        self.write_packfiles(BlobKind::FileChunk, false).await?;
 
         self.inner.index.lock().await.flush().await?;
         self.inner.dirty.store(false, Ordering::Release);
@@ -88,13 +90,13 @@ impl Manager {
         Ok(())
     }
 
-    /// Writes all queued blobs to disk if the packfile is over threshold.
-    async fn trigger_write_if_desired(&self) -> Result<Option<u64>, PackfileError> {
+    /// Writes all queued blobs of the given kind to disk if the packfile is over threshold.
+    async fn trigger_write_if_desired(&self, kind: BlobKind) -> Result<Option<u64>, PackfileError> {
         let mut candidates_size: usize = 0;
         let mut candidates_cnt: usize = 0;
 
         {
-            let blobs = self.inner.blobs.lock().await;
+            let blobs = self.queue(kind).lock().await;
             let mut index = self.inner.index.lock().await;
 
             for blob in blobs.iter() {
@@ -106,15 +108,15 @@ impl Manager {
         }
 
         if candidates_size >= PACKFILE_TARGET_SIZE || candidates_cnt >= PACKFILE_MAX_BLOBS {
-            return self.write_packfiles(true).await.map(Some);
+            return self.write_packfiles(kind, true).await.map(Some);
         }
 
         Ok(None)
     }
 
-    /// Always writes all queued blobs to disk.
-    async fn write_packfiles(&self, report_buffer_limit: bool) -> Result<u64, PackfileError> {
-        let mut blobs = self.inner.blobs.lock().await;
+    /// Always writes all queued blobs of the given kind to disk.
+    async fn write_packfiles(&self, kind: BlobKind, report_buffer_limit: bool) -> Result<u64, PackfileError> {
+        let mut blobs = self.queue(kind).lock().await;
         let mut index = self.inner.index.lock().await;
 
         let mut buffer_limit_exceeded = false;
@@ -161,30 +163,14 @@ impl Manager {
                 continue;
             }
 
-            let (packfile_id, buffer) = Self::serialize_packfile(&mut data, &mut header, bytes_written)?;
-
-            assert!(
-                buffer.len() <= PACKFILE_MAX_SIZE,
-                "bug: violated packfile size limit ({} B)",
-                buffer.len()
-            );
-
-            let file_path = self.get_packfile_path(packfile_id, true).await?;
-
-            // ensure that we are not overwriting an existing packfile by chance
-            let mut file = OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(file_path)
-                .await?;
-
             // save the packfile data to disk and add it to index
-            file.write_all(&buffer).await?;
+            let (packfile_id, packfile_size) = self
+                .write_packfile_file(&mut data, &mut header, bytes_written)
+                .await?;
             index.finalize_packfile(&packfile_index, packfile_id).await?;
-            println!("wrote packfile {} of size {}", hex::encode(packfile_id), buffer.len());
 
             let all_written_packfiles_size =
-                self.inner.packfiles_size.fetch_add(buffer.len() as u64, Relaxed);
+                self.inner.packfiles_size.fetch_add(packfile_size as u64, Relaxed);
 
             if all_written_packfiles_size > self.inner.packfiles_size_max {
                 println!(
@@ -203,6 +189,43 @@ impl Manager {
         }
     }
 
+    /// Serializes a single packfile and writes it to a new file, returning its ID and size. Packfiles
+    /// containing only trees are also written to the metadata cache, if there is one.
+    pub(super) async fn write_packfile_file(
+        &self,
+        data: &mut Vec<u8>,
+        header: &mut Vec<PackfileHeaderBlob>,
+        bytes_written: usize,
+    ) -> Result<(PackfileId, usize), PackfileError> {
+        let is_metadata = header.iter().all(|blob| blob.kind == BlobKind::Tree);
+        let (packfile_id, buffer) = Self::serialize_packfile(data, header, bytes_written)?;
+
+        assert!(buffer.len() <= PACKFILE_MAX_SIZE, "bug: violated packfile size limit ({} B)", buffer.len());
+
+        let file_path = self.get_packfile_path(packfile_id, true).await?;
+        Self::write_new_file(&file_path, &buffer).await?;
+        println!("wrote packfile {} of size {}", hex::encode(packfile_id), buffer.len());
+
+        if let Some(cache_path) = self.inner.metadata_cache_path.as_ref().filter(|_| is_metadata) {
+            let cached_path =
+                get_packfile_path(cache_path, packfile_id, true).map_err(std::io::Error::other)?;
+            Self::write_new_file(&cached_path, &buffer).await?;
+        }
+
+        Ok((packfile_id, buffer.len()))
+    }
+
+    /// Writes the data to a file that must not exist yet.
+    async fn write_new_file(path: &Path, data: &[u8]) -> Result<(), PackfileError> {
+        // ensure that we are not overwriting an existing packfile by chance
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path).await?;
+
+        file.write_all(data).await?;
+        file.sync_all().await?;
+
+        Ok(())
+    }
+
     /// Serializes and encrypts a single packfile.
     fn serialize_packfile(
         data: &mut Vec<u8>,
@@ -252,6 +275,20 @@ impl Manager {
 
         Ok(file_path)
     }
+
+    /// Returns the path to a packfile in the metadata cache, if there is a cache and the packfile is
+    /// stored in it.
+    pub async fn get_cached_packfile_path(
+        &self,
+        packfile_hash: PackfileId,
+    ) -> Result<Option<PathBuf>, PackfileError> {
+        let Some(cache_path) = &self.inner.metadata_cache_path else {
+            return Ok(None);
+        };
+
+        let file_path = get_packfile_path(cache_path, packfile_hash, false).map_err(std::io::Error::other)?;
+        Ok(fs::try_exists(&file_path).await?.then_some(file_path))
+    }
 }
 
 #[cfg(test)]
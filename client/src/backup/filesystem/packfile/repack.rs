@@ -0,0 +1,180 @@
+//! Contains the logic for moving blobs out of sparse packfiles into new, full packfiles.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    sync::atomic::Ordering::Relaxed,
+};
+
+use shared::types::{BlobHash, PackfileId, BLOB_NONCE_SIZE};
+use tokio::fs;
+
+use crate::backup::filesystem::{
+    packfile::{blob_index::BlobIndex, Manager, PACKFILE_MAX_BLOBS, PACKFILE_TARGET_SIZE},
+    BlobEncrypted, BlobKind, CompressionKind, PackfileError, PackfileHeaderBlob,
+};
+
+/// Packfiles smaller than this size are considered underfilled and worth repacking.
+pub const PACKFILE_UNDERFILLED_SIZE: u64 = (PACKFILE_TARGET_SIZE / 2) as u64;
+
+/// Describes the changes made by repacking.
+#[derive(Debug, Default)]
+pub struct RepackResult {
+    /// Newly written packfiles, containing the live blobs.
+    pub new_packfiles: Vec<PackfileId>,
+    /// Packfiles that were deleted after their live blobs were moved.
+    pub removed_packfiles: Vec<PackfileId>,
+    /// Numbers of the index files that were replaced when compacting the index.
+    pub removed_index_files: Vec<u32>,
+}
+
+impl Manager {
+    /// Returns the packfiles known by the index that are present on disk, but are smaller
+    /// than `PACKFILE_UNDERFILLED_SIZE`.
+    pub async fn underfilled_packfiles(&self) -> Result<Vec<PackfileId>, PackfileError> {
+        let mut underfilled = Vec::new();
+        for packfile_id in self.packfile_blobs().await.into_keys() {
+            let path = self.get_packfile_path(packfile_id, false).await?;
+            match fs::metadata(path).await {
+                Ok(metadata) if metadata.len() < PACKFILE_UNDERFILLED_SIZE => underfilled.push(packfile_id),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(underfilled)
+    }
+
+    /// Moves the blobs satisfying `is_live` out of the given packfiles into new packfiles, and
+    /// deletes the old packfiles along with all the blobs that were not moved.
+    ///
+    /// The steps are ordered so that every live blob stays readable if the process is interrupted:
+    /// new packfiles are written and synced first, then index entries pointing to them are written
+    /// (newer index entries take precedence over older ones), then the index is compacted to drop
+    /// the entries of the old packfiles, and only after that the old packfiles are deleted. An
+    /// interruption can at worst leave behind unreferenced packfiles or duplicate index entries.
+    pub async fn repack(
+        &self,
+        packfiles: &[PackfileId],
+        is_live: impl Fn(&BlobHash) -> bool,
+    ) -> Result<RepackResult, PackfileError> {
+        let mut result = RepackResult::default();
+        if packfiles.is_empty() {
+            return Ok(result);
+        }
+
+        // write out anything queued first, and prevent new blobs from being written while repacking
+        self.flush().await?;
+        let _blobs = self.inner.blobs.lock().await;
+        let _tree_blobs = self.inner.tree_blobs.lock().await;
+        let mut index = self.inner.index.lock().await;
+
+        let mut moved: HashSet<BlobHash> = HashSet::new();
+        // trees and file chunks are kept in separate packfiles, just like when writing new blobs
+        let mut pending: HashMap<BlobKind, (Vec<BlobEncrypted>, usize)> = HashMap::new();
+
+        for packfile_id in packfiles {
+            let blobs = self
+                .get_encrypted_blobs(*packfile_id, |hash| is_live(hash) && !moved.contains(hash))
+                .await?;
+
+            for blob in blobs {
+                moved.insert(blob.hash);
+                let (kind_pending, pending_size) = pending.entry(blob.kind).or_default();
+                *pending_size += blob.data.len() + BLOB_NONCE_SIZE;
+                kind_pending.push(blob);
+
+                if *pending_size >= PACKFILE_TARGET_SIZE || kind_pending.len() >= PACKFILE_MAX_BLOBS {
+                    let new_packfile = self.write_repacked_packfile(&mut index, kind_pending).await?;
+                    result.new_packfiles.push(new_packfile);
+                    *pending_size = 0;
+                }
+            }
+        }
+
+        for (mut kind_pending, _) in pending.into_values() {
+            if !kind_pending.is_empty() {
+                let new_packfile = self.write_repacked_packfile(&mut index, &mut kind_pending).await?;
+                result.new_packfiles.push(new_packfile);
+            }
+        }
+
+        // the live blobs now point to the new packfiles, so the old entries can be dropped
+        index.flush().await?;
+        result.removed_index_files = index.remove_packfiles(&packfiles.iter().copied().collect()).await?;
+
+        // nothing references the old packfiles anymore
+        for packfile_id in packfiles {
+            let path = self.get_packfile_path(*packfile_id, false).await?;
+            match fs::metadata(&path).await {
+                Ok(metadata) => {
+                    fs::remove_file(&path).await?;
+                    self.inner.packfiles_size.fetch_sub(metadata.len(), Relaxed);
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            if let Some(cached_path) = self.get_cached_packfile_path(*packfile_id).await? {
+                fs::remove_file(cached_path).await?;
+            }
+
+            result.removed_packfiles.push(*packfile_id);
+        }
+
+        Ok(result)
+    }
+
+    /// Removes the entries of packfiles whose data was lost from the index, without moving any of
+    /// their blobs. The blobs are then no longer deduplicated, so they get packed again from the
+    /// source files. Returns the numbers of the index files that were replaced.
+    pub async fn forget_packfiles(&self, packfiles: &HashSet<PackfileId>) -> Result<Vec<u32>, PackfileError> {
+        if packfiles.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.flush().await?;
+        let _blobs = self.inner.blobs.lock().await;
+        let _tree_blobs = self.inner.tree_blobs.lock().await;
+        let mut index = self.inner.index.lock().await;
+
+        index.flush().await?;
+        index.remove_packfiles(packfiles).await
+    }
+
+    /// Writes the already encrypted blobs into a new packfile and adds them to the index.
+    async fn write_repacked_packfile(
+        &self,
+        index: &mut BlobIndex,
+        blobs: &mut Vec<BlobEncrypted>,
+    ) -> Result<PackfileId, PackfileError> {
+        let mut data: Vec<u8> = Vec::new();
+        let mut header: Vec<PackfileHeaderBlob> = Vec::new();
+
+        for blob in blobs.iter_mut() {
+            header.push(PackfileHeaderBlob {
+                hash: blob.hash,
+                kind: blob.kind,
+                compression: CompressionKind::Zstd,
+                offset: data.len() as u64,
+                length: blob.data.len() as u64,
+            });
+
+            data.extend_from_slice(&blob.nonce);
+            data.append(&mut blob.data);
+        }
+
+        let bytes_written = data.len();
+        let (packfile_id, packfile_size) = self
+            .write_packfile_file(&mut data, &mut header, bytes_written)
+            .await?;
+        self.inner.packfiles_size.fetch_add(packfile_size as u64, Relaxed);
+
+        for blob in blobs.drain(..) {
+            index.push(&blob.hash, &packfile_id).await?;
+        }
+
+        Ok(packfile_id)
+    }
+}
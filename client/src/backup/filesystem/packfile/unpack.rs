@@ -2,9 +2,9 @@
 
 use aes_gcm::{AeadInPlace, Aes256Gcm, KeyInit, Nonce};
 use bincode::Options;
-use shared::types::{BlobHash, BLOB_NONCE_SIZE};
+use shared::types::{BlobHash, PackfileId, BLOB_NONCE_SIZE};
 use tokio::{
-    fs::File,
+    fs::{self, File},
     io::{AsyncReadExt, AsyncSeekExt},
 };
 use zstd::bulk::Decompressor;
@@ -12,40 +12,17 @@ use zstd::bulk::Decompressor;
 use crate::{
     backup::filesystem::{
         packfile::{Manager, KEY_DERIVATION_CONSTANT_HEADER, PACKFILE_MAX_SIZE},
-        Blob, PackfileError, PackfileHeaderBlob,
+        Blob, BlobEncrypted, PackfileError, PackfileHeaderBlob,
     },
     defaults::BLOB_MAX_UNCOMPRESSED_SIZE,
-    KEYS,
+    log, KEYS,
 };
 
 impl Manager {
     /// Returns the blob if it exists in the packfile.
     pub async fn get_blob(&mut self, blob_hash: &BlobHash) -> Result<Option<Blob>, PackfileError> {
         if let Some(packfile_id) = self.inner.index.lock().await.find_packfile(blob_hash) {
-            let path = self.get_packfile_path(packfile_id, false).await?;
-            let mut packfile = File::open(path).await?;
-            let packfile_size = packfile.metadata().await?.len();
-            if packfile_size > PACKFILE_MAX_SIZE as u64 {
-                return Err(PackfileError::PackfileTooLarge);
-            }
-
-            let mut header_size_bytes: [u8; core::mem::size_of::<u64>()] = Default::default();
-            packfile.read_exact(&mut header_size_bytes).await?;
-            let header_size = u64::from_le_bytes(header_size_bytes);
-
-            if header_size > packfile_size || header_size == 0 {
-                return Err(PackfileError::InvalidHeaderSize);
-            }
-
-            let mut header_buf = vec![0; header_size as usize];
-            packfile.read_exact(&mut header_buf).await?;
-
-            let key = KEYS.get().unwrap().derive_backup_key(KEY_DERIVATION_CONSTANT_HEADER);
-            let cipher = Aes256Gcm::new(&key.into());
-            cipher.decrypt_in_place(Nonce::from_slice(&packfile_id), b"", &mut header_buf)?;
-
-            let header: Vec<PackfileHeaderBlob> =
-                bincode::options().with_varint_encoding().deserialize(&header_buf)?;
+            let (mut packfile, header) = self.read_header(packfile_id).await?;
 
             for blob_metadata in header {
                 if blob_metadata.hash == *blob_hash {
@@ -77,8 +54,83 @@ impl Manager {
             Err(PackfileError::IndexHeaderMismatch)
         } else {
             // ideally handle index not having the blob better
-            println!("blob not found in index!!");
+            log!("[packfile] blob {} not found in index", hex::encode(blob_hash));
             Ok(None)
         }
     }
+
+    /// Returns the raw encrypted blobs from a packfile that satisfy the predicate, without
+    /// decrypting their contents.
+    pub async fn get_encrypted_blobs(
+        &self,
+        packfile_id: PackfileId,
+        predicate: impl Fn(&BlobHash) -> bool,
+    ) -> Result<Vec<BlobEncrypted>, PackfileError> {
+        let (mut packfile, header) = self.read_header(packfile_id).await?;
+        let data_start = packfile.stream_position().await?;
+
+        let mut blobs = Vec::new();
+        for blob_metadata in header.into_iter().filter(|b| predicate(&b.hash)) {
+            let mut nonce = [0; BLOB_NONCE_SIZE];
+            let length =
+                usize::try_from(blob_metadata.length).map_err(|_| PackfileError::PackfileTooLarge)?;
+            let mut data = vec![0; length];
+            packfile
+                .seek(std::io::SeekFrom::Start(data_start + blob_metadata.offset))
+                .await?;
+
+            packfile.read_exact(&mut nonce).await?;
+            packfile.read_exact(&mut data).await?;
+
+            blobs.push(BlobEncrypted {
+                hash: blob_metadata.hash,
+                kind: blob_metadata.kind,
+                data,
+                nonce,
+            });
+        }
+
+        Ok(blobs)
+    }
+
+    /// Opens a packfile and reads its header, leaving the file positioned at the start of blob data.
+    /// Packfiles missing from the output folder are read from the metadata cache if possible.
+    async fn read_header(
+        &self,
+        packfile_id: PackfileId,
+    ) -> Result<(File, Vec<PackfileHeaderBlob>), PackfileError> {
+        let mut path = self.get_packfile_path(packfile_id, false).await?;
+        if !fs::try_exists(&path).await? {
+            if let Some(cached_path) = self.get_cached_packfile_path(packfile_id).await? {
+                path = cached_path;
+            }
+        }
+
+        let mut packfile = File::open(path).await?;
+        let packfile_size = packfile.metadata().await?.len();
+        if packfile_size > PACKFILE_MAX_SIZE as u64 {
+            return Err(PackfileError::PackfileTooLarge);
+        }
+
+        let mut header_size_bytes: [u8; core::mem::size_of::<u64>()] = Default::default();
+        packfile.read_exact(&mut header_size_bytes).await?;
+        let header_size = u64::from_le_bytes(header_size_bytes);
+
+        if header_size > packfile_size || header_size == 0 {
+            return Err(PackfileError::InvalidHeaderSize);
+        }
+
+        let header_size = usize::try_from(header_size).map_err(|_| PackfileError::InvalidHeaderSize)?;
+        let mut header_buf = vec![0; header_size];
+        packfile.read_exact(&mut header_buf).await?;
+
+        let key = KEYS.get().unwrap().derive_backup_key(KEY_DERIVATION_CONSTANT_HEADER);
+        let cipher = Aes256Gcm::new(&key.into());
+        cipher.decrypt_in_place(Nonce::from_slice(&packfile_id), b"", &mut header_buf)?;
+
+        let header: Vec<PackfileHeaderBlob> =
+            bincode::options().with_varint_encoding().deserialize(&header_buf)?;
+
+        Ok((packfile, header))
+    }
 }
@@ -3,30 +3,35 @@
 
 use std::{path::PathBuf, time::Duration};
 
-use anyhow::{anyhow, bail};
+use anyhow::bail;
 use backup_orchestrator::BackupOrchestrator;
 use cast::From;
 use fs_extra::dir::get_size;
 use futures_util::{try_join, FutureExt};
-use human_bytes::human_bytes;
-use shared::{p2p_message::RequestType, server_message::BackupRestoreInfo, types::ClientId};
-use tokio::{sync::OnceCell, time::sleep};
+use tokio::sync::OnceCell;
 
 use crate::{
-    backup::{
-        filesystem::{dir_packer, dir_unpacker},
-        restore_orchestrator::RestoreOrchestrator,
-    },
-    log,
+    backup::{filesystem::dir_packer, restore_orchestrator::RestoreOrchestrator},
     net_server::requests,
-    CONFIG, P2P_CONN_REQUESTS, UI,
+    CONFIG, UI,
 };
 
 pub mod backup_orchestrator;
+pub mod browse;
+pub mod check;
+pub mod diff;
+pub mod erasure;
+pub mod export;
 pub mod filesystem;
+pub mod prune;
+pub mod replicate;
+pub mod restore;
 pub mod restore_orchestrator;
 pub mod restore_send;
+pub mod retention;
+pub mod search;
 pub mod send;
+pub mod tree_source;
 
 /// A global state of the backup process, used for coordinating all components.
 pub static BACKUP_ORCHESTRATOR: OnceCell<BackupOrchestrator> = OnceCell::const_new();
@@ -62,7 +67,7 @@ pub async fn run() -> anyhow::Result<()> {
 
     // start tasks for filesystem walking and for sending to peers
     let pack_result = tokio::spawn(dir_packer::pack(backup_path.clone().unwrap(), destination.clone()));
-    let transport_result = tokio::spawn(send::send(destination));
+    let transport_result = tokio::spawn(send::send(destination, None));
 
     // start a task for sending progress updates to the UI
     let progress_sender = tokio::spawn(send_progress_updates());
@@ -72,6 +77,9 @@ pub async fn run() -> anyhow::Result<()> {
 
     BACKUP_ORCHESTRATOR.get().unwrap().set_backup_finished();
 
+    // the index of a browsing session doesn't know the blobs of the new snapshot
+    browse::reset().await;
+
     // stop sending progress updates
     progress_sender.abort();
 
@@ -113,96 +121,6 @@ async fn send_progress_updates() {
     }
 }
 
-/// Initialize the restore process, requesting files from peers and unpacking them.
-pub async fn request_restore() -> anyhow::Result<()> {
-    RestoreOrchestrator::initialize_static().await?;
-    RESTORE_ORCHESTRATOR.get().unwrap().set_started()?;
-    return match run_restore().await {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            RESTORE_ORCHESTRATOR.get().unwrap().set_finished(false, e.to_string());
-            Err(anyhow!("restore failed: {e}"))
-        }
-    };
-}
-
-/// Run the actual restore procedure.
-pub async fn run_restore() -> anyhow::Result<()> {
-    let config = CONFIG.get().unwrap();
-
-    let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
-    orchestrator.set_started()?;
-
-    // retrieve the snapshot id and contacted peers from the server
-    let BackupRestoreInfo { snapshot_hash, peers } = requests::backup_restore().await?;
-
-    log!("[restore] restoring from snapshot {}", hex::encode(snapshot_hash));
-    // request all files from all peers
-    for peer in peers {
-        log!("[restore] requesting files from peer {}", hex::encode(peer));
-        orchestrator.add_peer(peer).await;
-        request_restore_from_peer(peer).await?;
-    }
-
-    // we will now wait for the requests to complete in the background
-    loop {
-        // if something fails, restore is no longer running
-        if !orchestrator.is_running() {
-            bail!("restore from some peers failed");
-        }
-
-        // if all peers have completed, we can stop waiting
-        if orchestrator.all_peers_completed().await {
-            break;
-        }
-
-        // waiting is fine for now
-        sleep(Duration::from_secs(1)).await;
-    }
-
-    UI.get().unwrap().set_pack_running(true);
-
-    log!("[restore] now restoring files to the backup path...");
-    // restore files to backup path
-    dir_unpacker::unpack(
-        config.get_restored_packfiles_folder()?,
-        config
-            .get_backup_path()
-            .await?
-            .ok_or(anyhow!("backup path not set"))?,
-        snapshot_hash,
-    )
-    .await?;
-
-    let packfile_size = get_size(config.get_restored_packfiles_folder()?)?;
-
-    log!("[restore] deleting temporary files...");
-    tokio::fs::remove_dir_all(config.get_restored_packfiles_folder()?).await?;
-
-    orchestrator.set_finished(
-        true,
-        format!(
-            "Restore completed successfully!\nReceived and unpacked {} worth of data.",
-            human_bytes(packfile_size as f64)
-        ),
-    );
-
-    log!("[restore] restore completed successfully!");
-    Ok(())
-}
-
-/// Request all files from a peer for restoration.
-async fn request_restore_from_peer(peer_id: ClientId) -> anyhow::Result<()> {
-    let nonce = P2P_CONN_REQUESTS
-        .get()
-        .unwrap()
-        .add_request(peer_id, RequestType::RestoreAll)
-        .await?;
-    requests::p2p_connection_begin(peer_id, nonce).await?;
-
-    Ok(())
-}
-
 /// Estimate the size of the data currently being backed up.
 async fn estimate_size(backup_path: &PathBuf) {
     match get_size(backup_path) {
@@ -0,0 +1,317 @@
+//! Implements garbage collection of blobs and packfiles that are no longer referenced by any
+//! snapshot, for example after snapshots have been forgotten.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::ErrorKind,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail};
+use shared::{
+    p2p_message::{DeleteRequest, RequestType},
+    server_message::BackupRestoreInfo,
+    types::{BlobHash, ClientId, PackfileId},
+};
+use tokio::fs;
+
+use crate::{
+    backup::{
+        filesystem::{dir_unpacker::fetch_tree, file_utils::get_packfile_path, packfile, TreeKind},
+        restore::fetch_from_peers,
+        restore_orchestrator::{FolderUse, RestoreOrchestrator},
+        send, tree_source,
+    },
+    config::Config,
+    defaults::{INDEX_FOLDER, PRUNE_REPACK_GARBAGE_PERCENT},
+    log,
+    net_p2p::delete,
+    net_server::requests,
+    CONFIG,
+};
+
+/// Describes what needs to be done to remove unreferenced data from the backup.
+#[derive(Debug, Default)]
+pub struct PrunePlan {
+    /// All blobs reachable from the remaining snapshots.
+    pub live_blobs: HashSet<BlobHash>,
+    /// Packfiles that contain no live blobs, and can be deleted right away.
+    pub delete: Vec<PackfileId>,
+    /// Packfiles that contain mostly unreferenced blobs or are underfilled, their live blobs have
+    /// to be repacked into new packfiles before they can be deleted.
+    pub repack: Vec<PackfileId>,
+    /// Total number of blobs in all packfiles.
+    pub total_blobs: usize,
+    /// Number of blobs that are not referenced by any snapshot.
+    pub garbage_blobs: usize,
+}
+
+impl PrunePlan {
+    /// Returns whether there is nothing to prune.
+    pub fn is_empty(&self) -> bool {
+        self.delete.is_empty() && self.repack.is_empty()
+    }
+
+    /// Returns a short human readable summary of the plan.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} of {} blobs are unreferenced, {} packfiles can be deleted and {} packfiles should be repacked",
+            self.garbage_blobs,
+            self.total_blobs,
+            self.delete.len(),
+            self.repack.len()
+        )
+    }
+}
+
+/// Walk all trees starting from the given snapshot roots, and collect the hashes of all blobs
+/// (both trees and file chunks) that are reachable from them.
+pub async fn mark_live_blobs(
+    packer: packfile::Manager,
+    roots: impl IntoIterator<Item = BlobHash>,
+) -> anyhow::Result<HashSet<BlobHash>> {
+    let mut live = HashSet::new();
+    let mut tree_queue: VecDeque<BlobHash> = roots.into_iter().collect();
+
+    while let Some(hash) = tree_queue.pop_front() {
+        // subtrees that did not change are shared between snapshots, only walk them once
+        if !live.insert(hash) {
+            continue;
+        }
+
+        // siblings are stored as separate blobs, so they are marked individually
+        let tree = fetch_tree(packer.clone(), &hash).await?;
+        tree_queue.extend(tree.next_sibling);
+
+        // all children of dir type tree are trees, all children of file type tree are chunks
+        match tree.kind {
+            TreeKind::Dir => tree_queue.extend(tree.children),
+            TreeKind::File => live.extend(tree.children),
+        }
+    }
+
+    Ok(live)
+}
+
+/// Decide what to do with each packfile based on the set of live blobs.
+pub fn plan_packfiles(
+    packfiles: &HashMap<PackfileId, Vec<BlobHash>>,
+    live_blobs: HashSet<BlobHash>,
+) -> PrunePlan {
+    let mut plan = PrunePlan::default();
+
+    for (packfile_id, blobs) in packfiles {
+        let garbage = blobs.iter().filter(|b| !live_blobs.contains(*b)).count();
+        plan.total_blobs += blobs.len();
+        plan.garbage_blobs += garbage;
+
+        if garbage == blobs.len() {
+            plan.delete.push(*packfile_id);
+        } else if garbage * 100 >= blobs.len() * PRUNE_REPACK_GARBAGE_PERCENT {
+            plan.repack.push(*packfile_id);
+        }
+    }
+
+    plan.live_blobs = live_blobs;
+    plan
+}
+
+/// Create a prune plan for the packfiles in the given folder, keeping everything that is
+/// reachable from the snapshots the server still knows about.
+pub async fn plan(packfile_dir: PathBuf) -> anyhow::Result<PrunePlan> {
+    let snapshots = requests::snapshot_list().await?;
+    if snapshots.is_empty() {
+        bail!("no snapshots found, refusing to prune everything");
+    }
+
+    let packer = packfile::Manager::new(packfile_dir).await?;
+    let roots: HashSet<BlobHash> = snapshots.iter().map(|s| s.snapshot_hash).collect();
+
+    log!("[prune] marking blobs reachable from {} snapshots", snapshots.len());
+    let live_blobs = mark_live_blobs(packer.clone(), roots).await?;
+
+    let mut plan = plan_packfiles(&packer.packfile_blobs().await, live_blobs);
+
+    // small packfiles left behind by flushing at the end of each backup are worth merging as well
+    let underfilled: Vec<_> = packer
+        .underfilled_packfiles()
+        .await?
+        .into_iter()
+        .filter(|p| !plan.delete.contains(p) && !plan.repack.contains(p))
+        .collect();
+    if plan.repack.len() + underfilled.len() > 1 {
+        plan.repack.extend(underfilled);
+    }
+
+    Ok(plan)
+}
+
+/// Fetch all our data from peers and create a prune plan for it. Unless this is a dry run, the live
+/// blobs of sparse packfiles are then repacked and sent to peers with a backup, and peers are asked
+/// to delete the packfiles that are no longer needed. The received data is removed once done.
+pub async fn run(dry_run: bool) -> anyhow::Result<PrunePlan> {
+    let orchestrator = RestoreOrchestrator::start_in_restore_folder(FolderUse::Task, "pruning").await?;
+
+    let (plan, peers) = match fetch_and_plan(dry_run).await {
+        Ok(result) => {
+            orchestrator.set_finished(true, format!("Prune plan: {}.", result.0.summary()));
+            result
+        }
+        Err(e) => {
+            orchestrator.set_finished(false, e.to_string());
+            return Err(anyhow!("prune failed: {e}"));
+        }
+    };
+
+    if !dry_run {
+        apply_plan(&plan, &peers)
+            .await
+            .map_err(|e| anyhow!("prune failed: {e}"))?;
+    }
+
+    Ok(plan)
+}
+
+/// Fetch all our data from peers and create a prune plan for it. Unless this is a dry run, the
+/// packfiles to repack are copied into the local buffer. Returns the plan and the peers storing
+/// our data.
+async fn fetch_and_plan(dry_run: bool) -> anyhow::Result<(PrunePlan, Vec<ClientId>)> {
+    let config = CONFIG.get().unwrap();
+    let restore_folder = config.get_restored_packfiles_folder()?;
+
+    // trees are only stored in packfiles, so we need to get all of them back from peers first
+    let BackupRestoreInfo { peers, .. } = requests::backup_restore().await?;
+    let unavailable = fetch_from_peers(&peers, RequestType::RestoreAll).await?;
+    if !unavailable.is_empty() {
+        // without all the trees, live blobs could be mistaken for garbage
+        bail!("cannot prune, {} peers could not provide their data", unavailable.len());
+    }
+
+    let result = async {
+        let plan = plan(restore_folder.clone()).await?;
+
+        // the live blobs are read from the received packfiles, the new packfiles are written locally
+        if !dry_run {
+            let buffer = config.get_packfile_path()?;
+            for packfile_id in &plan.repack {
+                fs::copy(
+                    get_packfile_path(&restore_folder, *packfile_id, false)?,
+                    get_packfile_path(&buffer, *packfile_id, true)?,
+                )
+                .await?;
+            }
+        }
+
+        anyhow::Ok(plan)
+    }
+    .await;
+
+    log!("[prune] deleting temporary files...");
+    RestoreOrchestrator::remove_restore_folder().await?;
+
+    let plan = result?;
+    log!("[prune] {}", plan.summary());
+
+    Ok((plan, peers))
+}
+
+/// Repack the sparse packfiles in the local buffer and remove the packfiles to delete from the local
+/// index, send the new packfiles and the updated index to peers, and then ask peers to delete the old
+/// packfiles and the index files that were replaced. Nothing is deleted if sending fails or the new
+/// packfiles aren't stored by any peer, as the index stored by peers still points to the old packfiles.
+async fn apply_plan(plan: &PrunePlan, peers: &[ClientId]) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+    let buffer = config.get_packfile_path()?;
+    let packer = packfile::Manager::new(buffer.clone()).await?;
+
+    let repacked = packer
+        .repack(&plan.repack, |hash| plan.live_blobs.contains(hash))
+        .await?;
+
+    // blobs of deleted packfiles must not be deduplicated against anymore, so the local index has to
+    // forget them before any peer deletes them
+    let mut index_files = repacked.removed_index_files;
+    index_files.extend(
+        packer
+            .forget_packfiles(&plan.delete.iter().copied().collect())
+            .await?,
+    );
+
+    if !repacked.removed_packfiles.is_empty() {
+        log!(
+            "[prune] repacked {} packfiles into {}, sending them to peers",
+            repacked.removed_packfiles.len(),
+            repacked.new_packfiles.len()
+        );
+
+        // the new packfiles are sent along with the compacted index
+        send::send_buffer().await?;
+        for packfile_id in &repacked.new_packfiles {
+            if config.get_packfile_locations(*packfile_id).await?.is_empty() {
+                bail!("repacked packfile {} is not stored by any peer", hex::encode(packfile_id));
+            }
+        }
+    } else if !index_files.is_empty() {
+        send::replicate_index(&buffer.join(INDEX_FOLDER), None).await?;
+    }
+
+    tree_source::evict_unreachable_trees().await?;
+
+    let packfiles: Vec<PackfileId> = plan
+        .delete
+        .iter()
+        .chain(&repacked.removed_packfiles)
+        .copied()
+        .collect();
+    request_deletes(&packfiles, &index_files, peers).await
+}
+
+/// Ask the peers storing the given packfiles and index files to delete them.
+async fn request_deletes(
+    packfiles: &[PackfileId],
+    index_files: &[u32],
+    peers: &[ClientId],
+) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+
+    let mut requests: HashMap<ClientId, DeleteRequest> = HashMap::new();
+    for packfile_id in packfiles {
+        let locations = config.get_packfile_locations(*packfile_id).await?;
+
+        // packfiles sent before their locations were recorded could be stored by any peer
+        let holders =
+            if locations.is_empty() { peers.to_vec() } else { locations.iter().map(|l| l.peer_id).collect() };
+
+        for peer in holders {
+            requests.entry(peer).or_default().packfiles.push(*packfile_id);
+        }
+    }
+
+    for peer in config.get_packfile_peers().await? {
+        let delivered = config.get_peer_index_files(peer).await?;
+        let replaced: Vec<u32> = index_files
+            .iter()
+            .filter(|num| delivered.contains(num))
+            .copied()
+            .collect();
+        if !replaced.is_empty() {
+            requests.entry(peer).or_default().index_files = replaced;
+        }
+    }
+
+    for (peer, request) in requests {
+        delete::request_delete(peer, request).await?;
+    }
+
+    // deleted metadata packfiles are not needed in the local cache anymore
+    let metadata_cache = Config::get_metadata_cache_folder()?;
+    for packfile_id in packfiles {
+        match fs::remove_file(get_packfile_path(&metadata_cache, *packfile_id, false)?).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,415 @@
+//! Replicates the data stored by lost peers to other peers.
+//!
+//! A peer storing our data is considered lost if it hasn't been seen for a long time, or if it
+//! failed several audits in a row. Its packfiles are then recovered, preferably from the metadata
+//! cache or from the other peers storing them, into the local buffer of packfiles to send, and
+//! uploaded to other peers, obtaining new ones with storage requests if needed. Packfiles that can't
+//! be recovered are dropped from the index instead, so their blobs are packed again from the source
+//! files by the next backup.
+//!
+//! Peers marked for evacuation by the user are handled the same way, except that they can still
+//! provide their data, and they are asked to delete it once it's stored elsewhere.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    time::Duration,
+};
+
+use shared::{
+    p2p_message::{DeleteRequest, RequestType, RestoreSelectedRequest},
+    types::{ClientId, PackfileId},
+};
+use tokio::{fs, time::sleep};
+
+use crate::{
+    backup::{
+        backup_orchestrator::BackupOrchestrator,
+        filesystem::{file_utils::get_packfile_path, packfile},
+        restore::fetch_from_peers,
+        restore_orchestrator::{FolderUse, RestoreOrchestrator},
+        send,
+        send::Redundancy,
+        BACKUP_ORCHESTRATOR, RESTORE_ORCHESTRATOR,
+    },
+    config::{packfiles::PackfileLocation, peers::PeerState, Config},
+    defaults::{INDEX_FOLDER, PEER_LOST_AFTER, PEER_LOST_FAILED_AUDITS, REPLICATION_CHECK_INTERVAL},
+    log,
+    net_p2p::delete,
+    CONFIG,
+};
+
+/// Periodically look for lost peers and replicate their data elsewhere.
+pub async fn run_replication_checks() {
+    loop {
+        sleep(Duration::from_secs(REPLICATION_CHECK_INTERVAL)).await;
+
+        if let Err(e) = check_peers().await {
+            log!("[replicate] replicating the data of lost peers failed: {}", e);
+        }
+    }
+}
+
+/// Mark the peers storing our data that are lost, and replicate the data of all lost peers and peers
+/// being evacuated, including the ones whose replication didn't finish before.
+pub async fn check_peers() -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+    let peers = config.get_packfile_peers().await?;
+    let mut lost = config.get_peers_in_state(PeerState::Lost).await?;
+    let evacuating = config.get_peers_in_state(PeerState::Evacuating).await?;
+
+    // only active peers can become lost, blocked peers keep their state until they are evacuated
+    let mut newly_lost = Vec::new();
+    for peer_id in &peers {
+        let state = config.get_peer_state(*peer_id).await?.map(|info| info.state);
+        if state.is_some_and(|state| state != PeerState::Active) {
+            continue;
+        }
+
+        if let Some(reason) = lost_reason(*peer_id).await? {
+            newly_lost.push((*peer_id, reason));
+        }
+    }
+
+    // losing all peers at once more likely means that we were offline ourselves
+    if !newly_lost.is_empty() && newly_lost.len() == peers.len() {
+        log!("[replicate] all peers storing our data seem lost, not replicating anything");
+        return Ok(());
+    }
+
+    for (peer_id, reason) in newly_lost {
+        log!("[replicate] peer {} is lost: {}", hex::encode(peer_id), reason);
+        config.set_peer_state(peer_id, PeerState::Lost, &reason).await?;
+        lost.push(peer_id);
+    }
+
+    if lost.is_empty() && evacuating.is_empty() {
+        return Ok(());
+    }
+
+    let backup_running = BACKUP_ORCHESTRATOR
+        .get()
+        .is_some_and(BackupOrchestrator::is_backup_running);
+    let restore_running = RESTORE_ORCHESTRATOR
+        .get()
+        .is_some_and(RestoreOrchestrator::is_running);
+    if backup_running || restore_running {
+        log!("[replicate] a backup or a restore is running, replication postponed");
+        return Ok(());
+    }
+
+    replicate(&lost, &evacuating).await
+}
+
+/// Returns why a peer is considered lost, or `None` if it isn't.
+async fn lost_reason(peer_id: ClientId) -> anyhow::Result<Option<String>> {
+    let config = CONFIG.get().unwrap();
+
+    if let Some(info) = config.get_peer_info(peer_id).await? {
+        let unseen = Config::get_unix_timestamp() - info.last_seen;
+        if unseen > PEER_LOST_AFTER {
+            return Ok(Some(format!("not seen for {} days", unseen / (24 * 60 * 60))));
+        }
+    }
+
+    let results = config
+        .get_recent_audit_results(peer_id, PEER_LOST_FAILED_AUDITS)
+        .await?;
+    if results.len() == usize::try_from(PEER_LOST_FAILED_AUDITS)? && results.iter().all(|passed| !passed) {
+        return Ok(Some(format!("failed the last {PEER_LOST_FAILED_AUDITS} audits")));
+    }
+
+    Ok(None)
+}
+
+/// Recover the packfiles of the lost and evacuated peers into the local buffer, send them to other
+/// peers, and forget the ones that can't be recovered.
+async fn replicate(lost: &[ClientId], evacuating: &[ClientId]) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+    let buffer = config.get_packfile_path()?;
+    let leaving: Vec<ClientId> = lost.iter().chain(evacuating).copied().collect();
+
+    // the peers that can provide each packfile, or a shard of it, evacuated peers still can
+    let mut holders: HashMap<PackfileId, Vec<ClientId>> = HashMap::new();
+    for peer_id in &leaving {
+        for location in config.get_peer_packfiles(*peer_id).await? {
+            holders.entry(location.packfile_id).or_default();
+        }
+    }
+    for (packfile_id, peers) in &mut holders {
+        for location in config.get_packfile_locations(*packfile_id).await? {
+            if !lost.contains(&location.peer_id) {
+                peers.push(location.peer_id);
+            }
+        }
+    }
+    log!("[replicate] replicating {} packfiles of {} peers", holders.len(), leaving.len());
+
+    // packfiles waiting to be sent are still in the buffer, metadata packfiles are kept in the cache
+    let cache = Config::get_metadata_cache_folder()?;
+    let mut recovered = HashSet::new();
+    for packfile_id in holders.keys() {
+        let cached = get_packfile_path(&cache, *packfile_id, false)?;
+        if get_packfile_path(&buffer, *packfile_id, false)?.try_exists()?
+            || copy_packfile(&cached, &buffer, *packfile_id).await?
+        {
+            recovered.insert(*packfile_id);
+        }
+    }
+
+    holders.retain(|packfile_id, peers| !recovered.contains(packfile_id) && !peers.is_empty());
+    if !holders.is_empty() {
+        match fetch_packfiles(&holders, &buffer).await {
+            Ok(fetched) => recovered.extend(fetched),
+            Err(e) => log!("[replicate] cannot fetch packfiles from other peers: {}", e),
+        }
+    }
+
+    // nothing is given up before the recovered packfiles are stored by other peers
+    send::send_buffer().await?;
+
+    finish_lost_peers(lost, &leaving, &recovered, &buffer).await?;
+    finish_replicated_packfiles(&recovered, evacuating, &leaving, &buffer).await
+}
+
+/// Stop tracking the packfiles of the lost peers that are stored by other peers now, and drop the
+/// ones that neither were recovered nor can be recovered from other peers from the index, sending
+/// the updated index. Lost peers are replaced once none of their packfiles are left, the remaining
+/// ones are replicated again with the next check.
+async fn finish_lost_peers(
+    lost: &[ClientId],
+    leaving: &[ClientId],
+    recovered: &HashSet<PackfileId>,
+    buffer: &Path,
+) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+
+    let mut forgotten = HashSet::new();
+    for peer_id in lost {
+        let mut done = Vec::new();
+        for location in config.get_peer_packfiles(*peer_id).await? {
+            let packfile_id = location.packfile_id;
+            if recovered.contains(&packfile_id) {
+                if is_stored_elsewhere(packfile_id, leaving, buffer).await? {
+                    done.push(packfile_id);
+                }
+                continue;
+            }
+
+            // packfiles that couldn't be fetched now are still there as long as other peers store enough of them
+            let mut locations = config.get_packfile_locations(packfile_id).await?;
+            locations.retain(|location| !lost.contains(&location.peer_id));
+            if !is_recoverable(&locations) {
+                forgotten.insert(packfile_id);
+                done.push(packfile_id);
+            }
+        }
+
+        config.remove_packfile_locations(*peer_id, &done).await?;
+        config.remove_audit_challenges(*peer_id, &done).await?;
+
+        let remaining = config.get_peer_packfiles(*peer_id).await?.len();
+        if remaining > 0 {
+            log!(
+                "[replicate] {} packfiles of peer {} are not stored elsewhere yet, retrying later",
+                remaining,
+                hex::encode(peer_id)
+            );
+        } else {
+            set_replaced(*peer_id).await?;
+        }
+    }
+
+    if !forgotten.is_empty() {
+        log!("[replicate] {} packfiles could not be recovered, they will be packed again", forgotten.len());
+        packfile::Manager::new(buffer.to_path_buf())
+            .await?
+            .forget_packfiles(&forgotten)
+            .await?;
+        send::replicate_index(&buffer.join(INDEX_FOLDER), None).await?;
+    }
+
+    Ok(())
+}
+
+/// Returns whether a recovered packfile was sent, and is stored by a peer that isn't being left.
+async fn is_stored_elsewhere(
+    packfile_id: PackfileId,
+    leaving: &[ClientId],
+    buffer: &Path,
+) -> anyhow::Result<bool> {
+    // the packfile is deleted from the buffer once enough peers store it
+    if get_packfile_path(buffer, packfile_id, false)?.try_exists()? {
+        return Ok(false);
+    }
+
+    Ok(CONFIG
+        .get()
+        .unwrap()
+        .get_packfile_locations(packfile_id)
+        .await?
+        .iter()
+        .any(|location| !leaving.contains(&location.peer_id)))
+}
+
+/// Returns whether a packfile can be recovered from the given copies or shards of it.
+fn is_recoverable(locations: &[PackfileLocation]) -> bool {
+    if locations.iter().any(|location| location.shard.is_none()) {
+        return true;
+    }
+
+    // only shards of the same coding can be combined
+    let mut shards: HashMap<u8, HashSet<u8>> = HashMap::new();
+    for shard in locations.iter().filter_map(|location| location.shard) {
+        shards.entry(shard.data_shards).or_default().insert(shard.index);
+    }
+
+    shards
+        .iter()
+        .any(|(data_shards, indexes)| indexes.len() >= usize::from(*data_shards))
+}
+
+/// Once the recovered packfiles are stored by enough other peers, ask the evacuated peers and the
+/// peers storing shards or copies that don't match the current redundancy setting to delete them.
+/// Evacuated peers are replaced once all of their packfiles are stored elsewhere, their index files
+/// are deleted as well then. The remaining ones are replicated again with the next check.
+async fn finish_replicated_packfiles(
+    recovered: &HashSet<PackfileId>,
+    evacuating: &[ClientId],
+    leaving: &[ClientId],
+    buffer: &Path,
+) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+    let redundancy = Redundancy::load().await?;
+
+    let mut requests: HashMap<ClientId, DeleteRequest> = HashMap::new();
+    for packfile_id in recovered {
+        if !is_stored_elsewhere(*packfile_id, leaving, buffer).await? {
+            continue;
+        }
+
+        for location in config.get_packfile_locations(*packfile_id).await? {
+            if evacuating.contains(&location.peer_id) || !redundancy.matches(location.shard) {
+                requests
+                    .entry(location.peer_id)
+                    .or_default()
+                    .packfiles
+                    .push(*packfile_id);
+            }
+        }
+    }
+
+    for peer_id in evacuating {
+        let moved = requests.get(peer_id).map_or(0, |request| request.packfiles.len());
+        let remaining = config.get_peer_packfiles(*peer_id).await?.len().saturating_sub(moved);
+        if remaining > 0 {
+            log!(
+                "[replicate] {} packfiles of peer {} are not stored elsewhere yet, retrying later",
+                remaining,
+                hex::encode(peer_id)
+            );
+            continue;
+        }
+
+        requests.entry(*peer_id).or_default().index_files =
+            config.get_peer_index_files(*peer_id).await?.into_iter().collect();
+        set_replaced(*peer_id).await?;
+    }
+
+    for (peer_id, request) in requests {
+        delete::request_delete(peer_id, request).await?;
+    }
+
+    Ok(())
+}
+
+/// Mark a peer whose data was replicated elsewhere as replaced, keeping the reason it was left for.
+async fn set_replaced(peer_id: ClientId) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+
+    let reason = config
+        .get_peer_state(peer_id)
+        .await?
+        .map(|state| state.reason)
+        .unwrap_or_default();
+    config.set_peer_state(peer_id, PeerState::Replaced, &reason).await?;
+    log!("[replicate] data of peer {} was replicated", hex::encode(peer_id));
+
+    Ok(())
+}
+
+/// Fetch packfiles from the other peers storing them, and copy the received ones into the buffer.
+/// Returns the packfiles that were received.
+async fn fetch_packfiles(
+    holders: &HashMap<PackfileId, Vec<ClientId>>,
+    buffer: &Path,
+) -> anyhow::Result<HashSet<PackfileId>> {
+    let orchestrator =
+        RestoreOrchestrator::start_in_restore_folder(FolderUse::Fetch, "replicating data").await?;
+
+    let folder = CONFIG.get().unwrap().get_restored_packfiles_folder()?;
+    let result = async {
+        let packfiles: HashSet<PackfileId> = holders.keys().copied().collect();
+        let mut peers: Vec<ClientId> = holders.values().flatten().copied().collect();
+        peers.sort_unstable();
+        peers.dedup();
+
+        orchestrator.expect_packfiles(&packfiles).await?;
+        let request = RestoreSelectedRequest {
+            packfiles: packfiles.iter().copied().collect(),
+            include_index: false,
+        };
+        fetch_from_peers(&peers, RequestType::RestoreSelected(request)).await?;
+
+        let mut fetched = HashSet::new();
+        for packfile_id in packfiles {
+            if copy_packfile(&get_packfile_path(&folder, packfile_id, false)?, buffer, packfile_id).await? {
+                fetched.insert(packfile_id);
+            }
+        }
+
+        anyhow::Ok(fetched)
+    }
+    .await;
+
+    orchestrator.end_fetch();
+    RestoreOrchestrator::remove_restore_folder().await?;
+
+    result
+}
+
+/// Copy a packfile into the buffer of packfiles to send, returns false if it doesn't exist.
+async fn copy_packfile(source: &Path, buffer: &Path, packfile_id: PackfileId) -> anyhow::Result<bool> {
+    if !source.try_exists()? {
+        return Ok(false);
+    }
+
+    fs::copy(source, get_packfile_path(buffer, packfile_id, true)?).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_audits_make_peers_lost() {
+        let config = crate::config::init_test_config().await;
+        let peer_id = [6; 32];
+        config
+            .add_or_increment_peer_storage(peer_id, 1_000_000)
+            .await
+            .unwrap();
+        assert!(lost_reason(peer_id).await.unwrap().is_none());
+
+        // the peer was seen just now, only the audits can make it lost
+        for failed in 1..=PEER_LOST_FAILED_AUDITS {
+            config.add_audit_result(peer_id, [7; 12], false).await.unwrap();
+            assert_eq!(lost_reason(peer_id).await.unwrap().is_some(), failed == PEER_LOST_FAILED_AUDITS);
+        }
+
+        // a passed audit breaks the series of failed ones
+        config.add_audit_result(peer_id, [7; 12], true).await.unwrap();
+        assert!(lost_reason(peer_id).await.unwrap().is_none());
+    }
+}
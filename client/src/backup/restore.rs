@@ -0,0 +1,657 @@
+//! Contains the logic for restoring backups, requesting files from peers and unpacking them.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use cast::From;
+use human_bytes::human_bytes;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use shared::{
+    p2p_message::{RequestType, RestoreSelectedRequest},
+    server_message::BackupRestoreInfo,
+    types::{BlobHash, ClientId, PackfileId},
+};
+use tokio::{
+    sync::mpsc::{self, UnboundedSender},
+    time::sleep,
+};
+
+use crate::{
+    backup::{
+        browse, erasure,
+        filesystem::{
+            dir_unpacker,
+            dir_unpacker::{OverwritePolicy, StreamingUnpacker, UnpackEvent, UnrestoredEntry},
+            file_utils::get_packfile_path,
+            packfile, Tree, TreeKind,
+        },
+        restore_orchestrator::{PeerRestoreStatus, RestoreOrchestrator},
+        RESTORE_ORCHESTRATOR,
+    },
+    config::Config,
+    defaults::{
+        INDEX_FOLDER, RESTORE_PEER_RETRIES, RESTORE_PEER_RETRY_DELAY, RESTORE_PEER_TIMEOUT,
+        RESTORE_PROGRESS_INTERVAL, RESTORE_REPORT_LOG_LIMIT,
+    },
+    log,
+    net_server::requests,
+    CONFIG, P2P_CONN_REQUESTS, UI,
+};
+
+/// Options selecting what to restore.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct RestoreOptions {
+    /// A file or directory inside the snapshot to restore, relative to the backup path. The whole
+    /// snapshot is restored if not set.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// The directory to restore into, the backup path is used if not set.
+    #[serde(default)]
+    pub destination: Option<PathBuf>,
+    /// What to do with files that already exist at the destination.
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
+}
+
+impl RestoreOptions {
+    /// Returns the selected path inside the snapshot, ensuring it can't point outside of it.
+    fn snapshot_path(&self) -> anyhow::Result<Option<&Path>> {
+        match &self.path {
+            Some(path) if path.as_os_str().is_empty() => Ok(None),
+            Some(path) if path.components().all(|c| matches!(c, Component::Normal(_))) => {
+                Ok(Some(path.as_path()))
+            }
+            Some(path) => bail!("restore path {path:?} has to be relative to the backup path"),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the directory to restore into.
+    async fn destination(&self) -> anyhow::Result<PathBuf> {
+        match &self.destination {
+            Some(destination) if !destination.as_os_str().is_empty() => Ok(destination.clone()),
+            _ => CONFIG
+                .get()
+                .unwrap()
+                .get_backup_path()
+                .await?
+                .ok_or(anyhow!("backup path not set")),
+        }
+    }
+}
+
+/// A restore that was started but didn't finish, it can be resumed.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct InterruptedRestore {
+    /// The hex encoded hash of the snapshot being restored.
+    pub snapshot_hash: String,
+    pub options: RestoreOptions,
+}
+
+/// Initialize the restore process, requesting files from peers and unpacking them.
+pub async fn request_restore(options: RestoreOptions) -> anyhow::Result<()> {
+    start_restore(options, None).await
+}
+
+/// Resume the interrupted restore, skipping the packfiles and files restored so far.
+pub async fn resume_restore() -> anyhow::Result<()> {
+    let restore = CONFIG
+        .get()
+        .unwrap()
+        .get_interrupted_restore()
+        .await?
+        .ok_or(anyhow!("there is no interrupted restore to resume"))?;
+    let snapshot_hash = hex::decode(&restore.snapshot_hash)?
+        .try_into()
+        .map_err(|_| anyhow!("invalid snapshot hash {}", restore.snapshot_hash))?;
+
+    start_restore(restore.options, Some(snapshot_hash)).await
+}
+
+/// Run a new restore, or resume a restore of the given snapshot.
+async fn start_restore(options: RestoreOptions, resume_snapshot: Option<BlobHash>) -> anyhow::Result<()> {
+    browse::reset().await;
+    RestoreOrchestrator::initialize_static().await?;
+    RESTORE_ORCHESTRATOR.get().unwrap().set_started()?;
+
+    let progress_reporter = tokio::spawn(report_progress());
+    let result = match run_restore(options, resume_snapshot).await {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            RESTORE_ORCHESTRATOR.get().unwrap().set_finished(false, e.to_string());
+            Err(anyhow!("restore failed: {e}"))
+        }
+    };
+
+    progress_reporter.abort();
+
+    // let the user know if the restore can be resumed
+    UI.get()
+        .unwrap()
+        .send_interrupted_restore(CONFIG.get().unwrap().get_interrupted_restore().await?);
+
+    result
+}
+
+/// Periodically send the restore progress to the user interface.
+async fn report_progress() {
+    let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+    loop {
+        UI.get().unwrap().send_restore_progress(orchestrator.progress().await);
+        sleep(Duration::from_secs(RESTORE_PROGRESS_INTERVAL)).await;
+    }
+}
+
+/// Run the actual restore procedure, resuming the interrupted restore of a snapshot if given.
+pub async fn run_restore(options: RestoreOptions, resume_snapshot: Option<BlobHash>) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+
+    let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+    orchestrator.set_started()?;
+
+    let snapshot_path = options.snapshot_path()?;
+    let destination = options.destination().await?;
+
+    // retrieve the snapshot id and contacted peers from the server
+    let BackupRestoreInfo { snapshot_hash: latest_snapshot, peers } = requests::backup_restore().await?;
+
+    let folder = config.get_restored_packfiles_folder()?;
+    let (snapshot_hash, resumed_packfiles, restored_files) = match resume_snapshot {
+        Some(snapshot_hash) => {
+            // packfiles that were received, but not unpacked and deleted yet, are not fetched again
+            let mut packfiles = Vec::new();
+            for packfile_id in config.get_restored_packfiles().await? {
+                if get_packfile_path(&folder, packfile_id, false)?.try_exists()? {
+                    packfiles.push(packfile_id);
+                }
+            }
+
+            let files = config.get_restored_files().await?;
+            log!(
+                "[restore] resuming the restore, {} files and {} packfiles were already received",
+                files.len(),
+                packfiles.len()
+            );
+            (snapshot_hash, packfiles, files)
+        }
+        None => {
+            // leftovers of an interrupted restore are not used, start from scratch
+            if folder.try_exists()? {
+                tokio::fs::remove_dir_all(&folder).await?;
+            }
+
+            let restore = InterruptedRestore {
+                snapshot_hash: hex::encode(latest_snapshot),
+                options: options.clone(),
+            };
+            config.begin_restore(&restore).await?;
+            (latest_snapshot, Vec::new(), HashMap::new())
+        }
+    };
+
+    for packfile_id in &resumed_packfiles {
+        orchestrator.claim_packfile(*packfile_id).await;
+    }
+
+    log!("[restore] restoring from snapshot {}", hex::encode(snapshot_hash));
+    let mut fetcher = SelectiveFetcher::new(peers).await?;
+    fetcher.fetched.extend(resumed_packfiles.iter().copied());
+
+    // files are restored while the packfiles are being received, so the whole backup doesn't have
+    // to be stored on disk at once
+    let (unpacker_sender, unpacker_receiver) = mpsc::unbounded_channel();
+    let unpacker = StreamingUnpacker::new(
+        fetcher.packer.clone(),
+        destination.clone(),
+        snapshot_path,
+        options.overwrite,
+        restored_files,
+    )
+    .await?;
+    orchestrator.set_unpacker(Some(unpacker_sender.clone())).await;
+    let unpacker = tokio::spawn(unpacker.run(unpacker_receiver));
+
+    for packfile_id in resumed_packfiles {
+        send_unpack_event(&unpacker_sender, UnpackEvent::Packfile(packfile_id))?;
+    }
+
+    UI.get().unwrap().set_pack_running(true);
+
+    match snapshot_path {
+        Some(path) => log!("[restore] now restoring {:?} to {:?}...", path, destination),
+        None => log!("[restore] now restoring files to {:?}...", destination),
+    }
+
+    let fetch_result = fetch_snapshot(&mut fetcher, &unpacker_sender, snapshot_hash, snapshot_path).await;
+
+    // let the unpacker finish, no more packfiles are coming
+    orchestrator.set_unpacker(None).await;
+    drop(unpacker_sender);
+    let unrestored = unpacker.await??;
+    fetch_result?;
+    let unavailable_peers = fetcher.unavailable;
+
+    if !unrestored.is_empty() {
+        report_unrestored(&unrestored, &unavailable_peers).await?;
+    }
+
+    let received_size = human_bytes(f64::cast(orchestrator.received_bytes()));
+
+    // the received data is kept, so the restore can be resumed once the missing peers are back
+    if !unrestored.is_empty() {
+        orchestrator.set_finished(
+            false,
+            format!(
+                "Restore incomplete, {} files or directories could not be restored, see the log for details. \
+                 The restore can be resumed later.\nReceived and unpacked {received_size} worth of data.",
+                unrestored.len()
+            ),
+        );
+
+        log!("[restore] restore finished, {} entries could not be restored", unrestored.len());
+        return Ok(());
+    }
+
+    log!("[restore] deleting temporary files...");
+    tokio::fs::remove_dir_all(config.get_restored_packfiles_folder()?).await?;
+    config.end_restore().await?;
+
+    orchestrator.set_finished(
+        true,
+        format!("Restore completed successfully!\nReceived and unpacked {received_size} worth of data."),
+    );
+
+    log!("[restore] restore completed successfully!");
+    Ok(())
+}
+
+/// Log the files and directories that could not be restored, along with the peers that store the
+/// missing data. Must be called before the received packfiles are deleted.
+async fn report_unrestored(
+    unrestored: &[UnrestoredEntry],
+    unavailable_peers: &[ClientId],
+) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+    let packer = packfile::Manager::new(config.get_restored_packfiles_folder()?).await?;
+
+    log!("[restore] {} files or directories could not be restored:", unrestored.len());
+    for entry in unrestored.iter().take(RESTORE_REPORT_LOG_LIMIT) {
+        if entry.missing_blobs.is_empty() {
+            log!("[restore]   {}: writing the file failed", entry.path.display());
+            continue;
+        }
+
+        let mut peers = HashSet::new();
+        for blob_hash in &entry.missing_blobs {
+            let locations = match packer.find_packfile(blob_hash).await {
+                Some(packfile_id) => config.get_packfile_locations(packfile_id).await?,
+                None => Vec::new(),
+            };
+
+            // without a record of where the data was sent, it can be with any of the missing peers
+            if locations.is_empty() {
+                peers.extend(unavailable_peers.iter().copied());
+            } else {
+                peers.extend(locations.iter().map(|l| l.peer_id));
+            }
+        }
+
+        let peers =
+            if peers.is_empty() { String::from("unknown") } else { peers.iter().map(hex::encode).join(", ") };
+        log!("[restore]   {}: data missing, stored by peers {peers}", entry.path.display());
+    }
+
+    if unrestored.len() > RESTORE_REPORT_LOG_LIMIT {
+        log!("[restore]   ...and {} more", unrestored.len() - RESTORE_REPORT_LOG_LIMIT);
+    }
+
+    Ok(())
+}
+
+/// Walk the trees of a snapshot, or only of the selected path in it, passing the directories and
+/// files to restore to the unpacker, then fetch the packfiles with the file contents. The trees are
+/// walked one level at a time, each level requires another round of requests, as we can't know
+/// which blobs are needed before we have the parent trees.
+pub async fn fetch_snapshot(
+    fetcher: &mut SelectiveFetcher,
+    unpacker: &UnboundedSender<UnpackEvent>,
+    snapshot_hash: BlobHash,
+    path: Option<&Path>,
+) -> anyhow::Result<()> {
+    let path = path.unwrap_or(Path::new(""));
+    let tree = fetcher.resolve_path(snapshot_hash, path).await?;
+
+    // packfiles with file contents are fetched only after all trees are known
+    let mut data_packfiles = HashSet::new();
+    let mut level = Vec::new();
+    match tree.kind {
+        TreeKind::File => {
+            data_packfiles.extend(fetcher.packfiles_to_fetch(&tree.children).await?);
+            send_unpack_event(unpacker, UnpackEvent::File(path.to_path_buf(), tree))?;
+        }
+        TreeKind::Dir => {
+            // the root directory of the restore is not touched
+            if !path.as_os_str().is_empty() {
+                send_unpack_event(unpacker, UnpackEvent::Dir(path.to_path_buf(), tree.clone()))?;
+            }
+            level.push((path.to_path_buf(), tree));
+        }
+    }
+
+    // all children of dir type tree are trees, all children of file type tree are chunks
+    while !level.is_empty() {
+        let children: Vec<BlobHash> = level.iter().flat_map(|(_, tree)| tree.children.clone()).collect();
+        fetcher.fetch_blobs(&children).await?;
+
+        let mut next_level = Vec::new();
+        for (dir_path, dir_tree) in &level {
+            for hash in &dir_tree.children {
+                // missing trees are reported as unrestored, restore everything else
+                let child_tree = match fetcher.fetch_full_tree(hash).await {
+                    Ok(tree) => tree,
+                    Err(e) => {
+                        log!("[restore] cannot fetch a tree in {:?}: {}", dir_path, e);
+                        let entry = UnrestoredEntry { path: dir_path.clone(), missing_blobs: vec![*hash] };
+                        send_unpack_event(unpacker, UnpackEvent::Unrestored(entry))?;
+                        continue;
+                    }
+                };
+
+                let child_path = dir_path.join(&child_tree.name);
+                match child_tree.kind {
+                    TreeKind::File => {
+                        data_packfiles.extend(fetcher.packfiles_to_fetch(&child_tree.children).await?);
+                        send_unpack_event(unpacker, UnpackEvent::File(child_path, child_tree))?;
+                    }
+                    TreeKind::Dir => {
+                        send_unpack_event(
+                            unpacker,
+                            UnpackEvent::Dir(child_path.clone(), child_tree.clone()),
+                        )?;
+                        next_level.push((child_path, child_tree));
+                    }
+                }
+            }
+        }
+
+        level = next_level;
+    }
+
+    send_unpack_event(unpacker, UnpackEvent::PlanComplete)?;
+
+    log!("[restore] fetching {} packfiles with file contents", data_packfiles.len());
+    fetcher.fetch_packfiles(data_packfiles).await?;
+
+    log!("[restore] fetched {} packfiles needed for the restore", fetcher.fetched.len());
+    Ok(())
+}
+
+/// Pass an event to the unpacker, which only stops early on an error.
+fn send_unpack_event(unpacker: &UnboundedSender<UnpackEvent>, event: UnpackEvent) -> anyhow::Result<()> {
+    unpacker
+        .send(event)
+        .map_err(|_| anyhow!("unpacking the restored files failed"))
+}
+
+/// Fetches packfiles from peers on demand, only the ones containing the requested blobs.
+pub struct SelectiveFetcher {
+    peers: Vec<ClientId>,
+    pub packer: packfile::Manager,
+    fetched: HashSet<PackfileId>,
+    /// Peers that could not provide their data, they are not asked again.
+    pub unavailable: Vec<ClientId>,
+}
+
+impl SelectiveFetcher {
+    /// Load the index, so we know which packfiles to ask for. The local backup index is used if it
+    /// exists, otherwise the index is fetched from peers.
+    pub async fn new(peers: Vec<ClientId>) -> anyhow::Result<Self> {
+        let config = CONFIG.get().unwrap();
+        let folder = config.get_restored_packfiles_folder()?;
+
+        let unavailable = if copy_local_index(&folder).await? {
+            Vec::new()
+        } else {
+            let request = RestoreSelectedRequest { packfiles: Vec::new(), include_index: true };
+            fetch_from_peers(&peers, RequestType::RestoreSelected(request)).await?
+        };
+
+        let packer =
+            packfile::Manager::with_metadata_cache(folder, Config::get_metadata_cache_folder()?).await?;
+        let peers = peers.into_iter().filter(|peer| !unavailable.contains(peer)).collect();
+        Ok(Self {
+            peers,
+            packer,
+            fetched: HashSet::new(),
+            unavailable,
+        })
+    }
+
+    /// Fetch all packfiles containing the given blobs that were not fetched yet.
+    pub async fn fetch_blobs(&mut self, blobs: impl IntoIterator<Item = &BlobHash>) -> anyhow::Result<()> {
+        let needed = self.packfiles_to_fetch(blobs).await?;
+        self.fetch_packfiles(needed).await
+    }
+
+    /// Returns the packfiles containing the given blobs that were not fetched yet. Blobs that are not
+    /// in the index are skipped, they are reported when unpacking. Metadata packfiles kept in the
+    /// local cache are never fetched.
+    pub async fn packfiles_to_fetch(
+        &mut self,
+        blobs: impl IntoIterator<Item = &BlobHash>,
+    ) -> anyhow::Result<HashSet<PackfileId>> {
+        let mut needed = HashSet::new();
+        for blob_hash in blobs {
+            match self.packer.find_packfile(blob_hash).await {
+                Some(packfile_id) if !self.fetched.contains(&packfile_id) => {
+                    if self.packer.get_cached_packfile_path(packfile_id).await?.is_some() {
+                        self.fetched.insert(packfile_id);
+                    } else {
+                        needed.insert(packfile_id);
+                    }
+                }
+                Some(_) => {}
+                None => log!("[restore] blob {} not found in index", hex::encode(blob_hash)),
+            }
+        }
+
+        Ok(needed)
+    }
+
+    /// Fetch the given packfiles from all peers.
+    async fn fetch_packfiles(&mut self, needed: HashSet<PackfileId>) -> anyhow::Result<()> {
+        if needed.is_empty() {
+            return Ok(());
+        }
+
+        let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+        orchestrator.expect_packfiles(&needed).await?;
+
+        let request = RestoreSelectedRequest {
+            packfiles: needed.iter().copied().collect(),
+            include_index: false,
+        };
+        let unavailable = fetch_from_peers(&self.peers, RequestType::RestoreSelected(request)).await?;
+        self.peers.retain(|peer| !unavailable.contains(peer));
+        self.unavailable.extend(unavailable);
+
+        // erasure coded packfiles are only available if enough of their shards were received
+        let folder = CONFIG.get().unwrap().get_restored_packfiles_folder()?;
+        for packfile_id in &needed {
+            if !orchestrator.is_packfile_received(*packfile_id).await {
+                log!("[restore] warning: {}", erasure::describe_missing(&folder, *packfile_id)?);
+            }
+        }
+
+        // packfiles that were not received are not requested again
+        self.fetched.extend(needed);
+
+        Ok(())
+    }
+
+    /// Find the tree of a path inside a snapshot, fetching the trees on the way.
+    pub async fn resolve_path(&mut self, snapshot_hash: BlobHash, path: &Path) -> anyhow::Result<Tree> {
+        let mut tree = self.fetch_full_tree(&snapshot_hash).await?;
+        for component in path {
+            if tree.kind != TreeKind::Dir {
+                bail!("path {path:?} not found in snapshot, {:?} is not a directory", tree.name);
+            }
+
+            self.fetch_blobs(&tree.children).await?;
+
+            let mut found = None;
+            for hash in &tree.children {
+                let child_tree = self.fetch_full_tree(hash).await?;
+                if child_tree.name.as_str() == component {
+                    found = Some(child_tree);
+                    break;
+                }
+            }
+
+            tree = found.ok_or(anyhow!("path {path:?} not found in snapshot"))?;
+        }
+
+        Ok(tree)
+    }
+
+    /// Fetch a tree and all its siblings, along with the packfiles containing them.
+    pub async fn fetch_full_tree(&mut self, hash: &BlobHash) -> anyhow::Result<Tree> {
+        self.fetch_blobs([hash]).await?;
+        let mut tree = dir_unpacker::fetch_tree(self.packer.clone(), hash).await?;
+
+        let mut next_hash = tree.next_sibling;
+        while let Some(hash) = &next_hash {
+            self.fetch_blobs([hash]).await?;
+            let mut partial_tree = dir_unpacker::fetch_tree(self.packer.clone(), hash).await?;
+            tree.children.append(&mut partial_tree.children);
+            next_hash = partial_tree.next_sibling;
+        }
+
+        Ok(tree)
+    }
+}
+
+/// Copy the index files of the local backup into the restore folder, returns false if there are none,
+/// for example when restoring on a new device.
+async fn copy_local_index(folder: &Path) -> anyhow::Result<bool> {
+    let local_index = CONFIG.get().unwrap().get_packfile_path()?.join(INDEX_FOLDER);
+    if !local_index.try_exists()? {
+        return Ok(false);
+    }
+
+    let mut copied = false;
+    tokio::fs::create_dir_all(folder.join(INDEX_FOLDER)).await?;
+    for file in local_index.read_dir()? {
+        let file = file?;
+        if file.file_type()?.is_file() {
+            tokio::fs::copy(file.path(), folder.join(INDEX_FOLDER).join(file.file_name())).await?;
+            copied = true;
+        }
+    }
+
+    Ok(copied)
+}
+
+/// Send a restore request to all given peers, and wait until their files are received into the
+/// restore folder. Requests to peers that fail or don't send anything for a while are retried a few
+/// times, then the peer is given up on. Returns the peers that could not provide their data. The
+/// restore orchestrator has to be in the started state.
+pub async fn fetch_from_peers(peers: &[ClientId], request: RequestType) -> anyhow::Result<Vec<ClientId>> {
+    let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+
+    // request all files from all peers
+    for peer in peers {
+        request_restore_from_peer(*peer, request.clone()).await;
+    }
+
+    let mut attempts: HashMap<ClientId, u32> = peers.iter().map(|peer| (*peer, 1)).collect();
+    let mut retry_at: HashMap<ClientId, Instant> = HashMap::new();
+    let mut unavailable = Vec::new();
+
+    // we will now wait for the requests to complete in the background
+    loop {
+        if !orchestrator.is_running() {
+            bail!("restore is no longer running");
+        }
+
+        let mut waiting = false;
+        for peer in peers {
+            if unavailable.contains(peer) {
+                continue;
+            }
+
+            if let Some(time) = retry_at.get(peer) {
+                if Instant::now() >= *time {
+                    retry_at.remove(peer);
+                    *attempts.entry(*peer).or_default() += 1;
+                    request_restore_from_peer(*peer, request.clone()).await;
+                }
+
+                waiting = true;
+                continue;
+            }
+
+            let reason = match orchestrator.peer_status(*peer).await {
+                Some((PeerRestoreStatus::Completed, _)) => continue,
+                Some((PeerRestoreStatus::Pending, idle))
+                    if idle < Duration::from_secs(RESTORE_PEER_TIMEOUT) =>
+                {
+                    waiting = true;
+                    continue;
+                }
+                Some((PeerRestoreStatus::Pending, _)) => String::from("timed out"),
+                Some((PeerRestoreStatus::Failed(reason), _)) => reason,
+                None => String::from("unknown peer"),
+            };
+
+            if attempts.get(peer).copied().unwrap_or_default() > RESTORE_PEER_RETRIES {
+                log!("[restore] giving up on peer {}: {}", hex::encode(peer), reason);
+                unavailable.push(*peer);
+            } else {
+                log!(
+                    "[restore] request to peer {} failed ({}), retrying in {} s",
+                    hex::encode(peer),
+                    reason,
+                    RESTORE_PEER_RETRY_DELAY
+                );
+                retry_at.insert(*peer, Instant::now() + Duration::from_secs(RESTORE_PEER_RETRY_DELAY));
+                waiting = true;
+            }
+        }
+
+        if !waiting {
+            break;
+        }
+
+        sleep(Duration::from_secs(1)).await;
+    }
+
+    if !peers.is_empty() && unavailable.len() == peers.len() {
+        bail!("none of the peers could provide their data");
+    }
+
+    Ok(unavailable)
+}
+
+/// Request files from a peer for restoration, failures are recorded in the restore orchestrator.
+async fn request_restore_from_peer(peer_id: ClientId, request: RequestType) {
+    let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+    log!("[restore] requesting files from peer {}", hex::encode(peer_id));
+    orchestrator.add_peer(peer_id).await;
+
+    let result = async {
+        let nonce = P2P_CONN_REQUESTS.get().unwrap().add_request(peer_id, request).await?;
+        requests::p2p_connection_begin(peer_id, nonce).await
+    };
+
+    match result.await {
+        Ok(true) => {}
+        Ok(false) => orchestrator.fail_peer(peer_id, "peer is not connected").await,
+        Err(e) => orchestrator.fail_peer(peer_id, e.to_string()).await,
+    }
+}
@@ -1,21 +1,108 @@
 //! Implements coordination between various parts of the restore process.
 
-use std::{collections::HashMap, sync::atomic::AtomicBool};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
 use anyhow::bail;
-use shared::types::ClientId;
-use tokio::sync::Mutex;
+use shared::types::{ClientId, PackfileId};
+use tokio::sync::{mpsc::UnboundedSender, Mutex};
 
 use crate::{
-    backup::{BACKUP_ORCHESTRATOR, RESTORE_ORCHESTRATOR},
-    UI,
+    backup::{browse, filesystem::dir_unpacker::UnpackEvent, BACKUP_ORCHESTRATOR, RESTORE_ORCHESTRATOR},
+    ui::ws_status_message::{Messenger, PeerRestoreProgress, RestoreProgress},
+    CONFIG, UI,
 };
 
 /// Stores the state of the restore process, and provides options for
 /// different moving parts to communicate with each other.
 pub struct RestoreOrchestrator {
     restore_running: AtomicBool,
-    peer_restore_status: Mutex<HashMap<ClientId, bool>>,
+    peer_restore_status: Mutex<HashMap<ClientId, PeerRestoreState>>,
+    /// Packfiles received from any peer, the same packfile can be stored by multiple peers.
+    received_packfiles: Mutex<HashSet<PackfileId>>,
+    received_bytes: AtomicU64,
+    /// Notified about received packfiles while a restore is unpacking.
+    unpacker: Mutex<Option<UnboundedSender<UnpackEvent>>>,
+    progress: Mutex<ProgressState>,
+}
+
+/// Counters for reporting the restore progress.
+#[derive(Debug)]
+struct ProgressState {
+    started: Instant,
+    packfiles_received: u64,
+    packfiles_expected: u64,
+    files_total: u64,
+    files_done: u64,
+    bytes_total: u64,
+    bytes_done: u64,
+    /// Set once all files to restore are known.
+    total_known: bool,
+}
+
+impl Default for ProgressState {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            packfiles_received: 0,
+            packfiles_expected: 0,
+            files_total: 0,
+            files_done: 0,
+            bytes_total: 0,
+            bytes_done: 0,
+            total_known: false,
+        }
+    }
+}
+
+/// What the folder for received packfiles is taken over for, other than a restore.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FolderUse {
+    /// A task shown in the user interface like a restore, such as a check or an export. It has to
+    /// be finished with `set_finished`.
+    Task,
+    /// Fetching data without reporting it, such as for replication. It has to be ended with
+    /// `end_fetch`.
+    Fetch,
+    /// Starting a browsing session, which keeps its data in the folder. Fetching has to be ended
+    /// with `end_fetch`.
+    Browse,
+}
+
+/// The state of receiving the restore data from a single peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerRestoreStatus {
+    /// Waiting for the peer to send its data.
+    Pending,
+    /// The peer has sent all requested data.
+    Completed,
+    /// Receiving data from the peer failed, with the reason.
+    Failed(String),
+}
+
+#[derive(Debug)]
+struct PeerRestoreState {
+    status: PeerRestoreStatus,
+    last_activity: Instant,
+    bytes_received: u64,
+    packfiles_received: u64,
+    /// The number of packfiles and shards requested from the peer, that it's known to store.
+    packfiles_expected: u64,
+}
+
+impl PeerRestoreState {
+    fn new() -> Self {
+        Self {
+            status: PeerRestoreStatus::Pending,
+            last_activity: Instant::now(),
+            bytes_received: 0,
+            packfiles_received: 0,
+            packfiles_expected: 0,
+        }
+    }
 }
 
 impl RestoreOrchestrator {
@@ -27,12 +114,19 @@ impl RestoreOrchestrator {
                     bail!("restore already running");
                 }
                 orchestrator.peer_restore_status.lock().await.clear();
+                orchestrator.received_packfiles.lock().await.clear();
+                orchestrator.received_bytes.store(0, Ordering::Relaxed);
+                *orchestrator.progress.lock().await = ProgressState::default();
             }
             None => {
                 RESTORE_ORCHESTRATOR
                     .set(RestoreOrchestrator {
                         restore_running: AtomicBool::new(false),
                         peer_restore_status: Mutex::new(HashMap::new()),
+                        received_packfiles: Mutex::new(HashSet::new()),
+                        received_bytes: AtomicU64::new(0),
+                        unpacker: Mutex::new(None),
+                        progress: Mutex::new(ProgressState::default()),
                     })
                     .map_err(|_| anyhow::anyhow!("failed to initialize restore orchestrator"))?;
             }
@@ -41,6 +135,47 @@ impl RestoreOrchestrator {
         Ok(())
     }
 
+    /// Take over the folder for received packfiles, which is shared by restores and everything else
+    /// fetching data from peers, and empty it. Fails if an interrupted restore has to be resumed
+    /// before the given task, or if the folder is in use. Unless a browsing session is starting, the
+    /// current one is discarded, as its data is in the folder as well.
+    pub async fn start_in_restore_folder(usage: FolderUse, task: &str) -> anyhow::Result<&'static Self> {
+        if CONFIG.get().unwrap().get_interrupted_restore().await?.is_some() {
+            bail!("an interrupted restore has to be resumed before {task}");
+        }
+
+        Self::initialize_static().await?;
+        let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+        match usage {
+            FolderUse::Task => orchestrator.set_started()?,
+            FolderUse::Fetch | FolderUse::Browse => orchestrator.begin_fetch()?,
+        }
+
+        if usage != FolderUse::Browse {
+            browse::reset().await;
+        }
+
+        if let Err(e) = Self::remove_restore_folder().await {
+            match usage {
+                FolderUse::Task => orchestrator.set_finished(false, e.to_string()),
+                FolderUse::Fetch | FolderUse::Browse => orchestrator.end_fetch(),
+            }
+            return Err(e);
+        }
+
+        Ok(orchestrator)
+    }
+
+    /// Remove the folder for received packfiles along with everything in it, if it exists.
+    pub async fn remove_restore_folder() -> anyhow::Result<()> {
+        let folder = CONFIG.get().unwrap().get_restored_packfiles_folder()?;
+        if folder.try_exists()? {
+            tokio::fs::remove_dir_all(&folder).await?;
+        }
+
+        Ok(())
+    }
+
     /// Set the restore to started state.
     pub fn set_started(&self) -> anyhow::Result<()> {
         if let Some(orchestrator) = BACKUP_ORCHESTRATOR.get() {
@@ -49,9 +184,11 @@ impl RestoreOrchestrator {
             }
         }
 
-        UI.get().unwrap().send_restore_started();
-        self.restore_running.store(true, std::sync::atomic::Ordering::Relaxed);
+        if self.restore_running.swap(true, Ordering::Relaxed) {
+            bail!("restore already running");
+        }
 
+        UI.get().unwrap().send_restore_started();
         Ok(())
     }
 
@@ -62,26 +199,211 @@ impl RestoreOrchestrator {
             .store(false, std::sync::atomic::Ordering::Relaxed);
     }
 
+    /// Start fetching data from peers outside of a restore, such as when browsing a snapshot,
+    /// without reporting it to the user interface.
+    pub fn begin_fetch(&self) -> anyhow::Result<()> {
+        if let Some(orchestrator) = BACKUP_ORCHESTRATOR.get() {
+            if orchestrator.is_backup_running() {
+                bail!("backup running, cannot fetch data from peers");
+            }
+        }
+
+        if self.restore_running.swap(true, Ordering::Relaxed) {
+            bail!("restore already running");
+        }
+
+        Ok(())
+    }
+
+    /// Finish fetching data started by `begin_fetch`.
+    pub fn end_fetch(&self) {
+        self.restore_running.store(false, Ordering::Relaxed);
+    }
+
     /// Check if the restore is running.
     pub fn is_running(&self) -> bool {
         self.restore_running.load(std::sync::atomic::Ordering::Relaxed)
     }
 
-    /// Add a peer to the restore orchestrator.
+    /// Add a peer to the restore orchestrator, or reset its state when the request is retried.
     pub async fn add_peer(&self, client_id: ClientId) {
         let mut peer_restore_status = self.peer_restore_status.lock().await;
-        peer_restore_status.insert(client_id, false);
+        let state = peer_restore_status
+            .entry(client_id)
+            .or_insert_with(PeerRestoreState::new);
+        state.status = PeerRestoreStatus::Pending;
+        state.last_activity = Instant::now();
+    }
+
+    /// Record that some data has been received from a peer.
+    pub async fn peer_activity(&self, client_id: ClientId) {
+        if let Some(state) = self.peer_restore_status.lock().await.get_mut(&client_id) {
+            state.last_activity = Instant::now();
+        }
+    }
+
+    /// Record that a packfile or a shard has been received from a peer.
+    pub async fn peer_received(&self, client_id: ClientId, bytes: u64) {
+        if let Some(state) = self.peer_restore_status.lock().await.get_mut(&client_id) {
+            state.last_activity = Instant::now();
+            state.bytes_received += bytes;
+            state.packfiles_received += 1;
+        }
+        self.received_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Record that packfiles are about to be requested, along with the peers known to store them.
+    pub async fn expect_packfiles(&self, packfiles: &HashSet<PackfileId>) -> anyhow::Result<()> {
+        let config = CONFIG.get().unwrap();
+        let mut expected: HashMap<ClientId, u64> = HashMap::new();
+        for packfile_id in packfiles {
+            for location in config.get_packfile_locations(*packfile_id).await? {
+                *expected.entry(location.peer_id).or_default() += 1;
+            }
+        }
+
+        let mut peer_restore_status = self.peer_restore_status.lock().await;
+        for (peer_id, count) in expected {
+            peer_restore_status
+                .entry(peer_id)
+                .or_insert_with(PeerRestoreState::new)
+                .packfiles_expected += count;
+        }
+
+        self.progress.lock().await.packfiles_expected += packfiles.len() as u64;
+        Ok(())
+    }
+
+    /// Record a file to be restored, of the given size.
+    pub async fn add_unpack_total(&self, bytes: u64) {
+        let mut progress = self.progress.lock().await;
+        progress.files_total += 1;
+        progress.bytes_total += bytes;
+    }
+
+    /// Record a restored file, of the given size.
+    pub async fn add_unpack_done(&self, bytes: u64) {
+        let mut progress = self.progress.lock().await;
+        progress.files_done += 1;
+        progress.bytes_done += bytes;
+    }
+
+    /// Record that all files to restore are known.
+    pub async fn set_unpack_total_known(&self) {
+        self.progress.lock().await.total_known = true;
+    }
+
+    /// Get the current progress of the restore.
+    pub async fn progress(&self) -> RestoreProgress {
+        let mut peers: Vec<PeerRestoreProgress> = self
+            .peer_restore_status
+            .lock()
+            .await
+            .iter()
+            .map(|(id, state)| PeerRestoreProgress {
+                id: Messenger::peer_id_display(id),
+                status: match &state.status {
+                    PeerRestoreStatus::Pending => String::from("receiving"),
+                    PeerRestoreStatus::Completed => String::from("completed"),
+                    PeerRestoreStatus::Failed(reason) => format!("failed: {reason}"),
+                },
+                bytes_received: state.bytes_received,
+                packfiles_received: state.packfiles_received,
+                packfiles_expected: state.packfiles_expected,
+            })
+            .collect();
+        peers.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let progress = self.progress.lock().await;
+
+        // the remaining time is estimated from the rate of restoring file contents so far
+        let eta_seconds = if progress.total_known && progress.bytes_done > 0 {
+            let elapsed = progress.started.elapsed().as_millis();
+            let remaining = u128::from(progress.bytes_total.saturating_sub(progress.bytes_done));
+            u64::try_from(elapsed * remaining / u128::from(progress.bytes_done) / 1000).ok()
+        } else {
+            None
+        };
+
+        RestoreProgress {
+            peers,
+            bytes_received: self.received_bytes(),
+            packfiles_received: progress.packfiles_received,
+            packfiles_expected: progress.packfiles_expected,
+            files_done: progress.files_done,
+            files_total: progress.files_total,
+            bytes_done: progress.bytes_done,
+            bytes_total: progress.bytes_total,
+            total_known: progress.total_known,
+            eta_seconds,
+        }
     }
 
     /// Mark a peer as completed.
     pub async fn complete_peer(&self, client_id: ClientId) {
-        let mut peer_restore_status = self.peer_restore_status.lock().await;
-        peer_restore_status.insert(client_id, true);
+        self.set_peer_status(client_id, PeerRestoreStatus::Completed).await;
+    }
+
+    /// Mark a peer as failed, with the reason.
+    pub async fn fail_peer(&self, client_id: ClientId, reason: impl Into<String>) {
+        self.set_peer_status(client_id, PeerRestoreStatus::Failed(reason.into()))
+            .await;
+    }
+
+    async fn set_peer_status(&self, client_id: ClientId, status: PeerRestoreStatus) {
+        if let Some(state) = self.peer_restore_status.lock().await.get_mut(&client_id) {
+            state.status = status;
+            state.last_activity = Instant::now();
+        }
+    }
+
+    /// Get the status of a peer and the time since any data has been received from it.
+    pub async fn peer_status(&self, client_id: ClientId) -> Option<(PeerRestoreStatus, Duration)> {
+        self.peer_restore_status
+            .lock()
+            .await
+            .get(&client_id)
+            .map(|state| (state.status.clone(), state.last_activity.elapsed()))
+    }
+
+    /// Claim a packfile before saving it, returns false if it was already received from another
+    /// peer and should be skipped.
+    pub async fn claim_packfile(&self, packfile_id: PackfileId) -> bool {
+        self.received_packfiles.lock().await.insert(packfile_id)
+    }
+
+    /// Release a claimed packfile that could not be saved, so it can be received from another peer.
+    pub async fn release_packfile(&self, packfile_id: PackfileId) {
+        self.received_packfiles.lock().await.remove(&packfile_id);
+    }
+
+    /// Check if a packfile has been received from any peer.
+    pub async fn is_packfile_received(&self, packfile_id: PackfileId) -> bool {
+        self.received_packfiles.lock().await.contains(&packfile_id)
+    }
+
+    /// Record a saved packfile, notifying the unpacker if there is one.
+    pub async fn packfile_saved(&self, packfile_id: PackfileId) -> anyhow::Result<()> {
+        self.progress.lock().await.packfiles_received += 1;
+
+        if let Some(unpacker) = &*self.unpacker.lock().await {
+            // the packfile doesn't have to be received again if the restore is interrupted
+            CONFIG.get().unwrap().add_restored_packfile(packfile_id).await?;
+
+            // the unpacker only stops on an error, which is reported by the restore
+            let _ = unpacker.send(UnpackEvent::Packfile(packfile_id));
+        }
+
+        Ok(())
+    }
+
+    /// Set or remove the unpacker notified about received packfiles.
+    pub async fn set_unpacker(&self, unpacker: Option<UnboundedSender<UnpackEvent>>) {
+        *self.unpacker.lock().await = unpacker;
     }
 
-    /// Check if all peers have completed.
-    pub async fn all_peers_completed(&self) -> bool {
-        let peer_restore_status = self.peer_restore_status.lock().await;
-        peer_restore_status.values().all(|v| *v)
+    /// Get the number of bytes received from peers during the restore.
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes.load(Ordering::Relaxed)
     }
 }
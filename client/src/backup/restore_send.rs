@@ -1,29 +1,33 @@
-//! Contains logic for sending all received data to a peer during a restore operation.
+//! Contains logic for sending received data back to a peer during a restore operation.
+
+use std::{collections::BTreeMap, io::ErrorKind, path::Path};
 
 use anyhow::bail;
 use shared::{
-    p2p_message::FileInfo,
+    p2p_message::{FileInfo, RestoreSelectedRequest},
     types::{ClientId, TransportSessionNonce},
 };
-use tokio::{fs, net::TcpStream};
+use tokio::{fs, net::TcpStream, sync::Mutex};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
 use crate::{
-    backup::filesystem::file_utils::{parse_index_path_into_id, parse_packfile_path_into_id},
+    backup::filesystem::file_utils::{
+        find_shards, get_packfile_path, parse_index_path_into_id, parse_packfile_path_into_id,
+        parse_shard_path,
+    },
     config::Config,
-    defaults::{INDEX_FOLDER, PACKFILE_FOLDER, RESTORE_THROTTLE_DELAY},
+    defaults::{INDEX_FOLDER, PACKFILE_FOLDER, RESTORE_THROTTLE_DELAY, SHARD_FOLDER},
     log,
     net_p2p::{obfuscate_data_impl, transport::BackupTransportManager},
     CONFIG,
 };
 
-/// Send all packfiles and index files that we have received from a peer to that peer, over an
-/// already established WebSocket connection.
-pub async fn restore_all_data_to_peer(
-    peer_id: ClientId,
-    nonce: TransportSessionNonce,
-    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
-) -> anyhow::Result<()> {
+/// The start of the current throttling window of each peer, and how many bytes of selected data were
+/// sent back to it since.
+static SELECTED_BYTES_SENT: Mutex<BTreeMap<ClientId, (i64, u64)>> = Mutex::const_new(BTreeMap::new());
+
+/// Reject the restore request if the peer made another one recently, otherwise record it.
+async fn throttle_restore_request(peer_id: ClientId) -> anyhow::Result<()> {
     let config = CONFIG.get().unwrap();
 
     if let Some(last_request) = config.get_last_peer_restore_request(peer_id).await? {
@@ -33,7 +37,61 @@ pub async fn restore_all_data_to_peer(
         }
     }
 
-    config.log_peer_restore_request(peer_id).await?;
+    config.log_peer_restore_request(peer_id).await
+}
+
+/// Reject the selective restore request if the data sent back to the peer in the last
+/// `RESTORE_THROTTLE_DELAY` seconds adds up to all the data we store for it. Restores fetching their
+/// data in several rounds only request a part of it each time, so they are not slowed down, while
+/// requesting everything over and over is limited like full restores.
+async fn throttle_selected_request(peer_id: ClientId) -> anyhow::Result<()> {
+    let stored = match CONFIG.get().unwrap().get_peer_info(peer_id).await? {
+        Some(info) => u64::try_from(info.bytes_received)?,
+        None => 0,
+    };
+
+    let now = Config::get_unix_timestamp();
+    let delay = i64::try_from(RESTORE_THROTTLE_DELAY)?;
+    let mut sent = SELECTED_BYTES_SENT.lock().await;
+    let (window_start, bytes) = sent.entry(peer_id).or_insert((now, 0));
+    if now - *window_start >= delay {
+        *window_start = now;
+        *bytes = 0;
+    }
+
+    if *bytes > 0 && *bytes >= stored {
+        let remaining = delay - (now - *window_start);
+        bail!("rate limited restore request from {}, wait {remaining}s", hex::encode(peer_id));
+    }
+
+    Ok(())
+}
+
+/// Send selected data back to the peer, counting it towards its throttling window.
+async fn send_selected(
+    transport: &mut BackupTransportManager,
+    peer_id: ClientId,
+    data: Vec<u8>,
+    file_info: FileInfo,
+) -> anyhow::Result<()> {
+    let size = u64::try_from(data.len())?;
+    transport.send_data(data, file_info).await?;
+
+    if let Some((_, bytes)) = SELECTED_BYTES_SENT.lock().await.get_mut(&peer_id) {
+        *bytes += size;
+    }
+
+    Ok(())
+}
+
+/// Send all packfiles and index files that we have received from a peer to that peer, over an
+/// already established WebSocket connection.
+pub async fn restore_all_data_to_peer(
+    peer_id: ClientId,
+    nonce: TransportSessionNonce,
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+) -> anyhow::Result<()> {
+    throttle_restore_request(peer_id).await?;
 
     let mut transport = BackupTransportManager::new(socket, nonce, peer_id);
     let mut file_path = CONFIG.get().unwrap().get_received_packfiles_folder()?;
@@ -68,6 +126,112 @@ pub async fn restore_all_data_to_peer(
         }
     }
 
+    send_shards(&mut transport, &file_path, obfuscation_key).await?;
+    send_index_files(&mut transport, &file_path, obfuscation_key).await?;
+
+    transport.done().await;
+    log!("[rsend] restore sending done!");
+    Ok(())
+}
+
+/// Send the selected packfiles that we have received from a peer back to that peer, skipping
+/// the ones we don't have. Selective restores are rate limited by the amount of data sent back, as
+/// they can request all the data as well.
+pub async fn restore_selected_data_to_peer(
+    peer_id: ClientId,
+    nonce: TransportSessionNonce,
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    request: RestoreSelectedRequest,
+) -> anyhow::Result<()> {
+    throttle_selected_request(peer_id).await?;
+
+    let mut transport = BackupTransportManager::new(socket, nonce, peer_id);
+    let mut file_path = CONFIG.get().unwrap().get_received_packfiles_folder()?;
+    file_path.push(hex::encode(peer_id));
+
+    let obfuscation_key = CONFIG.get().unwrap().get_obfuscation_key().await?.to_le_bytes();
+
+    log!("[rsend] restoring {} selected packfiles to peer", request.packfiles.len());
+    for &packfile_id in &request.packfiles {
+        let path = get_packfile_path(&file_path, packfile_id, false)?;
+        let mut data = match fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => bail!("error reading received packfile: {e}"),
+        };
+
+        // deobfuscate data on disk using the same algorithm as obfuscation as it's a simple xor
+        obfuscate_data_impl(&mut data, obfuscation_key);
+
+        send_selected(&mut transport, peer_id, data, FileInfo::Packfile(packfile_id)).await?;
+    }
+
+    // we may only have a shard of some of the packfiles
+    for packfile_id in &request.packfiles {
+        for (index, path) in find_shards(&file_path, *packfile_id)? {
+            let mut data = fs::read(&path).await?;
+            obfuscate_data_impl(&mut data, obfuscation_key);
+            send_selected(&mut transport, peer_id, data, FileInfo::PackfileShard(*packfile_id, index))
+                .await?;
+        }
+    }
+
+    if request.include_index {
+        send_index_files(&mut transport, &file_path, obfuscation_key).await?;
+    }
+
+    transport.done().await;
+    log!("[rsend] restore sending done!");
+    Ok(())
+}
+
+/// Send all packfile shards that we have received from a peer.
+async fn send_shards(
+    transport: &mut BackupTransportManager,
+    file_path: &Path,
+    obfuscation_key: [u8; 4],
+) -> anyhow::Result<()> {
+    let shard_folder = file_path.join(SHARD_FOLDER);
+    if !shard_folder.try_exists()? {
+        return Ok(());
+    }
+
+    log!("[rsend] restoring packfile shards to peer");
+    for entry in shard_folder.read_dir()? {
+        match entry {
+            Ok(entry) if entry.file_type()?.is_dir() => {
+                for shard in entry.path().read_dir()? {
+                    let shard = shard?;
+                    if !shard.file_type()?.is_file() {
+                        continue;
+                    }
+
+                    let mut data = fs::read(shard.path()).await?;
+
+                    // deobfuscate data on disk using the same algorithm as obfuscation as it's a simple xor
+                    obfuscate_data_impl(&mut data, obfuscation_key);
+
+                    let (packfile_id, index) = parse_shard_path(&shard.path())?;
+                    transport
+                        .send_data(data, FileInfo::PackfileShard(packfile_id, index))
+                        .await?;
+                }
+            }
+            // skip any other entries if present
+            Ok(_) => {}
+            Err(e) => bail!("error reading received shard directory: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Send all index files that we have received from a peer.
+async fn send_index_files(
+    transport: &mut BackupTransportManager,
+    file_path: &Path,
+    obfuscation_key: [u8; 4],
+) -> anyhow::Result<()> {
     log!("[rsend] restoring index to peer");
     for entry in file_path.join(INDEX_FOLDER).read_dir()? {
         match entry {
@@ -88,7 +252,5 @@ pub async fn restore_all_data_to_peer(
         }
     }
 
-    transport.done().await;
-    log!("[rsend] restore sending done!");
     Ok(())
 }
@@ -0,0 +1,205 @@
+//! Implements snapshot retention policies, deciding which snapshots to keep and which to forget.
+
+use std::cmp::Reverse;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use shared::server_message::SnapshotInfo;
+
+use crate::{backup::tree_source, log, net_server::requests, CONFIG};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Rules for which snapshots to keep, all other snapshots are forgotten. A snapshot is kept if it
+/// matches at least one of the rules. The daily, weekly and monthly rules keep the newest snapshot
+/// of each of the given number of most recent days/weeks/months that have a snapshot.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+#[allow(clippy::struct_field_names)]
+pub struct RetentionPolicy {
+    pub keep_last: u32,
+    pub keep_daily: u32,
+    pub keep_weekly: u32,
+    pub keep_monthly: u32,
+}
+
+impl RetentionPolicy {
+    /// Returns whether the policy has no rules set, in which case it would forget everything.
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0 && self.keep_daily == 0 && self.keep_weekly == 0 && self.keep_monthly == 0
+    }
+}
+
+/// Keeps track of the number of remaining snapshots to keep for a single periodic rule.
+struct PeriodBucket {
+    remaining: u32,
+    last_period: Option<i64>,
+    period_of: fn(i64) -> i64,
+}
+
+/// Split the snapshots into the ones that should be kept and the ones that should be forgotten
+/// according to the policy. Both lists are returned ordered from the newest snapshot.
+pub fn apply_policy(
+    policy: &RetentionPolicy,
+    snapshots: &[SnapshotInfo],
+) -> (Vec<SnapshotInfo>, Vec<SnapshotInfo>) {
+    let mut sorted = snapshots.to_vec();
+    sorted.sort_by_key(|s| Reverse(s.timestamp));
+
+    let mut last_remaining = policy.keep_last;
+    let mut buckets = [
+        PeriodBucket {
+            remaining: policy.keep_daily,
+            last_period: None,
+            period_of: day_of,
+        },
+        PeriodBucket {
+            remaining: policy.keep_weekly,
+            last_period: None,
+            period_of: week_of,
+        },
+        PeriodBucket {
+            remaining: policy.keep_monthly,
+            last_period: None,
+            period_of: month_of,
+        },
+    ];
+
+    let mut keep = Vec::new();
+    let mut forget = Vec::new();
+
+    for snapshot in sorted {
+        let mut keep_snapshot = false;
+
+        if last_remaining > 0 {
+            last_remaining -= 1;
+            keep_snapshot = true;
+        }
+
+        // snapshots are processed from the newest, so the first one seen in a period is kept
+        for bucket in &mut buckets {
+            let period = (bucket.period_of)(snapshot.timestamp);
+            if bucket.remaining > 0 && bucket.last_period != Some(period) {
+                bucket.last_period = Some(period);
+                bucket.remaining -= 1;
+                keep_snapshot = true;
+            }
+        }
+
+        if keep_snapshot {
+            keep.push(snapshot);
+        } else {
+            forget.push(snapshot);
+        }
+    }
+
+    (keep, forget)
+}
+
+/// Apply the configured retention policy to all our snapshots, and ask the server to forget the
+/// ones that are not kept. Returns the snapshots that were (or in a dry run, would be) forgotten.
+pub async fn forget(dry_run: bool) -> anyhow::Result<Vec<SnapshotInfo>> {
+    let policy = CONFIG.get().unwrap().get_retention_policy().await?;
+    if policy.is_empty() {
+        bail!("no retention policy is set, refusing to forget all snapshots");
+    }
+
+    let snapshots = requests::snapshot_list().await?;
+    let (keep, forget) = apply_policy(&policy, &snapshots);
+
+    log!("[forget] {} snapshots in total, keeping {}", snapshots.len(), keep.len());
+    if forget.is_empty() {
+        log!("[forget] no snapshots to forget");
+        return Ok(Vec::new());
+    }
+
+    let forgotten = requests::snapshot_forget(forget.iter().map(|s| s.id).collect(), dry_run).await?;
+    for snapshot in &forgotten.snapshots {
+        log!(
+            "[forget] {} snapshot {} from {}",
+            if dry_run { "would forget" } else { "forgot" },
+            hex::encode(snapshot.snapshot_hash),
+            format_timestamp(snapshot.timestamp)
+        );
+    }
+
+    if !dry_run {
+        tree_source::evict_unreachable_trees().await?;
+    }
+
+    Ok(forgotten.snapshots)
+}
+
+/// Format a Unix timestamp as a UTC date and time.
+pub fn format_timestamp(timestamp: i64) -> String {
+    let (year, month, day) = civil_from_days(day_of(timestamp));
+    let seconds = timestamp.rem_euclid(SECONDS_PER_DAY);
+
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02} UTC", seconds / 3600, (seconds % 3600) / 60)
+}
+
+/// Returns the number of days since the Unix epoch.
+fn day_of(timestamp: i64) -> i64 {
+    timestamp.div_euclid(SECONDS_PER_DAY)
+}
+
+/// Returns the number of weeks since the Unix epoch, weeks start on Monday.
+fn week_of(timestamp: i64) -> i64 {
+    // the epoch was on a Thursday, so shift by three days to align weeks to Mondays
+    (day_of(timestamp) + 3).div_euclid(7)
+}
+
+/// Returns the number of months since the year 0.
+fn month_of(timestamp: i64) -> i64 {
+    let (year, month, _) = civil_from_days(day_of(timestamp));
+    year * 12 + month - 1
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) date in the proleptic Gregorian
+/// calendar (http://howardhinnant.github.io/date_algorithms.html#civil_from_days).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(id: i64, timestamp: i64) -> SnapshotInfo {
+        SnapshotInfo { id, snapshot_hash: [0; 32], timestamp }
+    }
+
+    #[test]
+    fn retention_policy_buckets() {
+        // 2023-02-28 12:00 UTC
+        let base = 1_677_585_600;
+        let snapshots: Vec<_> = (0..60).map(|i| snapshot(i, base + i * SECONDS_PER_DAY / 2)).collect();
+
+        let policy = RetentionPolicy {
+            keep_last: 3,
+            keep_daily: 5,
+            keep_weekly: 0,
+            keep_monthly: 2,
+        };
+        let (keep, forget) = apply_policy(&policy, &snapshots);
+        let kept: Vec<_> = keep.iter().map(|s| s.id).collect();
+
+        // three newest, then the newest of each of the five most recent days (the first two of
+        // which are already kept), and the newest of March (already kept) and February
+        assert_eq!(kept, vec![59, 58, 57, 56, 54, 52, 0]);
+        assert_eq!(keep.len() + forget.len(), snapshots.len());
+
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(day_of(base)), (2023, 2, 28));
+        assert_eq!(week_of(4 * SECONDS_PER_DAY), 1); // 1970-01-05 is a Monday
+    }
+}
@@ -0,0 +1,203 @@
+//! Searches for files and directories by name across snapshots.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::Serialize;
+use shared::types::BlobHash;
+
+use crate::{
+    backup::{
+        filesystem::{Tree, TreeKind},
+        tree_source::TreeSource,
+    },
+    defaults::SEARCH_RESULT_LIMIT,
+};
+
+/// A snapshot to search in.
+#[derive(Clone, Copy, Debug)]
+pub struct SearchedSnapshot {
+    pub hash: BlobHash,
+    /// Unix timestamp of the time the backup was completed.
+    pub timestamp: i64,
+}
+
+/// A path matching the search, with all its distinct versions.
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchMatch {
+    pub path: PathBuf,
+    /// Newest versions first.
+    pub versions: Vec<FileVersion>,
+}
+
+/// A version of a file or directory, identical in all the listed snapshots.
+#[derive(Clone, Debug, Serialize)]
+pub struct FileVersion {
+    pub kind: TreeKind,
+    pub size: Option<u64>,
+    pub mtime: Option<u64>,
+    /// Hex encoded hashes of the snapshots containing this version, newest first.
+    pub snapshots: Vec<String>,
+    /// The time of the newest snapshot containing this version.
+    pub last_seen: i64,
+}
+
+/// A directory of a snapshot to search in.
+struct Pending {
+    snapshot: SearchedSnapshot,
+    path: PathBuf,
+    children: Vec<BlobHash>,
+}
+
+/// Walk the trees of all given snapshots, looking for files and directories whose name matches the
+/// glob pattern. Returns the matching paths sorted, and whether the results were truncated.
+pub async fn search_trees(
+    source: &mut impl TreeSource,
+    snapshots: &[SearchedSnapshot],
+    pattern: &str,
+) -> anyhow::Result<(Vec<SearchMatch>, bool)> {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+
+    // the versions of each path are told apart by the hashes of their trees
+    let mut found: HashMap<PathBuf, HashMap<BlobHash, FileVersion>> = HashMap::new();
+
+    let roots: Vec<BlobHash> = snapshots.iter().map(|s| s.hash).collect();
+    source.prefetch(&roots).await?;
+    let mut level = Vec::new();
+    for snapshot in snapshots {
+        let tree = source.tree(&snapshot.hash).await?;
+        level.push(Pending {
+            snapshot: *snapshot,
+            path: PathBuf::new(),
+            children: tree.children,
+        });
+    }
+
+    // all snapshots are walked together one level at a time, so each level needs only one round of
+    // fetching
+    while !level.is_empty() {
+        let mut needed: Vec<BlobHash> = level.iter().flat_map(|p| p.children.iter().copied()).collect();
+        needed.sort_unstable();
+        needed.dedup();
+        source.prefetch(&needed).await?;
+
+        let mut next_level = Vec::new();
+        for pending in level {
+            for hash in &pending.children {
+                let tree = source.tree(hash).await?;
+                let path = pending.path.join(&tree.name);
+
+                let name: Vec<char> = tree.name.to_lowercase().chars().collect();
+                if glob_match(&pattern, &name) {
+                    add_version(&mut found, path.clone(), *hash, &tree, pending.snapshot);
+                }
+
+                if tree.kind == TreeKind::Dir {
+                    next_level.push(Pending {
+                        snapshot: pending.snapshot,
+                        path,
+                        children: tree.children,
+                    });
+                }
+            }
+        }
+
+        level = next_level;
+    }
+
+    let truncated = found.len() > SEARCH_RESULT_LIMIT;
+    let mut matches: Vec<SearchMatch> = found
+        .into_iter()
+        .map(|(path, versions)| {
+            let mut versions: Vec<FileVersion> = versions.into_values().collect();
+            versions.sort_by_key(|v| -v.last_seen);
+            SearchMatch { path, versions }
+        })
+        .collect();
+    matches.sort_by(|a, b| a.path.cmp(&b.path));
+    matches.truncate(SEARCH_RESULT_LIMIT);
+
+    Ok((matches, truncated))
+}
+
+/// Record that a version of a path is present in a snapshot.
+fn add_version(
+    found: &mut HashMap<PathBuf, HashMap<BlobHash, FileVersion>>,
+    path: PathBuf,
+    hash: BlobHash,
+    tree: &Tree,
+    snapshot: SearchedSnapshot,
+) {
+    let version = found
+        .entry(path)
+        .or_default()
+        .entry(hash)
+        .or_insert_with(|| FileVersion {
+            kind: tree.kind,
+            size: tree.metadata.size,
+            mtime: tree.metadata.mtime,
+            snapshots: Vec::new(),
+            last_seen: snapshot.timestamp,
+        });
+
+    // snapshots are searched newest first
+    version.snapshots.push(hex::encode(snapshot.hash));
+    version.last_seen = version.last_seen.max(snapshot.timestamp);
+}
+
+/// Match a name against a glob pattern, where `*` matches any sequence of characters and `?`
+/// matches a single character. A pattern without wildcards matches only the exact name.
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // the position after the last star in the pattern, and the name position it's matched up to
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+            }
+            Some(c) if *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // let the last star match one more character
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    backtrack = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        glob_match(&pattern.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(matches("report.pdf", "report.pdf"));
+        assert!(!matches("report.pdf", "report.pdf.bak"));
+        assert!(matches("*.pdf", "report.pdf"));
+        assert!(matches("*", ""));
+        assert!(matches("r*t.*", "report.pdf"));
+        assert!(matches("*a*b*", "xxaxxbxx"));
+        assert!(!matches("*a*b", "xxaxxbxx"));
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file.txt"));
+    }
+}
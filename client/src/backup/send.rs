@@ -1,9 +1,10 @@
 //! Allows for coordinated sending of files to peers during the backup process.
 
 use std::{
+    collections::HashSet,
     fs,
     path::{Path, PathBuf},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, bail};
@@ -17,25 +18,91 @@ use tokio::{net::TcpStream, time};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
 use crate::{
-    backup::{filesystem::file_utils, BACKUP_ORCHESTRATOR},
+    backup::{
+        backup_orchestrator::BackupOrchestrator, erasure, erasure::ErasureCoding, filesystem::file_utils,
+        BACKUP_ORCHESTRATOR,
+    },
+    config::{packfiles::ShardLocation, peers::PeerState},
     defaults::{
         INDEX_FOLDER, MAX_PACKFILE_LOCAL_BUFFER_SIZE, PACKFILE_FOLDER,
-        PACKFILE_LOCAL_BUFFER_RESUME_THRESHOLD, STORAGE_REQUEST_CAP, STORAGE_REQUEST_RETRY_DELAY,
-        STORAGE_REQUEST_STEP,
+        PACKFILE_LOCAL_BUFFER_RESUME_THRESHOLD, SEND_BUFFER_STALL_TIMEOUT, STORAGE_REQUEST_CAP,
+        STORAGE_REQUEST_RETRY_DELAY, STORAGE_REQUEST_STEP,
     },
     log,
-    net_p2p::transport::BackupTransportManager,
+    net_p2p::{audit, transport::BackupTransportManager},
     net_server::{requests, requests::p2p_connection_begin},
     CONFIG, P2P_CONN_REQUESTS, UI,
 };
 
+/// How packfiles are made redundant by storing them with multiple peers.
+#[derive(Clone, Copy, Debug)]
+pub enum Redundancy {
+    /// Every peer stores a full copy, with the given number of peers.
+    Replicate(u32),
+    /// Every peer stores a single shard.
+    ErasureCode(ErasureCoding),
+}
+
+impl Redundancy {
+    /// Load the configured redundancy, erasure coding takes precedence over replication if set.
+    pub async fn load() -> anyhow::Result<Self> {
+        let config = CONFIG.get().unwrap();
+        match config.get_erasure_coding().await? {
+            Some(coding) => Ok(Self::ErasureCode(coding)),
+            None => Ok(Self::Replicate(config.get_replication_factor().await?)),
+        }
+    }
+
+    /// The number of distinct peers that need to store each packfile.
+    pub fn peers_needed(self) -> usize {
+        match self {
+            Self::Replicate(factor) => usize::try_from(factor).unwrap_or(usize::MAX),
+            Self::ErasureCode(coding) => coding.total_shards().into(),
+        }
+    }
+
+    /// The number of peers that can be lost without losing data, plus one, to be comparable with
+    /// the number of copies of replicated packfiles.
+    pub fn target_redundancy(self) -> u32 {
+        match self {
+            Self::Replicate(factor) => factor,
+            Self::ErasureCode(coding) => u32::from(coding.parity_shards) + 1,
+        }
+    }
+
+    /// Returns whether a copy of a packfile, or the given shard of it, is stored the way this setting
+    /// stores packfiles.
+    pub fn matches(self, shard: Option<ShardLocation>) -> bool {
+        match self {
+            Self::Replicate(_) => shard.is_none(),
+            Self::ErasureCode(coding) => shard.is_some_and(|shard| {
+                shard.data_shards == coding.data_shards && shard.index < coding.total_shards()
+            }),
+        }
+    }
+
+    /// The storage needed with peers, in percent of the packfile size.
+    pub fn storage_percent(self) -> u64 {
+        match self {
+            Self::Replicate(factor) => u64::from(factor) * 100,
+            Self::ErasureCode(coding) => {
+                (u64::from(coding.total_shards()) * 100).div_ceil(u64::from(coding.data_shards))
+            }
+        }
+    }
+}
+
 /// A function designed to be a run in a task as a part of the backup process, it periodically scans
 /// the local filesystem for new packfiles, manages connections with peers and sends
 /// packfiles/indexes to them. Packfiles are sent just as they are being written, while indexes are
-/// sent after the packfiles are done. If files are not created anymore, and all the files have
-/// been sent successfully, the function terminates.
-pub async fn send(output_folder: PathBuf) -> anyhow::Result<()> {
+/// sent after the packfiles are done, to every peer that stores our packfiles. Each packfile is
+/// kept locally until enough distinct peers store a copy or an erasure coded shard of it,
+/// depending on the configured `Redundancy`. If files are not created anymore, and all the files
+/// have been sent successfully, the function terminates. With a `stall_timeout`, it fails instead
+/// of waiting for peers indefinitely once nothing could be sent for that long.
+pub async fn send(output_folder: PathBuf, stall_timeout: Option<Duration>) -> anyhow::Result<()> {
     let orchestrator = BACKUP_ORCHESTRATOR.get().unwrap();
+    let redundancy = Redundancy::load().await?;
 
     let pack_folder = output_folder.join(PACKFILE_FOLDER);
     let index_folder = output_folder.join(INDEX_FOLDER);
@@ -45,6 +112,10 @@ pub async fn send(output_folder: PathBuf) -> anyhow::Result<()> {
     let mut connection = None;
     let mut packfiles_done = false;
     let mut index_done = false;
+    // peers that already store all packfiles waiting to be replicated
+    let mut full_peers = HashSet::new();
+    let mut replicas_pending = false;
+    let mut last_progress = (Instant::now(), orchestrator.get_packfile_bytes_sent());
 
     // sending loop that takes care of reestablishing the connection and transporting all files
     loop {
@@ -56,33 +127,33 @@ pub async fn send(output_folder: PathBuf) -> anyhow::Result<()> {
         let current_written = orchestrator.get_packfile_bytes_written();
         let current_matched = orchestrator.get_storage_request_last_matched();
 
+        // new packfiles can be sent to any peer
+        if current_written > last_written {
+            full_peers.clear();
+        }
+
         // try establishing a peer connection if we just started or connection got terminated
         if connection.is_none() {
-            match get_peer_connection().await {
-                Ok((peer_id, transport)) => {
-                    UI.get().unwrap().progress_add_peer(peer_id).await;
-                    log!("[send] connection established with {}", hex::encode(peer_id));
-                    connection = Some((peer_id, transport));
-                }
-                Err(e) => {
-                    log!("[send] unable to get a peer connection: {}", e);
-                    connection = None;
-                }
-            }
+            connection = establish_connection(&full_peers).await;
         }
 
         if let Some(conn) = &mut connection {
-            if ((current_written > last_written || current_matched > last_matched)
+            if ((current_written > last_written || current_matched > last_matched || replicas_pending)
                 || orchestrator.is_packing_completed())
                 && !packfiles_done
             {
-                let send_result = send_packfiles_from_folder(&pack_folder, conn.0, &mut conn.1).await;
+                let send_result =
+                    send_packfiles_from_folder(&pack_folder, conn.0, &mut conn.1, redundancy).await;
 
                 // ideally distinguish between send errors and filesystem errors
                 match send_result {
-                    Ok(_) => {
+                    Ok(pending) => {
                         last_matched = current_matched;
                         last_written = current_written;
+                        replicas_pending = pending > 0;
+                        if replicas_pending {
+                            log!("[send] {} packfiles need more replicas, looking for another peer", pending);
+                        }
                     }
                     Err(e) => {
                         log!("[send] error sending packfiles: {}", e);
@@ -121,47 +192,115 @@ pub async fn send(output_folder: PathBuf) -> anyhow::Result<()> {
             if packfiles_done && index_done {
                 break;
             }
+
+            // the remaining packfiles are all stored by this peer already, continue with another one
+            if replicas_pending && !packfiles_done {
+                full_peers.insert(conn.0);
+                if let Some((_, transport)) = connection.take() {
+                    transport.done().await;
+                }
+            }
+        }
+
+        if is_stalled(&mut last_progress, stall_timeout) {
+            break;
         }
 
         // wait for an arbitrary amount of time until the next check
         time::sleep(Duration::from_secs(1)).await;
     }
 
+    // the index was sent over the last connection, the other peers storing our data need it too
+    let last_peer = connection.as_ref().map(|(peer_id, _)| *peer_id);
+    if let Some((_, transport)) = connection.take() {
+        transport.done().await;
+    }
+    if !index_done {
+        bail!("no packfiles could be sent to peers for {} seconds", last_progress.0.elapsed().as_secs());
+    }
+    replicate_index(&index_folder, last_peer).await?;
+
     log!("[send] sending done!");
     Ok(())
 }
 
-/// Try to send all index files using an existing connection.
+/// Get a connection to a peer not in `exclude` for sending, logging the failure if there is none.
+async fn establish_connection(exclude: &HashSet<ClientId>) -> Option<(ClientId, BackupTransportManager)> {
+    match get_peer_connection(exclude).await {
+        Ok((peer_id, transport)) => {
+            UI.get().unwrap().progress_add_peer(peer_id).await;
+            log!("[send] connection established with {}", hex::encode(peer_id));
+            Some((peer_id, transport))
+        }
+        Err(e) => {
+            log!("[send] unable to get a peer connection: {}", e);
+            None
+        }
+    }
+}
+
+/// Returns whether no packfile has been sent for longer than the timeout, if any. The time and the
+/// number of bytes sent are updated in `last_progress` whenever more bytes were sent.
+fn is_stalled(last_progress: &mut (Instant, u64), timeout: Option<Duration>) -> bool {
+    let bytes_sent = BACKUP_ORCHESTRATOR.get().unwrap().get_packfile_bytes_sent();
+    if bytes_sent > last_progress.1 {
+        *last_progress = (Instant::now(), bytes_sent);
+    }
+
+    timeout.is_some_and(|timeout| last_progress.0.elapsed() > timeout)
+}
+
+/// Send the packfiles waiting in the local buffer and the index to peers, without packing anything
+/// new or creating a snapshot. Unlike a backup, this fails if nothing could be sent for
+/// `SEND_BUFFER_STALL_TIMEOUT` seconds, so the caller knows that the packfiles are not stored yet.
+pub async fn send_buffer() -> anyhow::Result<()> {
+    let destination = CONFIG.get().unwrap().get_packfile_path()?;
+
+    BackupOrchestrator::initialize_static(&destination).await?;
+    let orchestrator = BACKUP_ORCHESTRATOR.get().unwrap();
+    orchestrator.set_backup_started();
+    orchestrator.set_packing_completed();
+
+    let result = send(destination, Some(Duration::from_secs(SEND_BUFFER_STALL_TIMEOUT))).await;
+
+    orchestrator.set_backup_finished();
+    result
+}
+
+/// Try to send all index files that the peer doesn't have yet using an existing connection.
 async fn send_index(
     folder: &Path,
     peer_id: ClientId,
     transport: &mut BackupTransportManager,
 ) -> anyhow::Result<()> {
     let config = CONFIG.get().unwrap();
+    let delivered = config.get_peer_index_files(peer_id).await?;
+
     for file in folder.read_dir()? {
         match file {
             Ok(file) if file.file_type()?.is_file() => {
                 let path = file.path();
                 let index_num = file_utils::parse_index_path_into_id(&path)?;
 
-                // skip the index files that have been already sent
-                let highest_sent_index = config.get_highest_sent_index_number().await?;
-                if highest_sent_index.is_some() && index_num <= highest_sent_index.unwrap() {
+                // skip the index files that this peer already has
+                if delivered.contains(&index_num) {
                     continue;
                 }
 
                 println!("[send] sending index file {}", path.display());
 
                 // send the index file, and don't delete it, we will need it for deduplication
-                if transport
+                let result = transport
                     .send_data(fs::read(&path)?, FileInfo::Index(index_num))
-                    .await
-                    .is_ok()
-                {
+                    .await;
+                config
+                    .add_peer_transfer(peer_id, result.as_ref().ok().copied())
+                    .await?;
+                if result.is_ok() {
                     config
                         .peer_increment_transmitted(peer_id, fs::metadata(&path)?.len())
                         .await?;
-                    config.save_highest_sent_index_number(index_num).await?;
+                    config.add_index_location(index_num, peer_id).await?;
                     println!("[send] index file {} sent successfully", path.display());
                 } else {
                     bail!("[send] sending index file {} failed", path.display());
@@ -175,12 +314,17 @@ async fn send_index(
     Ok(())
 }
 
-/// Try to send all packfiles using an existing connection.
+/// Try to send all packfiles using an existing connection. Returns the number of packfiles that
+/// still need to be sent to other peers to reach the configured redundancy.
 async fn send_packfiles_from_folder(
     folder: &Path,
     peer_id: ClientId,
     transport: &mut BackupTransportManager,
-) -> anyhow::Result<()> {
+    redundancy: Redundancy,
+) -> anyhow::Result<usize> {
+    let leaving = get_leaving_peers().await?;
+
+    let mut pending = 0;
     for packfile in folder.read_dir()? {
         match packfile {
             Ok(entry) if entry.file_type()?.is_dir() => {
@@ -188,7 +332,11 @@ async fn send_packfiles_from_folder(
                 for packfile in entry.path().read_dir()? {
                     match packfile {
                         Ok(entry) if entry.file_type()?.is_file() => {
-                            send_single_packfile(&entry.path(), peer_id, transport).await?;
+                            if send_single_packfile(&entry.path(), peer_id, transport, redundancy, &leaving)
+                                .await?
+                            {
+                                pending += 1;
+                            }
                         }
                         Err(e) => bail!("Error reading a packfile when sending: {e}"),
                         Ok(_) => {} // ignore folders
@@ -200,20 +348,40 @@ async fn send_packfiles_from_folder(
         }
     }
 
-    Ok(())
+    Ok(pending)
+}
+
+/// Returns the peers our data is moved away from or was moved away from already, the copies and
+/// shards they store don't count towards the redundancy.
+async fn get_leaving_peers() -> anyhow::Result<HashSet<ClientId>> {
+    let config = CONFIG.get().unwrap();
+
+    let mut peers = HashSet::new();
+    for state in [PeerState::Lost, PeerState::Evacuating, PeerState::Replaced] {
+        peers.extend(config.get_peers_in_state(state).await?);
+    }
+
+    Ok(peers)
 }
 
 /// Try to obtain a connection to a peer by using the strategy of first using existing established
-/// connections, then connecting to known peers in order of most storage, and finally sending
-/// a storage request if one hasn't been sent recently.
-async fn get_peer_connection() -> anyhow::Result<(ClientId, BackupTransportManager)> {
+/// connections, then connecting to known peers in order of reliability and storage, and finally sending
+/// a storage request if one hasn't been sent recently. Peers in `exclude` are never used.
+async fn get_peer_connection(
+    exclude: &HashSet<ClientId>,
+) -> anyhow::Result<(ClientId, BackupTransportManager)> {
     let orchestrator = BACKUP_ORCHESTRATOR.get().unwrap();
     let config = CONFIG.get().unwrap();
 
-    let peers_with_storage = &config.find_peers_with_storage().await?;
+    let peers_with_storage: &Vec<ClientId> = &config
+        .find_peers_with_storage()
+        .await?
+        .into_iter()
+        .filter(|peer| !exclude.contains(peer))
+        .collect();
 
     // first try whether we have any active connections with peers that we can send to,
-    // and return the one for the peer with the most storage
+    // and return the one for the most reliable peer
     for peer in peers_with_storage {
         if let Some(transport) = orchestrator.active_transport_sessions.lock().await.remove(peer) {
             return Ok((*peer, transport));
@@ -221,24 +389,9 @@ async fn get_peer_connection() -> anyhow::Result<(ClientId, BackupTransportManag
     }
 
     // if no connections are active, try establishing them,
-    // starting with an existing peer with most storage
+    // starting with the most reliable existing peer
     for peer in peers_with_storage {
-        let nonce = P2P_CONN_REQUESTS
-            .get()
-            .unwrap()
-            .add_request(*peer, RequestType::Transport)
-            .await?;
-
-        log!("[send] trying to establish connection with {}", hex::encode(peer));
-        // the client we tried to notify might not be connected to the server at all, then we skip it
-        if !p2p_connection_begin(*peer, nonce).await? {
-            continue;
-        }
-
-        // wait for a while for the connection to establish
-        // better to replace by a channel subscription
-        tokio::time::sleep(Duration::from_secs(3)).await;
-        if let Some(transport) = orchestrator.active_transport_sessions.lock().await.remove(peer) {
+        if let Some(transport) = connect_to_peer(*peer).await? {
             return Ok((*peer, transport));
         }
     }
@@ -250,7 +403,12 @@ async fn get_peer_connection() -> anyhow::Result<(ClientId, BackupTransportManag
     tokio::time::sleep(Duration::from_secs(5)).await;
 
     // if we get a request fulfilled, the connection will be established automatically
-    for peer in &config.find_peers_with_storage().await? {
+    for peer in config
+        .find_peers_with_storage()
+        .await?
+        .iter()
+        .filter(|peer| !exclude.contains(*peer))
+    {
         log!("[send] storage request fulfilled immediately");
         if let Some(transport) = orchestrator.active_transport_sessions.lock().await.remove(peer) {
             return Ok((*peer, transport));
@@ -261,44 +419,171 @@ async fn get_peer_connection() -> anyhow::Result<(ClientId, BackupTransportManag
     Err(anyhow!("Unable to get any connections at this time"))
 }
 
-/// Transport a single packfile over an existing connection.
+/// Try to establish a transport connection with a specific peer, returns `None` if the peer
+/// doesn't connect in time.
+async fn connect_to_peer(peer: ClientId) -> anyhow::Result<Option<BackupTransportManager>> {
+    let orchestrator = BACKUP_ORCHESTRATOR.get().unwrap();
+    if let Some(transport) = orchestrator.active_transport_sessions.lock().await.remove(&peer) {
+        return Ok(Some(transport));
+    }
+
+    let nonce = P2P_CONN_REQUESTS
+        .get()
+        .unwrap()
+        .add_request(peer, RequestType::Transport)
+        .await?;
+
+    log!("[send] trying to establish connection with {}", hex::encode(peer));
+    // the client we tried to notify might not be connected to the server at all, then we skip it
+    if !p2p_connection_begin(peer, nonce).await? {
+        CONFIG.get().unwrap().add_peer_connection_attempt(peer, false).await?;
+        return Ok(None);
+    }
+
+    // wait for a while for the connection to establish
+    // better to replace by a channel subscription
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let transport = orchestrator.active_transport_sessions.lock().await.remove(&peer);
+    CONFIG
+        .get()
+        .unwrap()
+        .add_peer_connection_attempt(peer, transport.is_some())
+        .await?;
+
+    Ok(transport)
+}
+
+/// Make sure that every active peer storing our packfiles also has the full current index, so that any
+/// of them can be used for a restore. Peers that can't be reached now are retried with the next
+/// backup, failures are only logged.
+pub async fn replicate_index(folder: &Path, skip: Option<ClientId>) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+
+    let mut index_files = HashSet::new();
+    for file in folder.read_dir()? {
+        let file = file?;
+        if file.file_type()?.is_file() {
+            index_files.insert(file_utils::parse_index_path_into_id(&file.path())?);
+        }
+    }
+
+    for peer in config.get_active_packfile_peers().await? {
+        if Some(peer) == skip {
+            continue;
+        }
+
+        let delivered = config.get_peer_index_files(peer).await?;
+        if index_files.is_subset(&delivered) {
+            continue;
+        }
+
+        log!("[send] replicating index files to {}", hex::encode(peer));
+        match connect_to_peer(peer).await {
+            Ok(Some(mut transport)) => {
+                let result = send_index(folder, peer, &mut transport).await;
+                transport.done().await;
+                if let Err(e) = result {
+                    log!("[send] error replicating index files to {}: {e}", hex::encode(peer));
+                }
+            }
+            Ok(None) => log!("[send] peer {} is not available, index will be sent later", hex::encode(peer)),
+            Err(e) => log!("[send] unable to connect to {}: {e}", hex::encode(peer)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Transport a single packfile, or a shard of it, over an existing connection, unless the peer
+/// already stores a part of it. The packfile is deleted once it's stored by enough peers, returns
+/// whether it still needs to be sent to other peers.
 async fn send_single_packfile(
     path: &PathBuf,
     peer_id: ClientId,
     transport: &mut BackupTransportManager,
-) -> anyhow::Result<()> {
-    let orchestrator = BACKUP_ORCHESTRATOR.get().unwrap();
+    redundancy: Redundancy,
+    leaving: &HashSet<ClientId>,
+) -> anyhow::Result<bool> {
     let config = CONFIG.get().unwrap();
 
     let size = fs::metadata(path)?.len();
-    println!("[send] sending packfile {}", path.display());
+    let packfile_id = file_utils::parse_packfile_path_into_id(path)?;
+
+    // only count the locations matching the current setting, in case it was changed in between,
+    // and the ones with peers that keep storing our data
+    let all_locations = config.get_packfile_locations(packfile_id).await?;
+    let locations: Vec<_> = all_locations
+        .iter()
+        .filter(|l| redundancy.matches(l.shard) && !leaving.contains(&l.peer_id))
+        .collect();
+
+    let mut stored = locations.len();
+    if all_locations.iter().any(|l| l.peer_id == peer_id) {
+        return finish_packfile(path, size, stored, redundancy);
+    }
+
+    let (data, file_info, shard) = match redundancy {
+        Redundancy::Replicate(_) => (fs::read(path)?, FileInfo::Packfile(packfile_id), None),
+        Redundancy::ErasureCode(coding) => {
+            // send the first shard that isn't stored by any peer yet
+            let held: HashSet<u8> = locations.iter().filter_map(|l| l.shard.map(|s| s.index)).collect();
+            let Some(index) = (0..coding.total_shards()).find(|i| !held.contains(i)) else {
+                return finish_packfile(path, size, stored, redundancy);
+            };
+
+            let shard = erasure::encode(&fs::read(path)?, coding)?.swap_remove(usize::from(index));
+            let location = ShardLocation { index, data_shards: coding.data_shards };
+            (shard, FileInfo::PackfileShard(packfile_id, index), Some(location))
+        }
+    };
+
+    println!("[send] sending {file_info:?} from {}", path.display());
+    let sent_size = u64::try_from(data.len())?;
+    let checksum = blake3::hash(&data).into();
+    let challenges = audit::create_challenges(&data, packfile_id, peer_id, shard.map(|s| s.index))?;
 
     // this function will wait for an acknowledgement from the other party and only return after
     // the transport is confirmed, so we should be able to safely delete the packfile
-    if transport
-        .send_data(fs::read(path)?, FileInfo::Packfile(file_utils::parse_packfile_path_into_id(path)?))
-        .await
-        .is_ok()
-    {
-        orchestrator.increment_packfile_bytes_sent(size);
-        config.peer_increment_transmitted(peer_id, size).await?;
-
-        fs::remove_file(path)?;
+    let result = transport.send_data(data, file_info).await;
+    config
+        .add_peer_transfer(peer_id, result.as_ref().ok().copied())
+        .await?;
+    if result.is_ok() {
+        config.peer_increment_transmitted(peer_id, sent_size).await?;
+        config
+            .add_packfile_location(packfile_id, peer_id, sent_size, checksum, shard)
+            .await?;
+        config.add_audit_challenges(&challenges).await?;
+        stored += 1;
 
-        println!("[send] packfile {} sent successfully, deleted", path.display());
-        return Ok(());
+        println!("[send] packfile {} sent successfully", path.display());
+        return finish_packfile(path, size, stored, redundancy);
     }
 
     Err(anyhow!("Packfile not sent"))
 }
 
+/// Delete a local packfile if enough peers store it or its shards, returns whether more peers are
+/// needed.
+fn finish_packfile(path: &Path, size: u64, stored: usize, redundancy: Redundancy) -> anyhow::Result<bool> {
+    if stored < redundancy.peers_needed() {
+        return Ok(true);
+    }
+
+    fs::remove_file(path)?;
+    BACKUP_ORCHESTRATOR.get().unwrap().increment_packfile_bytes_sent(size);
+
+    println!("[send] packfile {} stored by {stored} peers, deleted", path.display());
+    Ok(false)
+}
+
 /// Send a storage request if we haven't sent one in a while.
 async fn send_storage_request_if_needed() -> anyhow::Result<()> {
     let orchestrator = BACKUP_ORCHESTRATOR.get().unwrap();
     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
 
     if now - orchestrator.get_storage_request_last_sent() > STORAGE_REQUEST_RETRY_DELAY {
-        let request_size = estimate_storage_request_size();
+        let request_size = estimate_storage_request_size(Redundancy::load().await?);
         log!("[send] sending a new storage request of size {} B", request_size);
 
         requests::backup_storage_request(request_size).await?;
@@ -356,9 +641,15 @@ pub async fn connection_established(
 }
 
 #[allow(overlapping_range_endpoints, clippy::match_overlapping_arm)]
-fn estimate_storage_request_size() -> u64 {
+fn estimate_storage_request_size(redundancy: Redundancy) -> u64 {
     let orchestrator = BACKUP_ORCHESTRATOR.get().unwrap();
-    let difference = orchestrator.get_size_estimate() - orchestrator.get_storage_request_fulfilled_size();
+
+    // every replica or shard of the data needs its own storage
+    let difference = (orchestrator
+        .get_size_estimate()
+        .saturating_mul(redundancy.storage_percent())
+        / 100)
+        .saturating_sub(orchestrator.get_storage_request_fulfilled_size());
 
     // exclusive range pattern are still nightly-only, this is probably the nicest solution
     match difference {
@@ -0,0 +1,92 @@
+//! Provides directory trees of snapshots to operations that walk them, such as diffs and searches.
+
+use shared::types::BlobHash;
+
+use crate::{
+    backup::{filesystem::Tree, restore::SelectiveFetcher},
+    log,
+    net_server::requests,
+    CONFIG,
+};
+
+/// Provides the trees of snapshots.
+#[async_trait::async_trait]
+pub trait TreeSource: Send {
+    /// Make the given trees available, all trees of a level are requested at once, so they can be
+    /// fetched together.
+    async fn prefetch(&mut self, hashes: &[BlobHash]) -> anyhow::Result<()>;
+
+    /// Returns a tree, along with the children of all its siblings.
+    async fn tree(&mut self, hash: &BlobHash) -> anyhow::Result<Tree>;
+}
+
+#[async_trait::async_trait]
+impl TreeSource for SelectiveFetcher {
+    async fn prefetch(&mut self, hashes: &[BlobHash]) -> anyhow::Result<()> {
+        self.fetch_blobs(hashes).await
+    }
+
+    async fn tree(&mut self, hash: &BlobHash) -> anyhow::Result<Tree> {
+        self.fetch_full_tree(hash).await
+    }
+}
+
+/// Keeps the trees provided by another source in the local database. Trees are content addressed,
+/// so a cached tree never changes, and walking the same trees again doesn't need any requests.
+pub struct CachedTrees<'a, S: TreeSource> {
+    inner: &'a mut S,
+}
+
+impl<'a, S: TreeSource> CachedTrees<'a, S> {
+    pub fn new(inner: &'a mut S) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: TreeSource> TreeSource for CachedTrees<'_, S> {
+    async fn prefetch(&mut self, hashes: &[BlobHash]) -> anyhow::Result<()> {
+        let config = CONFIG.get().unwrap();
+
+        let mut missing = Vec::new();
+        for hash in hashes {
+            if config.get_cached_tree(hash).await?.is_none() {
+                missing.push(*hash);
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        self.inner.prefetch(&missing).await
+    }
+
+    async fn tree(&mut self, hash: &BlobHash) -> anyhow::Result<Tree> {
+        let config = CONFIG.get().unwrap();
+        if let Some(tree) = config.get_cached_tree(hash).await? {
+            return Ok(tree);
+        }
+
+        let tree = self.inner.tree(hash).await?;
+        config.add_cached_tree(hash, &tree).await?;
+        Ok(tree)
+    }
+}
+
+/// Remove the cached trees that are no longer reachable from any snapshot known to the server, after
+/// snapshots have been forgotten or their data pruned.
+pub async fn evict_unreachable_trees() -> anyhow::Result<()> {
+    let snapshots: Vec<BlobHash> = requests::snapshot_list()
+        .await?
+        .into_iter()
+        .map(|s| s.snapshot_hash)
+        .collect();
+
+    let evicted = CONFIG.get().unwrap().evict_cached_trees(&snapshots).await?;
+    if evicted > 0 {
+        log!("[cache] removed {} cached trees of forgotten snapshots", evicted);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,267 @@
+//! Contains functions related to proof of storage audits of the peers storing our data.
+
+use shared::types::{ClientId, PackfileId};
+use sqlx::{sqlite::SqliteRow, Row};
+
+use crate::config::{Config, Transaction};
+
+/// A challenge computed when a packfile, or a shard of it, was sent to a peer. It's used for a
+/// single audit and then discarded.
+#[derive(Debug, Clone)]
+pub struct AuditChallenge {
+    pub packfile_id: PackfileId,
+    pub peer_id: ClientId,
+    /// Set if the peer stores only a shard of the packfile.
+    pub shard: Option<u8>,
+    pub key: [u8; 32],
+    pub offset: u64,
+    pub length: u64,
+    /// The keyed hash of the byte range that the peer has to answer with.
+    pub expected: [u8; 32],
+}
+
+/// The audit results of a single peer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AuditStats {
+    pub passed: u64,
+    pub failed: u64,
+    /// Unix timestamp of the last audit, if there was any.
+    pub last_audit: Option<i64>,
+    /// Whether the last audit was passed.
+    pub last_passed: bool,
+}
+
+impl Config {
+    /// Stores the challenges for auditing a peer that has just received a packfile or a shard.
+    pub async fn add_audit_challenges(&self, challenges: &[AuditChallenge]) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        for challenge in challenges {
+            transaction.add_audit_challenge(challenge).await?;
+        }
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Picks a random unused challenge for a peer, if there is any left.
+    pub async fn get_random_audit_challenge(
+        &self,
+        peer_id: ClientId,
+    ) -> anyhow::Result<Option<AuditChallenge>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_random_audit_challenge(peer_id).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Removes and returns the challenge for a peer with the given key, so it can't be used again.
+    pub async fn take_audit_challenge(
+        &self,
+        peer_id: ClientId,
+        key: [u8; 32],
+    ) -> anyhow::Result<Option<AuditChallenge>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.take_audit_challenge(peer_id, key).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Removes the challenges for packfiles that a peer no longer stores.
+    pub async fn remove_audit_challenges(
+        &self,
+        peer_id: ClientId,
+        packfiles: &[PackfileId],
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.remove_audit_challenges(peer_id, packfiles).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Records the result of an audit of a peer.
+    pub async fn add_audit_result(
+        &self,
+        peer_id: ClientId,
+        packfile_id: PackfileId,
+        passed: bool,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.add_audit_result(peer_id, packfile_id, passed).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets the summary of all audits of a peer.
+    pub async fn get_audit_stats(&self, peer_id: ClientId) -> anyhow::Result<AuditStats> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_audit_stats(peer_id).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets whether the most recent audits of a peer were passed, newest first.
+    pub async fn get_recent_audit_results(&self, peer_id: ClientId, limit: u32) -> anyhow::Result<Vec<bool>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_recent_audit_results(peer_id, limit).await;
+        transaction.commit().await?;
+
+        result
+    }
+}
+
+impl Transaction<'_> {
+    /// Stores a challenge for auditing a peer.
+    pub async fn add_audit_challenge(&mut self, challenge: &AuditChallenge) -> anyhow::Result<()> {
+        sqlx::query(
+            "insert into audit_challenges
+                (packfile_id, peer_id, shard, key, offset, length, expected)
+                values ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(&challenge.packfile_id[..])
+        .bind(&challenge.peer_id[..])
+        .bind(challenge.shard.map(i64::from))
+        .bind(&challenge.key[..])
+        .bind(i64::try_from(challenge.offset)?)
+        .bind(i64::try_from(challenge.length)?)
+        .bind(&challenge.expected[..])
+        .execute(&mut self.transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Picks a random unused challenge for a peer.
+    pub async fn get_random_audit_challenge(
+        &mut self,
+        peer_id: ClientId,
+    ) -> anyhow::Result<Option<AuditChallenge>> {
+        let row = sqlx::query(
+            "select packfile_id, peer_id, shard, key, offset, length, expected
+                from audit_challenges where peer_id = $1 order by random() limit 1",
+        )
+        .bind(&peer_id[..])
+        .fetch_optional(&mut self.transaction)
+        .await?;
+
+        row.as_ref().map(row_to_audit_challenge).transpose()
+    }
+
+    /// Removes and returns the challenge for a peer with the given key.
+    pub async fn take_audit_challenge(
+        &mut self,
+        peer_id: ClientId,
+        key: [u8; 32],
+    ) -> anyhow::Result<Option<AuditChallenge>> {
+        let row = sqlx::query(
+            "delete from audit_challenges where peer_id = $1 and key = $2
+                returning packfile_id, peer_id, shard, key, offset, length, expected",
+        )
+        .bind(&peer_id[..])
+        .bind(&key[..])
+        .fetch_optional(&mut self.transaction)
+        .await?;
+
+        row.as_ref().map(row_to_audit_challenge).transpose()
+    }
+
+    /// Removes the challenges for packfiles that a peer no longer stores.
+    pub async fn remove_audit_challenges(
+        &mut self,
+        peer_id: ClientId,
+        packfiles: &[PackfileId],
+    ) -> anyhow::Result<()> {
+        for packfile_id in packfiles {
+            sqlx::query("delete from audit_challenges where packfile_id = $1 and peer_id = $2")
+                .bind(&packfile_id[..])
+                .bind(&peer_id[..])
+                .execute(&mut self.transaction)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Records the result of an audit of a peer.
+    pub async fn add_audit_result(
+        &mut self,
+        peer_id: ClientId,
+        packfile_id: PackfileId,
+        passed: bool,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "insert into audit_results (peer_id, packfile_id, timestamp, passed) values ($1, $2, $3, $4)",
+        )
+        .bind(&peer_id[..])
+        .bind(&packfile_id[..])
+        .bind(Config::get_unix_timestamp())
+        .bind(passed)
+        .execute(&mut self.transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Gets the summary of all audits of a peer.
+    pub async fn get_audit_stats(&mut self, peer_id: ClientId) -> anyhow::Result<AuditStats> {
+        let row = sqlx::query(
+            "select coalesce(sum(passed), 0), count(*), max(timestamp),
+                (select passed from audit_results where peer_id = $1 order by timestamp desc, id desc limit 1)
+                from audit_results where peer_id = $1",
+        )
+        .bind(&peer_id[..])
+        .fetch_one(&mut self.transaction)
+        .await?;
+
+        let passed: i64 = row.try_get(0)?;
+        let total: i64 = row.try_get(1)?;
+        let last_passed: Option<bool> = row.try_get(3)?;
+
+        Ok(AuditStats {
+            passed: u64::try_from(passed)?,
+            failed: u64::try_from(total - passed)?,
+            last_audit: row.try_get(2)?,
+            last_passed: last_passed.unwrap_or_default(),
+        })
+    }
+
+    /// Gets whether the most recent audits of a peer were passed, newest first.
+    pub async fn get_recent_audit_results(
+        &mut self,
+        peer_id: ClientId,
+        limit: u32,
+    ) -> anyhow::Result<Vec<bool>> {
+        let rows = sqlx::query(
+            "select passed from audit_results where peer_id = $1 order by timestamp desc, id desc limit $2",
+        )
+        .bind(&peer_id[..])
+        .bind(limit)
+        .fetch_all(&mut self.transaction)
+        .await?;
+
+        rows.iter().map(|row| Ok(row.try_get(0)?)).collect()
+    }
+}
+
+/// Converts a database row into an audit challenge.
+fn row_to_audit_challenge(row: &SqliteRow) -> anyhow::Result<AuditChallenge> {
+    let packfile_id: &[u8] = row.try_get(0)?;
+    let peer_id: &[u8] = row.try_get(1)?;
+    let shard: Option<i64> = row.try_get(2)?;
+    let key: &[u8] = row.try_get(3)?;
+    let expected: &[u8] = row.try_get(6)?;
+
+    Ok(AuditChallenge {
+        packfile_id: packfile_id.try_into()?,
+        peer_id: peer_id.try_into()?,
+        shard: shard.map(u8::try_from).transpose()?,
+        key: key.try_into()?,
+        offset: u64::try_from(row.try_get::<i64, _>(4)?)?,
+        length: u64::try_from(row.try_get::<i64, _>(5)?)?,
+        expected: expected.try_into()?,
+    })
+}
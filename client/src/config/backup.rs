@@ -5,8 +5,11 @@ use std::path::PathBuf;
 use sqlx::Row;
 
 use crate::{
+    backup::{erasure::ErasureCoding, retention::RetentionPolicy},
     config::{Config, Transaction},
-    defaults::{APP_FOLDER_NAME, BACKUP_BUFFER_FOLDER_NAME},
+    defaults::{
+        APP_FOLDER_NAME, BACKUP_BUFFER_FOLDER_NAME, DEFAULT_REPLICATION_FACTOR, METADATA_CACHE_FOLDER,
+    },
 };
 
 impl Config {
@@ -37,19 +40,64 @@ impl Config {
         Ok(dir)
     }
 
-    /// Stores the highest sent index number.
-    pub async fn save_highest_sent_index_number(&self, index: u32) -> anyhow::Result<()> {
+    /// Gets the path to the folder with local copies of metadata packfiles.
+    pub fn get_metadata_cache_folder() -> anyhow::Result<PathBuf> {
+        let mut dir = Config::get_data_dir()?;
+        dir.push(APP_FOLDER_NAME);
+        dir.push(METADATA_CACHE_FOLDER);
+
+        Ok(dir)
+    }
+
+    /// Sets the snapshot retention policy.
+    pub async fn set_retention_policy(&self, policy: RetentionPolicy) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.set_retention_policy(policy).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets the snapshot retention policy, or the default (empty) policy if not set.
+    pub async fn get_retention_policy(&self) -> anyhow::Result<RetentionPolicy> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_retention_policy().await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Sets the number of distinct peers that should store each packfile.
+    pub async fn set_replication_factor(&self, factor: u32) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.set_replication_factor(factor).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets the number of distinct peers that should store each packfile.
+    pub async fn get_replication_factor(&self) -> anyhow::Result<u32> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_replication_factor().await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Sets the erasure coding of packfiles, `None` disables it in favor of replication.
+    pub async fn set_erasure_coding(&self, coding: Option<ErasureCoding>) -> anyhow::Result<()> {
         let mut transaction = self.transaction().await?;
-        let result = transaction.save_highest_sent_index_number(index).await;
+        let result = transaction.set_erasure_coding(coding).await;
         transaction.commit().await?;
 
         result
     }
 
-    /// Gets the highest sent index number.
-    pub async fn get_highest_sent_index_number(&self) -> anyhow::Result<Option<u32>> {
+    /// Gets the erasure coding of packfiles, `None` if packfiles are replicated instead.
+    pub async fn get_erasure_coding(&self) -> anyhow::Result<Option<ErasureCoding>> {
         let mut transaction = self.transaction().await?;
-        let result = transaction.get_highest_sent_index_number().await;
+        let result = transaction.get_erasure_coding().await;
         transaction.commit().await?;
 
         result
@@ -77,23 +125,69 @@ impl Transaction<'_> {
         Ok(path.map(|path: String| PathBuf::from(path)))
     }
 
-    /// Stores the highest sent index number.
-    pub async fn save_highest_sent_index_number(&mut self, index: u32) -> anyhow::Result<()> {
-        sqlx::query("insert or replace into config (key, value) values ('highest_sent_index', $1)")
-            .bind(i64::from(index))
+    /// Sets the snapshot retention policy.
+    pub async fn set_retention_policy(&mut self, policy: RetentionPolicy) -> anyhow::Result<()> {
+        sqlx::query("insert or replace into config (key, value) values ('retention_policy', $1)")
+            .bind(serde_json::to_string(&policy)?)
+            .execute(&mut self.transaction)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Gets the snapshot retention policy, or the default (empty) policy if not set.
+    pub async fn get_retention_policy(&mut self) -> anyhow::Result<RetentionPolicy> {
+        let policy: Option<String> = sqlx::query("select value from config where key = 'retention_policy'")
+            .fetch_optional(&mut self.transaction)
+            .await?
+            .map(|row| row.get(0));
+
+        match policy {
+            Some(policy) => Ok(serde_json::from_str(&policy)?),
+            None => Ok(RetentionPolicy::default()),
+        }
+    }
+
+    /// Sets the number of distinct peers that should store each packfile.
+    pub async fn set_replication_factor(&mut self, factor: u32) -> anyhow::Result<()> {
+        sqlx::query("insert or replace into config (key, value) values ('replication_factor', $1)")
+            .bind(i64::from(factor))
+            .execute(&mut self.transaction)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Gets the number of distinct peers that should store each packfile.
+    pub async fn get_replication_factor(&mut self) -> anyhow::Result<u32> {
+        let factor = sqlx::query("select value from config where key = 'replication_factor'")
+            .fetch_optional(&mut self.transaction)
+            .await?
+            .map(|row| row.get(0));
+
+        Ok(factor.unwrap_or(DEFAULT_REPLICATION_FACTOR))
+    }
+
+    /// Sets the erasure coding of packfiles, `None` disables it in favor of replication.
+    pub async fn set_erasure_coding(&mut self, coding: Option<ErasureCoding>) -> anyhow::Result<()> {
+        sqlx::query("insert or replace into config (key, value) values ('erasure_coding', $1)")
+            .bind(serde_json::to_string(&coding)?)
             .execute(&mut self.transaction)
             .await?;
 
         Ok(())
     }
 
-    /// Gets the highest sent index number.
-    pub async fn get_highest_sent_index_number(&mut self) -> anyhow::Result<Option<u32>> {
-        let index = sqlx::query("select value from config where key = 'highest_sent_index'")
+    /// Gets the erasure coding of packfiles, `None` if packfiles are replicated instead.
+    pub async fn get_erasure_coding(&mut self) -> anyhow::Result<Option<ErasureCoding>> {
+        let coding: Option<String> = sqlx::query("select value from config where key = 'erasure_coding'")
             .fetch_optional(&mut self.transaction)
             .await?
             .map(|row| row.get(0));
 
-        Ok(index)
+        match coding {
+            Some(coding) => Ok(serde_json::from_str(&coding)?),
+            None => Ok(None),
+        }
     }
 }
@@ -3,10 +3,15 @@
 //! The `Config` struct is used create a `Transaction` and has shorthand methods for most of the
 //! same methods in `Transaction`.
 
+pub mod audits;
 pub mod backup;
 pub mod identity;
 pub mod log;
+pub mod packfiles;
 pub mod peers;
+pub mod reliability;
+pub mod restore;
+pub mod tree_cache;
 
 use std::{
     env, fs,
@@ -36,8 +41,6 @@ pub struct Transaction<'a> {
 impl Config {
     /// Initializes the config database, creating the necessary tables if they don't exist.
     pub async fn init() -> Self {
-        let mut structure_initialized = true;
-
         let mut config_file = Config::get_config_dir().expect("Cannot find the system config directory");
         config_file.push(crate::defaults::APP_FOLDER_NAME);
 
@@ -52,7 +55,6 @@ impl Config {
         {
             fs::File::create(config_file.clone())
                 .unwrap_or_else(|_| panic!("Unable to write the config file at {}", config_file.display()));
-            structure_initialized = false;
         }
 
         let db_url = String::from("sqlite://")
@@ -68,11 +70,11 @@ impl Config {
                 .unwrap_or_else(|_| panic!("Unable to open a config file at {}", config_file.display())),
         };
 
-        if !structure_initialized {
-            Self::create_db_structure(&config.db_pool)
-                .await
-                .expect("Failed to create config database structure");
-        }
+        // all tables are created only if they don't exist, so this also adds tables introduced
+        // in newer versions to existing databases
+        Self::create_db_structure(&config.db_pool)
+            .await
+            .expect("Failed to create config database structure");
 
         config
     }
@@ -103,6 +105,7 @@ impl Config {
     }
 
     /// Creates the necessary tables in the database.
+    #[allow(clippy::too_many_lines)]
     async fn create_db_structure(pool: &SqlitePool) -> Result<SqliteQueryResult, Error> {
         sqlx::query(
             "create table if not exists config
@@ -131,6 +134,100 @@ impl Config {
                 timestamp  integer,
                 event_type integer,
                 event_data blob
+            );
+
+            create table if not exists packfile_locations
+            (
+                packfile_id blob    not null,
+                peer_id     blob    not null,
+                size        integer not null,
+                sent        integer not null,
+                checksum    blob    not null,
+                shard       integer,
+                data_shards integer,
+                constraint packfile_locations_pk
+                    primary key (packfile_id, peer_id)
+            );
+
+            create index if not exists packfile_locations_peer_id
+                on packfile_locations (peer_id);
+
+            create table if not exists index_locations
+            (
+                index_num integer not null,
+                peer_id   blob    not null,
+                sent      integer not null,
+                constraint index_locations_pk
+                    primary key (index_num, peer_id)
+            );
+
+            create table if not exists restored_packfiles
+            (
+                packfile_id blob not null
+                    constraint restored_packfiles_pk
+                        primary key
+            );
+
+            create table if not exists restored_files
+            (
+                path         text not null
+                    constraint restored_files_pk
+                        primary key,
+                written_path text not null
+            );
+
+            create table if not exists tree_cache
+            (
+                hash blob not null
+                    constraint tree_cache_pk
+                        primary key,
+                tree blob not null
+            );
+
+            create table if not exists audit_challenges
+            (
+                id          integer primary key,
+                packfile_id blob    not null,
+                peer_id     blob    not null,
+                shard       integer,
+                key         blob    not null,
+                offset      integer not null,
+                length      integer not null,
+                expected    blob    not null
+            );
+
+            create index if not exists audit_challenges_peer_id
+                on audit_challenges (peer_id);
+
+            create table if not exists audit_results
+            (
+                id          integer primary key,
+                peer_id     blob    not null,
+                packfile_id blob    not null,
+                timestamp   integer not null,
+                passed      integer not null
+            );
+
+            create table if not exists peer_states
+            (
+                peer_id blob    not null
+                    constraint peer_states_pk
+                        primary key,
+                state   integer not null,
+                reason  text    not null,
+                changed integer not null
+            );
+
+            create table if not exists peer_reliability
+            (
+                peer_id             blob    not null
+                    constraint peer_reliability_pk
+                        primary key,
+                connection_attempts integer not null,
+                connections         integer not null,
+                transfer_attempts   integer not null,
+                transfers           integer not null,
+                ack_latency         integer
             );",
         )
         .execute(pool)
@@ -156,6 +253,26 @@ impl Config {
     }
 }
 
+/// Initializes the global config with a database in a temporary folder, for tests that need it.
+#[cfg(test)]
+pub async fn init_test_config() -> &'static Config {
+    crate::CONFIG
+        .get_or_init(|| async {
+            let dir = env::temp_dir().join(format!("client-test-{}", std::process::id()));
+            fs::remove_dir_all(&dir).ok();
+            env::set_var("CONFIG_DIR", &dir);
+            env::set_var("DATA_DIR", &dir);
+
+            let config = Config::init().await;
+            config
+                .save_obfuscation_key(crate::identity::generate_obfuscation_key().unwrap())
+                .await
+                .unwrap();
+            config
+        })
+        .await
+}
+
 impl Transaction<'_> {
     /// Commits the transaction.
     pub async fn commit(self) -> anyhow::Result<()> {
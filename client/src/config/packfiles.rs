@@ -0,0 +1,330 @@
+//! Contains functions related to tracking which peers store our packfiles and index files.
+
+use std::collections::HashSet;
+
+use shared::types::{ClientId, PackfileId};
+use sqlx::{sqlite::SqliteRow, Row};
+
+use crate::config::{Config, Transaction};
+
+/// A copy of a packfile, or an erasure coded shard of it, stored by a peer.
+#[derive(Debug, Clone)]
+pub struct PackfileLocation {
+    pub packfile_id: PackfileId,
+    pub peer_id: ClientId,
+    pub size: i64,
+    pub sent: i64,
+    pub checksum: [u8; 32],
+    /// Set if the peer stores only a shard of the packfile.
+    pub shard: Option<ShardLocation>,
+}
+
+/// Identifies a shard of an erasure coded packfile.
+#[derive(Debug, Clone, Copy)]
+pub struct ShardLocation {
+    pub index: u8,
+    pub data_shards: u8,
+}
+
+impl Config {
+    /// Records that a packfile, or a shard of it, has been sent to a peer.
+    pub async fn add_packfile_location(
+        &self,
+        packfile_id: PackfileId,
+        peer_id: ClientId,
+        size: u64,
+        checksum: [u8; 32],
+        shard: Option<ShardLocation>,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction
+            .add_packfile_location(packfile_id, peer_id, size, checksum, shard)
+            .await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets all peers that store a copy of a packfile.
+    pub async fn get_packfile_locations(
+        &self,
+        packfile_id: PackfileId,
+    ) -> anyhow::Result<Vec<PackfileLocation>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_packfile_locations(packfile_id).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets all packfiles stored by a peer.
+    pub async fn get_peer_packfiles(&self, peer_id: ClientId) -> anyhow::Result<Vec<PackfileLocation>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_peer_packfiles(peer_id).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets the total size of the packfiles and shards stored by a peer.
+    pub async fn get_peer_stored_size(&self, peer_id: ClientId) -> anyhow::Result<u64> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_peer_stored_size(peer_id).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Removes the records of packfiles stored by a peer, returns the total size of the removed packfiles.
+    pub async fn remove_packfile_locations(
+        &self,
+        peer_id: ClientId,
+        packfiles: &[PackfileId],
+    ) -> anyhow::Result<u64> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.remove_packfile_locations(peer_id, packfiles).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets the lowest number of peers storing a packfile, out of all packfiles first sent before
+    /// the given time. Returns `None` if no such packfiles are recorded.
+    pub async fn get_min_packfile_replicas(&self, sent_before: i64) -> anyhow::Result<Option<u32>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_min_packfile_replicas(sent_before).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets all peers that store at least one of our packfiles or shards.
+    pub async fn get_packfile_peers(&self) -> anyhow::Result<Vec<ClientId>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_packfile_peers().await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Records that an index file has been sent to a peer.
+    pub async fn add_index_location(&self, index_num: u32, peer_id: ClientId) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.add_index_location(index_num, peer_id).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets the numbers of all index files stored by a peer.
+    pub async fn get_peer_index_files(&self, peer_id: ClientId) -> anyhow::Result<HashSet<u32>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_peer_index_files(peer_id).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Removes the records of index files stored by a peer.
+    pub async fn remove_index_locations(&self, peer_id: ClientId, index_files: &[u32]) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.remove_index_locations(peer_id, index_files).await;
+        transaction.commit().await?;
+
+        result
+    }
+}
+
+impl Transaction<'_> {
+    /// Records that a packfile, or a shard of it, has been sent to a peer.
+    pub async fn add_packfile_location(
+        &mut self,
+        packfile_id: PackfileId,
+        peer_id: ClientId,
+        size: u64,
+        checksum: [u8; 32],
+        shard: Option<ShardLocation>,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "insert or replace into packfile_locations
+                (packfile_id, peer_id, size, sent, checksum, shard, data_shards)
+                values ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(&packfile_id[..])
+        .bind(&peer_id[..])
+        .bind(i64::try_from(size)?)
+        .bind(Config::get_unix_timestamp())
+        .bind(&checksum[..])
+        .bind(shard.map(|s| i64::from(s.index)))
+        .bind(shard.map(|s| i64::from(s.data_shards)))
+        .execute(&mut self.transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Gets all peers that store a copy of a packfile.
+    pub async fn get_packfile_locations(
+        &mut self,
+        packfile_id: PackfileId,
+    ) -> anyhow::Result<Vec<PackfileLocation>> {
+        let rows = sqlx::query(
+            "select packfile_id, peer_id, size, sent, checksum, shard, data_shards
+                from packfile_locations where packfile_id = $1",
+        )
+        .bind(&packfile_id[..])
+        .fetch_all(&mut self.transaction)
+        .await?;
+
+        rows.iter().map(row_to_packfile_location).collect()
+    }
+
+    /// Gets all packfiles stored by a peer.
+    pub async fn get_peer_packfiles(&mut self, peer_id: ClientId) -> anyhow::Result<Vec<PackfileLocation>> {
+        let rows = sqlx::query(
+            "select packfile_id, peer_id, size, sent, checksum, shard, data_shards
+                from packfile_locations where peer_id = $1",
+        )
+        .bind(&peer_id[..])
+        .fetch_all(&mut self.transaction)
+        .await?;
+
+        rows.iter().map(row_to_packfile_location).collect()
+    }
+
+    /// Gets the total size of the packfiles and shards stored by a peer.
+    pub async fn get_peer_stored_size(&mut self, peer_id: ClientId) -> anyhow::Result<u64> {
+        let size: i64 =
+            sqlx::query("select coalesce(sum(size), 0) from packfile_locations where peer_id = $1")
+                .bind(&peer_id[..])
+                .fetch_one(&mut self.transaction)
+                .await?
+                .try_get(0)?;
+
+        Ok(u64::try_from(size)?)
+    }
+
+    /// Removes the records of packfiles stored by a peer, returns the total size of the removed packfiles.
+    pub async fn remove_packfile_locations(
+        &mut self,
+        peer_id: ClientId,
+        packfiles: &[PackfileId],
+    ) -> anyhow::Result<u64> {
+        let mut removed_size = 0;
+        for packfile_id in packfiles {
+            let size: Option<i64> = sqlx::query(
+                "delete from packfile_locations where packfile_id = $1 and peer_id = $2 returning size",
+            )
+            .bind(&packfile_id[..])
+            .bind(&peer_id[..])
+            .fetch_optional(&mut self.transaction)
+            .await?
+            .map(|row| row.try_get(0))
+            .transpose()?;
+
+            removed_size += u64::try_from(size.unwrap_or(0))?;
+        }
+
+        Ok(removed_size)
+    }
+
+    /// Gets the lowest number of peers storing a packfile, out of all packfiles first sent before
+    /// the given time. Returns `None` if no such packfiles are recorded. For erasure coded
+    /// packfiles, this is the number of peers that can be lost, plus one, which is the same as
+    /// the number of copies of a replicated packfile.
+    pub async fn get_min_packfile_replicas(&mut self, sent_before: i64) -> anyhow::Result<Option<u32>> {
+        let replicas: Option<i64> = sqlx::query(
+            "select min(copies) from
+                (select case when max(data_shards) is null then count(*)
+                        else max(count(*) - max(data_shards) + 1, 0) end as copies,
+                    min(sent) as first_sent
+                from packfile_locations group by packfile_id)
+                where first_sent <= $1",
+        )
+        .bind(sent_before)
+        .fetch_one(&mut self.transaction)
+        .await?
+        .try_get(0)?;
+
+        Ok(replicas.map(u32::try_from).transpose()?)
+    }
+
+    /// Gets all peers that store at least one of our packfiles or shards.
+    pub async fn get_packfile_peers(&mut self) -> anyhow::Result<Vec<ClientId>> {
+        let rows = sqlx::query("select distinct peer_id from packfile_locations")
+            .fetch_all(&mut self.transaction)
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                let peer_id: &[u8] = row.try_get(0)?;
+                Ok(peer_id.try_into()?)
+            })
+            .collect()
+    }
+
+    /// Records that an index file has been sent to a peer.
+    pub async fn add_index_location(&mut self, index_num: u32, peer_id: ClientId) -> anyhow::Result<()> {
+        sqlx::query("insert or replace into index_locations (index_num, peer_id, sent) values ($1, $2, $3)")
+            .bind(i64::from(index_num))
+            .bind(&peer_id[..])
+            .bind(Config::get_unix_timestamp())
+            .execute(&mut self.transaction)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Gets the numbers of all index files stored by a peer.
+    pub async fn get_peer_index_files(&mut self, peer_id: ClientId) -> anyhow::Result<HashSet<u32>> {
+        let rows = sqlx::query("select index_num from index_locations where peer_id = $1")
+            .bind(&peer_id[..])
+            .fetch_all(&mut self.transaction)
+            .await?;
+
+        rows.iter()
+            .map(|row| Ok(u32::try_from(row.try_get::<i64, _>(0)?)?))
+            .collect()
+    }
+
+    /// Removes the records of index files stored by a peer.
+    pub async fn remove_index_locations(
+        &mut self,
+        peer_id: ClientId,
+        index_files: &[u32],
+    ) -> anyhow::Result<()> {
+        for index_num in index_files {
+            sqlx::query("delete from index_locations where index_num = $1 and peer_id = $2")
+                .bind(i64::from(*index_num))
+                .bind(&peer_id[..])
+                .execute(&mut self.transaction)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Converts a database row into a packfile location.
+fn row_to_packfile_location(row: &SqliteRow) -> anyhow::Result<PackfileLocation> {
+    let packfile_id: &[u8] = row.try_get(0)?;
+    let peer_id: &[u8] = row.try_get(1)?;
+    let checksum: &[u8] = row.try_get(4)?;
+    let shard: Option<i64> = row.try_get(5)?;
+    let data_shards: Option<i64> = row.try_get(6)?;
+
+    Ok(PackfileLocation {
+        packfile_id: packfile_id.try_into()?,
+        peer_id: peer_id.try_into()?,
+        size: row.try_get(2)?,
+        sent: row.try_get(3)?,
+        checksum: checksum.try_into()?,
+        shard: match (shard, data_shards) {
+            (Some(index), Some(data_shards)) => Some(ShardLocation {
+                index: u8::try_from(index)?,
+                data_shards: u8::try_from(data_shards)?,
+            }),
+            _ => None,
+        },
+    })
+}
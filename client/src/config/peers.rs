@@ -2,6 +2,8 @@
 
 use std::path::PathBuf;
 
+use anyhow::anyhow;
+use serde::Serialize;
 use shared::types::ClientId;
 use sqlx::Row;
 
@@ -18,6 +20,46 @@ pub struct PeerInfo {
     pub last_seen: i64,
 }
 
+/// The state of a peer. Peers are active unless they were found to be lost or the user decided
+/// otherwise, the data stored by lost and evacuated peers is replicated to other peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PeerState {
+    Active = 0,
+    /// The peer is considered lost, replicating its data elsewhere is in progress.
+    Lost = 1,
+    /// The data of the lost or evacuated peer was replicated elsewhere, it's not used anymore.
+    Replaced = 2,
+    /// The peer is not used for our data, and can't send us more of its data.
+    Blocked = 3,
+    /// The user wants our data moved away from the peer, replicating it elsewhere is in progress.
+    Evacuating = 4,
+}
+
+impl PeerState {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(PeerState::Active),
+            1 => Some(PeerState::Lost),
+            2 => Some(PeerState::Replaced),
+            3 => Some(PeerState::Blocked),
+            4 => Some(PeerState::Evacuating),
+            _ => None,
+        }
+    }
+
+    fn to_id(self) -> u8 {
+        self as u8
+    }
+}
+
+/// The recorded state of a peer, along with the reason and the time of the last change.
+#[derive(Debug, Clone)]
+pub struct PeerStateInfo {
+    pub state: PeerState,
+    pub reason: String,
+    pub changed: i64,
+}
+
 impl Config {
     /// Gets the directory where received packfiles are stored.
     pub fn get_received_packfiles_folder(&self) -> anyhow::Result<PathBuf> {
@@ -77,7 +119,25 @@ impl Config {
         Ok(())
     }
 
-    /// Get peers that have negotiated storage available.
+    /// Decrement peer's transmitted bytes, for example after our files were deleted by the peer.
+    pub async fn peer_decrement_transmitted(&self, peer_id: ClientId, amount: u64) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        transaction.peer_decrement_transmitted(peer_id, amount).await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Decrement peer's received bytes, for example after their files were deleted.
+    pub async fn peer_decrement_received(&self, peer_id: ClientId, amount: u64) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        transaction.peer_decrement_received(peer_id, amount).await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Get peers that have negotiated storage available, the most reliable first.
     pub async fn find_peers_with_storage(&self) -> anyhow::Result<Vec<ClientId>> {
         let mut transaction = self.transaction().await?;
         let peers = transaction.find_peers_with_storage().await?;
@@ -103,6 +163,47 @@ impl Config {
 
         Ok(())
     }
+
+    /// Set the state of a peer, with the reason for the change.
+    pub async fn set_peer_state(
+        &self,
+        peer_id: ClientId,
+        state: PeerState,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.set_peer_state(peer_id, state, reason).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Get the recorded state of a peer, `None` if the peer is active and never was in another state.
+    pub async fn get_peer_state(&self, peer_id: ClientId) -> anyhow::Result<Option<PeerStateInfo>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_peer_state(peer_id).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Get all peers recorded in the given state.
+    pub async fn get_peers_in_state(&self, state: PeerState) -> anyhow::Result<Vec<ClientId>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_peers_in_state(state).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Get the active peers that store at least one of our packfiles or shards.
+    pub async fn get_active_packfile_peers(&self) -> anyhow::Result<Vec<ClientId>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_active_packfile_peers().await;
+        transaction.commit().await?;
+
+        result
+    }
 }
 
 impl Transaction<'_> {
@@ -172,24 +273,60 @@ impl Transaction<'_> {
         Ok(())
     }
 
-    /// Get peers that have negotiated storage available.
+    /// Decrement peer's transmitted bytes, never going below zero.
+    pub async fn peer_decrement_transmitted(&mut self, peer_id: ClientId, amount: u64) -> anyhow::Result<()> {
+        sqlx::query(
+            "update peers set bytes_transmitted = max(bytes_transmitted - $1, 0), last_seen = $2 where pubkey = $3",
+        )
+        .bind(i64::try_from(amount)?)
+        .bind(Config::get_unix_timestamp())
+        .bind(&peer_id[..])
+        .execute(&mut self.transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Decrement peer's received bytes, never going below zero.
+    pub async fn peer_decrement_received(&mut self, peer_id: ClientId, amount: u64) -> anyhow::Result<()> {
+        sqlx::query(
+            "update peers set bytes_received = max(bytes_received - $1, 0), last_seen = $2 where pubkey = $3",
+        )
+        .bind(i64::try_from(amount)?)
+        .bind(Config::get_unix_timestamp())
+        .bind(&peer_id[..])
+        .execute(&mut self.transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get active peers that have negotiated storage available. Peers are ordered by their reliability
+    /// score rounded to a tenth, and peers with a similar score by the most free storage.
     pub async fn find_peers_with_storage(&mut self) -> anyhow::Result<Vec<ClientId>> {
         let rows = sqlx::query(
             "select pubkey, (bytes_negotiated - bytes_transmitted) as free_storage \
-             from peers where free_storage > 0 or abs(free_storage) < $1 \
+             from peers where (free_storage > 0 or abs(free_storage) < $1) \
+             and pubkey not in (select peer_id from peer_states where state != $2) \
              order by free_storage desc",
         )
         .bind(PEER_STORAGE_USAGE_SPREAD)
+        .bind(PeerState::Active.to_id())
         .fetch_all(&mut self.transaction)
         .await?;
 
-        let mut peers: Vec<ClientId> = Vec::new();
+        let mut peers: Vec<(ClientId, f64)> = Vec::new();
         for row in rows {
             let peer_id: &[u8] = row.try_get(0)?;
-            peers.push(peer_id.try_into()?);
+            let peer_id = peer_id.try_into()?;
+            let score = self.get_peer_reliability(peer_id).await?.score();
+            peers.push((peer_id, (score * 10.0).round()));
         }
 
-        Ok(peers)
+        // the sort is stable, so peers with the same rounded score stay ordered by free storage
+        peers.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        Ok(peers.into_iter().map(|(peer_id, _)| peer_id).collect())
     }
 
     /// Get all peers with, optionally, last seen time within the given limit.
@@ -235,4 +372,77 @@ impl Transaction<'_> {
 
         Ok(())
     }
+
+    /// Set the state of a peer, with the reason for the change.
+    pub async fn set_peer_state(
+        &mut self,
+        peer_id: ClientId,
+        state: PeerState,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "insert or replace into peer_states (peer_id, state, reason, changed) values ($1, $2, $3, $4)",
+        )
+        .bind(&peer_id[..])
+        .bind(state.to_id())
+        .bind(reason)
+        .bind(Config::get_unix_timestamp())
+        .execute(&mut self.transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get the recorded state of a peer.
+    pub async fn get_peer_state(&mut self, peer_id: ClientId) -> anyhow::Result<Option<PeerStateInfo>> {
+        let row = sqlx::query("select state, reason, changed from peer_states where peer_id = $1")
+            .bind(&peer_id[..])
+            .fetch_optional(&mut self.transaction)
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(PeerStateInfo {
+                state: PeerState::from_id(row.try_get(0)?).ok_or(anyhow!("invalid peer state"))?,
+                reason: row.try_get(1)?,
+                changed: row.try_get(2)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Get all peers recorded in the given state.
+    pub async fn get_peers_in_state(&mut self, state: PeerState) -> anyhow::Result<Vec<ClientId>> {
+        let rows = sqlx::query("select peer_id from peer_states where state = $1")
+            .bind(state.to_id())
+            .fetch_all(&mut self.transaction)
+            .await?;
+
+        let mut peers: Vec<ClientId> = Vec::new();
+        for row in rows {
+            let peer_id: &[u8] = row.try_get(0)?;
+            peers.push(peer_id.try_into()?);
+        }
+
+        Ok(peers)
+    }
+
+    /// Get the active peers that store at least one of our packfiles or shards, peers without a
+    /// recorded state are active.
+    pub async fn get_active_packfile_peers(&mut self) -> anyhow::Result<Vec<ClientId>> {
+        let rows = sqlx::query(
+            "select distinct peer_id from packfile_locations \
+             where peer_id not in (select peer_id from peer_states where state != $1)",
+        )
+        .bind(PeerState::Active.to_id())
+        .fetch_all(&mut self.transaction)
+        .await?;
+
+        let mut peers: Vec<ClientId> = Vec::new();
+        for row in rows {
+            let peer_id: &[u8] = row.try_get(0)?;
+            peers.push(peer_id.try_into()?);
+        }
+
+        Ok(peers)
+    }
 }
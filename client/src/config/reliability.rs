@@ -0,0 +1,221 @@
+//! Contains functions related to tracking how reliable the peers storing our data are.
+
+use std::time::Duration;
+
+use cast::From;
+use serde::Serialize;
+use shared::types::ClientId;
+use sqlx::Row;
+
+use crate::{
+    config::{audits::AuditStats, Config, Transaction},
+    defaults::{
+        RELIABILITY_ACK_LATENCY_REFERENCE, RELIABILITY_WEIGHT_ACK_LATENCY, RELIABILITY_WEIGHT_AUDITS,
+        RELIABILITY_WEIGHT_TRANSFERS, RELIABILITY_WEIGHT_UPTIME,
+    },
+};
+
+/// Everything observed about a peer that its reliability score is built from.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerReliability {
+    /// Attempts to reach the peer, and how many of them found it online.
+    pub connection_attempts: u64,
+    pub connections: u64,
+    /// Files sent to the peer, and how many of them were acknowledged.
+    pub transfer_attempts: u64,
+    pub transfers: u64,
+    /// Moving average of the time between sending a file and receiving its acknowledgement, in
+    /// milliseconds, if any file was acknowledged.
+    pub ack_latency: Option<u64>,
+    pub audits: AuditStats,
+}
+
+/// A coarse summary of the reliability score, for displaying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PeerHealth {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl PeerReliability {
+    /// The reliability score between 0 and 1, a weighted average of the uptime, the transfer success
+    /// rate, the audit pass rate and the acknowledgement latency. Every rate starts at one half for
+    /// a new peer, and moves towards the observed rate as more is known about the peer.
+    pub fn score(&self) -> f64 {
+        let uptime = smoothed_rate(self.connections, self.connection_attempts);
+        let transfers = smoothed_rate(self.transfers, self.transfer_attempts);
+        let audits = smoothed_rate(self.audits.passed, self.audits.passed + self.audits.failed);
+
+        // the latency is half as good as possible when it's equal to the reference
+        let reference = f64::cast(RELIABILITY_ACK_LATENCY_REFERENCE);
+        let ack_latency = self
+            .ack_latency
+            .map_or(0.5, |latency| reference / (reference + f64::cast(latency)));
+
+        RELIABILITY_WEIGHT_UPTIME * uptime
+            + RELIABILITY_WEIGHT_TRANSFERS * transfers
+            + RELIABILITY_WEIGHT_AUDITS * audits
+            + RELIABILITY_WEIGHT_ACK_LATENCY * ack_latency
+    }
+
+    /// Summarize the reliability score.
+    pub fn health(&self) -> PeerHealth {
+        match self.score() {
+            score if score >= 0.75 => PeerHealth::Good,
+            score if score >= 0.4 => PeerHealth::Fair,
+            _ => PeerHealth::Poor,
+        }
+    }
+}
+
+/// The rate of successes, as if there was one more success and one more failure, so that a few
+/// observations don't swing it to the extremes.
+fn smoothed_rate(successes: u64, attempts: u64) -> f64 {
+    (f64::cast(successes) + 1.0) / (f64::cast(attempts) + 2.0)
+}
+
+impl Config {
+    /// Records an attempt to reach a peer, and whether it was online.
+    pub async fn add_peer_connection_attempt(
+        &self,
+        peer_id: ClientId,
+        connected: bool,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.add_peer_connection_attempt(peer_id, connected).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Records a file sent to a peer, with the acknowledgement latency if it was acknowledged.
+    pub async fn add_peer_transfer(
+        &self,
+        peer_id: ClientId,
+        ack_latency: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.add_peer_transfer(peer_id, ack_latency).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets everything observed about a peer, to compute its reliability score.
+    pub async fn get_peer_reliability(&self, peer_id: ClientId) -> anyhow::Result<PeerReliability> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_peer_reliability(peer_id).await;
+        transaction.commit().await?;
+
+        result
+    }
+}
+
+impl Transaction<'_> {
+    /// Records an attempt to reach a peer, and whether it was online.
+    pub async fn add_peer_connection_attempt(
+        &mut self,
+        peer_id: ClientId,
+        connected: bool,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "insert into peer_reliability (peer_id, connection_attempts, connections, transfer_attempts, transfers)
+                values ($1, 1, $2, 0, 0)
+                on conflict (peer_id) do update set
+                    connection_attempts = connection_attempts + 1, connections = connections + $2",
+        )
+        .bind(&peer_id[..])
+        .bind(i64::from(connected))
+        .execute(&mut self.transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records a file sent to a peer, with the acknowledgement latency if it was acknowledged.
+    pub async fn add_peer_transfer(
+        &mut self,
+        peer_id: ClientId,
+        ack_latency: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        let latency = ack_latency
+            .map(|latency| i64::try_from(latency.as_millis()))
+            .transpose()?;
+
+        // the latency is an exponential moving average, so it follows changes of the peer's connection
+        sqlx::query(
+            "insert into peer_reliability
+                (peer_id, connection_attempts, connections, transfer_attempts, transfers, ack_latency)
+                values ($1, 0, 0, 1, $2 is not null, $2)
+                on conflict (peer_id) do update set
+                    transfer_attempts = transfer_attempts + 1,
+                    transfers = transfers + ($2 is not null),
+                    ack_latency = coalesce((coalesce(ack_latency, $2) * 7 + $2) / 8, ack_latency)",
+        )
+        .bind(&peer_id[..])
+        .bind(latency)
+        .execute(&mut self.transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Gets everything observed about a peer, to compute its reliability score.
+    pub async fn get_peer_reliability(&mut self, peer_id: ClientId) -> anyhow::Result<PeerReliability> {
+        let audits = self.get_audit_stats(peer_id).await?;
+        let row = sqlx::query(
+            "select connection_attempts, connections, transfer_attempts, transfers, ack_latency
+                from peer_reliability where peer_id = $1",
+        )
+        .bind(&peer_id[..])
+        .fetch_optional(&mut self.transaction)
+        .await?;
+
+        let Some(row) = row else {
+            return Ok(PeerReliability { audits, ..Default::default() });
+        };
+
+        let ack_latency: Option<i64> = row.try_get(4)?;
+        Ok(PeerReliability {
+            connection_attempts: u64::try_from(row.try_get::<i64, _>(0)?)?,
+            connections: u64::try_from(row.try_get::<i64, _>(1)?)?,
+            transfer_attempts: u64::try_from(row.try_get::<i64, _>(2)?)?,
+            transfers: u64::try_from(row.try_get::<i64, _>(3)?)?,
+            ack_latency: ack_latency.map(u64::try_from).transpose()?,
+            audits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reliable_peers_score_higher() {
+        let new = PeerReliability::default();
+        let reliable = PeerReliability {
+            connection_attempts: 20,
+            connections: 19,
+            transfer_attempts: 50,
+            transfers: 50,
+            ack_latency: Some(200),
+            audits: AuditStats { passed: 10, ..Default::default() },
+        };
+        let unreliable = PeerReliability {
+            connection_attempts: 20,
+            connections: 4,
+            transfer_attempts: 50,
+            transfers: 30,
+            ack_latency: Some(20_000),
+            audits: AuditStats { passed: 2, failed: 5, ..Default::default() },
+        };
+
+        assert!((new.score() - 0.5).abs() < 1e-9);
+        assert!(reliable.score() > new.score() && new.score() > unreliable.score());
+        assert_eq!(reliable.health(), PeerHealth::Good);
+        assert_eq!(new.health(), PeerHealth::Fair);
+        assert_eq!(unreliable.health(), PeerHealth::Poor);
+    }
+}
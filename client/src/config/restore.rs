@@ -0,0 +1,173 @@
+//! Contains functions related to tracking the progress of a restore, so it can be resumed.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use shared::types::PackfileId;
+use sqlx::Row;
+
+use crate::{
+    backup::restore::InterruptedRestore,
+    config::{Config, Transaction},
+};
+
+impl Config {
+    /// Records a newly started restore, forgetting the progress of any previous one.
+    pub async fn begin_restore(&self, restore: &InterruptedRestore) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.begin_restore(restore).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Forgets the restore in progress, after it has finished.
+    pub async fn end_restore(&self) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.end_restore().await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets the restore that was started but didn't finish, if any.
+    pub async fn get_interrupted_restore(&self) -> anyhow::Result<Option<InterruptedRestore>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_interrupted_restore().await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Records that a packfile has been fully received during the restore.
+    pub async fn add_restored_packfile(&self, packfile_id: PackfileId) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.add_restored_packfile(packfile_id).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets all packfiles received during the restore, they may have been deleted since.
+    pub async fn get_restored_packfiles(&self) -> anyhow::Result<Vec<PackfileId>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_restored_packfiles().await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Records that a file has been fully written during the restore, by its path relative to the
+    /// restore destination, along with the relative path it was actually written to.
+    pub async fn add_restored_file(&self, path: &Path, written_path: &Path) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.add_restored_file(path, written_path).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets the relative paths of all files written during the restore, along with the paths they
+    /// were written to.
+    pub async fn get_restored_files(&self) -> anyhow::Result<HashMap<PathBuf, PathBuf>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_restored_files().await;
+        transaction.commit().await?;
+
+        result
+    }
+}
+
+impl Transaction<'_> {
+    /// Records a newly started restore, forgetting the progress of any previous one.
+    pub async fn begin_restore(&mut self, restore: &InterruptedRestore) -> anyhow::Result<()> {
+        self.end_restore().await?;
+        sqlx::query("insert or replace into config (key, value) values ('restore_in_progress', $1)")
+            .bind(serde_json::to_string(restore)?)
+            .execute(&mut self.transaction)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Forgets the restore in progress, after it has finished.
+    pub async fn end_restore(&mut self) -> anyhow::Result<()> {
+        sqlx::query("delete from config where key = 'restore_in_progress'")
+            .execute(&mut self.transaction)
+            .await?;
+        sqlx::query("delete from restored_packfiles")
+            .execute(&mut self.transaction)
+            .await?;
+        sqlx::query("delete from restored_files")
+            .execute(&mut self.transaction)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Gets the restore that was started but didn't finish, if any.
+    pub async fn get_interrupted_restore(&mut self) -> anyhow::Result<Option<InterruptedRestore>> {
+        let restore: Option<String> =
+            sqlx::query("select value from config where key = 'restore_in_progress'")
+                .fetch_optional(&mut self.transaction)
+                .await?
+                .map(|row| row.get(0));
+
+        match restore {
+            Some(restore) => Ok(Some(serde_json::from_str(&restore)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Records that a packfile has been fully received during the restore.
+    pub async fn add_restored_packfile(&mut self, packfile_id: PackfileId) -> anyhow::Result<()> {
+        sqlx::query("insert or ignore into restored_packfiles (packfile_id) values ($1)")
+            .bind(&packfile_id[..])
+            .execute(&mut self.transaction)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Gets all packfiles received during the restore, they may have been deleted since.
+    pub async fn get_restored_packfiles(&mut self) -> anyhow::Result<Vec<PackfileId>> {
+        let rows = sqlx::query("select packfile_id from restored_packfiles")
+            .fetch_all(&mut self.transaction)
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                let packfile_id: &[u8] = row.try_get(0)?;
+                Ok(packfile_id.try_into()?)
+            })
+            .collect()
+    }
+
+    /// Records that a file has been fully written during the restore.
+    pub async fn add_restored_file(&mut self, path: &Path, written_path: &Path) -> anyhow::Result<()> {
+        sqlx::query("insert or replace into restored_files (path, written_path) values ($1, $2)")
+            .bind(path.to_string_lossy())
+            .bind(written_path.to_string_lossy())
+            .execute(&mut self.transaction)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Gets the relative paths of all files written during the restore, along with the paths they
+    /// were written to.
+    pub async fn get_restored_files(&mut self) -> anyhow::Result<HashMap<PathBuf, PathBuf>> {
+        let rows = sqlx::query("select path, written_path from restored_files")
+            .fetch_all(&mut self.transaction)
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                let path = PathBuf::from(row.try_get::<String, _>(0)?);
+                Ok((path, PathBuf::from(row.try_get::<String, _>(1)?)))
+            })
+            .collect()
+    }
+}
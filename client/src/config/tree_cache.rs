@@ -0,0 +1,109 @@
+//! Contains functions for caching directory trees of snapshots, so they don't have to be fetched
+//! from peers again.
+
+use std::collections::HashSet;
+
+use shared::types::BlobHash;
+use sqlx::Row;
+
+use crate::{
+    backup::filesystem::{Tree, TreeKind},
+    config::{Config, Transaction},
+};
+
+impl Config {
+    /// Saves a tree along with the children of all its siblings, by the hash of the first one.
+    pub async fn add_cached_tree(&self, hash: &BlobHash, tree: &Tree) -> anyhow::Result<()> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.add_cached_tree(hash, tree).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Gets a cached tree by the hash of its first sibling.
+    pub async fn get_cached_tree(&self, hash: &BlobHash) -> anyhow::Result<Option<Tree>> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.get_cached_tree(hash).await;
+        transaction.commit().await?;
+
+        result
+    }
+
+    /// Removes the cached trees that can't be reached from any of the given snapshots. Returns the
+    /// number of removed trees.
+    pub async fn evict_cached_trees(&self, snapshots: &[BlobHash]) -> anyhow::Result<usize> {
+        let mut transaction = self.transaction().await?;
+        let result = transaction.evict_cached_trees(snapshots).await;
+        transaction.commit().await?;
+
+        result
+    }
+}
+
+impl Transaction<'_> {
+    /// Saves a tree along with the children of all its siblings, by the hash of the first one.
+    pub async fn add_cached_tree(&mut self, hash: &BlobHash, tree: &Tree) -> anyhow::Result<()> {
+        sqlx::query("insert or replace into tree_cache (hash, tree) values ($1, $2)")
+            .bind(&hash[..])
+            .bind(bincode::serialize(tree)?)
+            .execute(&mut self.transaction)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Gets a cached tree by the hash of its first sibling.
+    pub async fn get_cached_tree(&mut self, hash: &BlobHash) -> anyhow::Result<Option<Tree>> {
+        let tree: Option<Vec<u8>> = sqlx::query("select tree from tree_cache where hash = $1")
+            .bind(&hash[..])
+            .fetch_optional(&mut self.transaction)
+            .await?
+            .map(|row| row.get(0));
+
+        match tree {
+            Some(tree) => Ok(Some(bincode::deserialize(&tree)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the cached trees that can't be reached from any of the given snapshots. Trees are
+    /// only cached while walking down from a snapshot, so walking the cache is enough to find the
+    /// reachable ones.
+    pub async fn evict_cached_trees(&mut self, snapshots: &[BlobHash]) -> anyhow::Result<usize> {
+        let mut reachable = HashSet::new();
+        let mut queue = snapshots.to_vec();
+        while let Some(hash) = queue.pop() {
+            if !reachable.insert(hash) {
+                continue;
+            }
+
+            // the children of file trees are chunks, not trees
+            if let Some(tree) = self.get_cached_tree(&hash).await? {
+                if tree.kind == TreeKind::Dir {
+                    queue.extend(tree.children);
+                }
+            }
+        }
+
+        let rows = sqlx::query("select hash from tree_cache")
+            .fetch_all(&mut self.transaction)
+            .await?;
+
+        let mut evicted = 0;
+        for row in rows {
+            let hash: BlobHash = row.try_get::<&[u8], _>(0)?.try_into()?;
+            if reachable.contains(&hash) {
+                continue;
+            }
+
+            sqlx::query("delete from tree_cache where hash = $1")
+                .bind(&hash[..])
+                .execute(&mut self.transaction)
+                .await?;
+            evicted += 1;
+        }
+
+        Ok(evicted)
+    }
+}
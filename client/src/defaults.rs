@@ -18,18 +18,27 @@ pub const CONFIG_DB_FILE: &str = "config.db";
 /// The name of folder that contains the packfiles.
 pub const PACKFILE_FOLDER: &str = "pack";
 
+/// The name of folder that contains erasure coded shards of packfiles.
+pub const SHARD_FOLDER: &str = "shard";
+
 /// The name of folder that contains the index files.
 pub const INDEX_FOLDER: &str = "index";
 
 /// Folder name for storing packfiles that are generated locally and are waiting to be sent to other peers.
 pub const BACKUP_BUFFER_FOLDER_NAME: &str = "local_packfiles";
 
+/// Folder name for keeping local copies of packfiles containing trees, after they have been sent.
+pub const METADATA_CACHE_FOLDER: &str = "metadata_cache";
+
 /// Folder name for storing packfiles received from other peers.
 pub const RECEIVED_PACKFILES_FOLDER: &str = "received_packfiles";
 
 /// Folder name for storing packfiles that received from other peers in the process of backup restoration.
 pub const RESTORE_BUFFER_FOLDER: &str = "restore_packfiles";
 
+/// Appended to the name of a file while it's being restored.
+pub const RESTORE_PARTIAL_SUFFIX: &str = ".restore-partial";
+
 /// Maximum storage used over the negotiated storage space with other peers, per peer.
 pub const PEER_STORAGE_USAGE_SPREAD: i64 = 16 * 1024 * 1024; // 16 MiB
 
@@ -43,12 +52,78 @@ pub const PACKFILE_SEND_TIMEOUT: u64 = 20;
 /// Maximum amount of seconds to wait until considering packfile ack as failed.
 pub const PACKFILE_ACK_TIMEOUT: u64 = 5;
 
+/// The number of proof of storage challenges computed for every packfile or shard sent to a peer.
+pub const AUDIT_CHALLENGES_PER_FILE: usize = 8;
+
+/// The size of the byte range that a peer has to hash to answer an audit challenge.
+pub const AUDIT_RANGE_SIZE: u64 = 64 * 1024; // 64 KiB
+
+/// How often the peers storing our data are audited, in seconds.
+pub const AUDIT_INTERVAL: u64 = 6 * 60 * 60;
+
+/// Maximum amount of seconds to wait for the answer to an audit after the peer connects.
+pub const AUDIT_RESPONSE_TIMEOUT: u64 = 20;
+
+/// How often the peers storing our data are checked for being lost, in seconds. It's longer than
+/// `AUDIT_INTERVAL`, so peers that are online get audited, and seen, before the first check.
+pub const REPLICATION_CHECK_INTERVAL: u64 = 12 * 60 * 60;
+
+/// A peer storing our data is considered lost if it hasn't been seen for this many seconds.
+pub const PEER_LOST_AFTER: i64 = 7 * 24 * 60 * 60;
+
+/// A peer storing our data is considered lost if it failed this many audits in a row.
+pub const PEER_LOST_FAILED_AUDITS: u32 = 3;
+
+/// The weights of the uptime, transfer success rate, audit pass rate and acknowledgement latency in
+/// the reliability score of a peer, they add up to one.
+pub const RELIABILITY_WEIGHT_UPTIME: f64 = 0.3;
+pub const RELIABILITY_WEIGHT_TRANSFERS: f64 = 0.3;
+pub const RELIABILITY_WEIGHT_AUDITS: f64 = 0.3;
+pub const RELIABILITY_WEIGHT_ACK_LATENCY: f64 = 0.1;
+
+/// The acknowledgement latency that counts as half as good as an immediate acknowledgement, in
+/// milliseconds.
+pub const RELIABILITY_ACK_LATENCY_REFERENCE: u64 = 2000;
+
 /// Minimum number of seconds to wait before retrying to send a storage request.
 pub const STORAGE_REQUEST_RETRY_DELAY: u64 = 10;
 
+/// How long sending the local buffer outside of a backup can go without sending anything before it
+/// fails, in seconds.
+pub const SEND_BUFFER_STALL_TIMEOUT: u64 = 10 * 60;
+
 /// The number of seconds to wait before allowing a certain peer to resend a request to restore.
 pub const RESTORE_THROTTLE_DELAY: u64 = 60;
 
+/// Maximum number of seconds without receiving any file from a peer during a restore, before the
+/// request to the peer is considered failed.
+pub const RESTORE_PEER_TIMEOUT: u64 = 30;
+
+/// The number of times a failed restore request to a peer is retried before giving up on the peer.
+pub const RESTORE_PEER_RETRIES: u32 = 2;
+
+/// The number of seconds to wait before retrying a failed restore request, longer than the delay
+/// peers use to throttle restore requests, so the retry isn't rejected.
+pub const RESTORE_PEER_RETRY_DELAY: u64 = RESTORE_THROTTLE_DELAY + 5;
+
+/// How often the restore progress is sent to the user interface, in seconds.
+pub const RESTORE_PROGRESS_INTERVAL: u64 = 1;
+
+/// The maximum number of files that could not be restored to list in the log.
+pub const RESTORE_REPORT_LOG_LIMIT: usize = 100;
+
+/// The number of packfiles fetched from peers at a time by a deep repository check.
+pub const CHECK_FETCH_BATCH_SIZE: usize = 32;
+
+/// The maximum number of problems found by a repository check to list in the log.
+pub const CHECK_REPORT_LOG_LIMIT: usize = 100;
+
+/// The maximum number of matching paths returned by a search across snapshots.
+pub const SEARCH_RESULT_LIMIT: usize = 1000;
+
+/// The number of chunks and entries queued for writing into an exported archive.
+pub const EXPORT_QUEUE_SIZE: usize = 16;
+
 /// The maximum size of a single storage request at a time.
 pub const STORAGE_REQUEST_CAP: u64 = 150_000_000; // 150 MB
 
@@ -66,3 +141,15 @@ pub const BLOB_MINIMUM_TARGET_SIZE: usize = 256 * 1024; // 256 KiB
 
 /// Desired size of blob data, targeted by chunker.
 pub const BLOB_DESIRED_TARGET_SIZE: usize = 1 * 1024 * 1024; // 1 MiB
+
+/// Packfiles with at least this percentage of unreferenced blobs are repacked when pruning.
+pub const PRUNE_REPACK_GARBAGE_PERCENT: usize = 50;
+
+/// The default number of distinct peers that should store a copy of each packfile.
+pub const DEFAULT_REPLICATION_FACTOR: u32 = 1;
+
+/// The highest replication factor that can be configured.
+pub const MAX_REPLICATION_FACTOR: u32 = 8;
+
+/// The highest total number of data and parity shards a packfile can be split into.
+pub const MAX_ERASURE_CODING_SHARDS: u8 = 16;
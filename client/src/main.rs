@@ -42,6 +42,15 @@ static KEYS: OnceCell<KeyManager> = OnceCell::const_new();
 
 #[tokio::main]
 async fn main() {
+    // commands run instead of the UI and exit when done
+    let command = match ui::cli::parse_args(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{e}\n\n{}", ui::cli::USAGE);
+            process::exit(2);
+        }
+    };
+
     let config = Config::init().await;
     CONFIG.set(config.clone()).unwrap();
 
@@ -63,6 +72,9 @@ async fn main() {
     if config.is_initialized().await.expect("Unable to open config") {
         // initialize the key manager with existing secret
         identity::load_secret().await.expect("Unable to load secret");
+    } else if command.is_some() {
+        eprintln!("The client has to be set up first, please run it without arguments.");
+        process::exit(1);
     } else {
         // first time setup is currently CLI and blocking
         ui::cli::first_run_guide().await;
@@ -75,11 +87,23 @@ async fn main() {
     // initialize P2P connection manager
     P2P_CONN_REQUESTS.set(P2PConnectionManager::new()).unwrap();
 
+    // the server connection is needed to reach the peers storing the snapshot
+    if let Some(command) = command {
+        tokio::spawn(net_server::connect_ws());
+        ui::cli::run_command(command).await;
+    }
+
     // allow to override the default UI bind address
     let ui_bind_addr = env::var("UI_BIND_ADDR").unwrap_or(defaults::UI_BIND_ADDR.to_string());
 
-    // start the UI and the server WebSocket connection
-    let tasks = vec![tokio::spawn(net_server::connect_ws()), tokio::spawn(ui::run(ui_bind_addr))];
+    // start the UI, the server WebSocket connection, the periodic audits of peers and the
+    // replication of data stored by lost peers
+    let tasks = vec![
+        tokio::spawn(net_server::connect_ws()),
+        tokio::spawn(ui::run(ui_bind_addr)),
+        tokio::spawn(net_p2p::audit::run_audits()),
+        tokio::spawn(backup::replicate::run_replication_checks()),
+    ];
 
     future::join_all(tasks).await;
 }
@@ -0,0 +1,323 @@
+//! Implements proof of storage audits, checking that peers still store the data we sent them.
+//!
+//! When a packfile or a shard is sent, a few challenges are computed from its data: a random key
+//! and a random byte range, along with the keyed hash of that range. An audit asks the peer for the
+//! keyed hash of the range without revealing it beforehand, so the peer can only answer correctly if
+//! it still has the data. Every challenge is used only once.
+
+use std::{
+    io::{ErrorKind, SeekFrom},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use futures_util::{SinkExt, StreamExt};
+use shared::{
+    p2p_message::{AuditRequest, AuditResponseBody, EncapsulatedMsg, FileInfo, Header, RequestType},
+    types::{ClientId, PackfileId, TransportSessionNonce},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::{
+    backup::filesystem::file_utils::{get_index_path, get_packfile_path, get_shard_path},
+    config::audits::AuditChallenge,
+    defaults::{AUDIT_CHALLENGES_PER_FILE, AUDIT_INTERVAL, AUDIT_RANGE_SIZE, AUDIT_RESPONSE_TIMEOUT},
+    log,
+    net_p2p::{handle_connections::validate_encapsulated_signature, obfuscate_data_impl},
+    net_server::requests,
+    CONFIG, KEYS, P2P_CONN_REQUESTS,
+};
+
+/// Compute the challenges for auditing a peer that stores the given data, a packfile or a shard.
+pub fn create_challenges(
+    data: &[u8],
+    packfile_id: PackfileId,
+    peer_id: ClientId,
+    shard: Option<u8>,
+) -> anyhow::Result<Vec<AuditChallenge>> {
+    let size = u64::try_from(data.len())?;
+    let length = size.min(AUDIT_RANGE_SIZE);
+
+    let mut challenges = Vec::with_capacity(AUDIT_CHALLENGES_PER_FILE);
+    for _ in 0..AUDIT_CHALLENGES_PER_FILE {
+        let mut key = [0; 32];
+        getrandom::getrandom(&mut key)?;
+
+        let mut offset = [0; 8];
+        getrandom::getrandom(&mut offset)?;
+        let offset = u64::from_le_bytes(offset) % (size - length + 1);
+
+        let range = &data[usize::try_from(offset)?..usize::try_from(offset + length)?];
+        challenges.push(AuditChallenge {
+            packfile_id,
+            peer_id,
+            shard,
+            key,
+            offset,
+            length,
+            expected: blake3::keyed_hash(&key, range).into(),
+        });
+    }
+
+    Ok(challenges)
+}
+
+/// Periodically audit every active peer that stores our data, with a random challenge each time.
+pub async fn run_audits() {
+    loop {
+        sleep(Duration::from_secs(AUDIT_INTERVAL)).await;
+
+        let peers = match CONFIG.get().unwrap().get_active_packfile_peers().await {
+            Ok(peers) => peers,
+            Err(e) => {
+                log!("[audit] cannot get the peers to audit: {}", e);
+                continue;
+            }
+        };
+
+        for peer_id in peers {
+            if let Err(e) = request_audit(peer_id).await {
+                log!("[audit] cannot audit peer {}: {}", hex::encode(peer_id), e);
+            }
+        }
+    }
+}
+
+/// Send an audit request to a peer, if there is a challenge left for it. The answer is checked in
+/// the background once the peer connects. Returns whether the request was sent.
+pub async fn request_audit(peer_id: ClientId) -> anyhow::Result<bool> {
+    let Some(challenge) = CONFIG.get().unwrap().get_random_audit_challenge(peer_id).await? else {
+        return Ok(false);
+    };
+
+    let file = match challenge.shard {
+        Some(index) => FileInfo::PackfileShard(challenge.packfile_id, index),
+        None => FileInfo::Packfile(challenge.packfile_id),
+    };
+    let request = AuditRequest {
+        file,
+        key: challenge.key,
+        offset: challenge.offset,
+        length: challenge.length,
+    };
+
+    let nonce = P2P_CONN_REQUESTS
+        .get()
+        .unwrap()
+        .add_request(peer_id, RequestType::Audit(request))
+        .await?;
+
+    // peers that are offline are not audited, the challenge stays for the next time
+    let online = requests::p2p_connection_begin(peer_id, nonce).await?;
+    CONFIG
+        .get()
+        .unwrap()
+        .add_peer_connection_attempt(peer_id, online)
+        .await?;
+
+    Ok(online)
+}
+
+/// Answers an audit request with the keyed hash of the requested range of a file we store for the
+/// peer, or with no proof if we don't have it.
+pub async fn handle_audit(
+    client_id: ClientId,
+    nonce: TransportSessionNonce,
+    mut stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    request: AuditRequest,
+) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+    let mut folder = config.get_received_packfiles_folder()?;
+    folder.push(hex::encode(client_id));
+    let obfuscation_key = config.get_obfuscation_key().await?.to_le_bytes();
+
+    let proof = match compute_proof(&folder, &request, obfuscation_key).await {
+        Ok(proof) => Some(proof),
+        Err(e) => {
+            log!("[audit] cannot answer an audit of {:?} by {}: {}", request.file, hex::encode(client_id), e);
+            None
+        }
+    };
+
+    // the request message had sequence number 0 from the peer, our answer is the first message from us
+    let body = bincode::serialize(&AuditResponseBody {
+        header: Header { sequence_number: 0, session_nonce: nonce },
+        proof,
+    })?;
+    let signature = KEYS.get().unwrap().sign(&body).to_vec();
+    stream
+        .send(Message::Binary(bincode::serialize(&EncapsulatedMsg { body, signature })?))
+        .await?;
+    stream.close(None).await.ok();
+
+    Ok(())
+}
+
+/// Waits for the peer to answer our already sent audit request, and records whether it passed.
+pub async fn wait_for_proof(
+    peer_id: ClientId,
+    nonce: TransportSessionNonce,
+    mut stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    request: AuditRequest,
+) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+    let challenge = config
+        .take_audit_challenge(peer_id, request.key)
+        .await?
+        .ok_or(anyhow!("audit challenge not found"))?;
+
+    // the peer connected, so it's online even if it fails the audit
+    config.peer_update_last_seen(peer_id).await?;
+
+    let proof = match timeout(Duration::from_secs(AUDIT_RESPONSE_TIMEOUT), stream.next()).await {
+        Ok(Some(Ok(Message::Binary(data)))) => parse_response(&data, nonce, peer_id)?,
+        Ok(Some(Ok(_))) => bail!("received invalid message type from peer"),
+        Ok(Some(Err(e))) => bail!("error while waiting for audit response: {e}"),
+        // a peer that connects but doesn't answer fails the audit
+        Ok(None) | Err(_) => None,
+    };
+    stream.close(None).await.ok();
+
+    let passed = proof == Some(challenge.expected);
+    config
+        .add_audit_result(peer_id, challenge.packfile_id, passed)
+        .await?;
+
+    if passed {
+        log!("[audit] peer {} passed an audit of {:?}", hex::encode(peer_id), request.file);
+    } else {
+        log!(
+            "[audit] peer {} failed an audit of {:?}: {}",
+            hex::encode(peer_id),
+            request.file,
+            if proof.is_some() { "wrong answer" } else { "no answer" }
+        );
+    }
+
+    Ok(())
+}
+
+/// Compute the keyed hash of the requested byte range of a file we store for the peer. Files are
+/// stored obfuscated, the proof is computed over the data as it was sent.
+async fn compute_proof(
+    folder: &Path,
+    request: &AuditRequest,
+    obfuscation_key: [u8; 4],
+) -> anyhow::Result<[u8; 32]> {
+    let path = match request.file {
+        FileInfo::Packfile(id) => get_packfile_path(folder, id, false)?,
+        FileInfo::PackfileShard(id, index) => get_shard_path(folder, id, index, false)?,
+        FileInfo::Index(id) => get_index_path(folder, id),
+    };
+
+    // the range is limited, so a peer can't make us read large amounts of data
+    if request.length > AUDIT_RANGE_SIZE {
+        bail!("requested range of {} B is too large", request.length);
+    }
+
+    let mut file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => bail!("file not found"),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut data = vec![0; usize::try_from(request.length)?];
+    file.seek(SeekFrom::Start(request.offset)).await?;
+    file.read_exact(&mut data).await?;
+
+    // the whole file is obfuscated from its start, so the key has to be lined up with the offset
+    let mut key = obfuscation_key;
+    key.rotate_left(usize::try_from(request.offset % 4)?);
+    obfuscate_data_impl(&mut data, key);
+
+    Ok(blake3::keyed_hash(&request.key, &data).into())
+}
+
+/// Verify and decode the answer to an audit request.
+fn parse_response(
+    data: &[u8],
+    nonce: TransportSessionNonce,
+    peer_id: ClientId,
+) -> anyhow::Result<Option<[u8; 32]>> {
+    let encapsulated: EncapsulatedMsg = bincode::deserialize(data)?;
+    validate_encapsulated_signature(&peer_id, &encapsulated)?;
+
+    let body: AuditResponseBody = bincode::deserialize(&encapsulated.body)?;
+    if body.header.session_nonce != nonce || body.header.sequence_number != 0 {
+        bail!("invalid replay protection header in audit response");
+    }
+
+    Ok(body.proof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net_p2p::{receive::Receiver, received_files_writer::PeerDataReceiver};
+
+    #[test]
+    fn challenges_match_the_data() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let challenges = create_challenges(&data, [1; 12], [2; 32], None).unwrap();
+        assert_eq!(challenges.len(), AUDIT_CHALLENGES_PER_FILE);
+
+        for challenge in &challenges {
+            let start = usize::try_from(challenge.offset).unwrap();
+            let range = &data[start..start + usize::try_from(challenge.length).unwrap()];
+            assert_eq!(challenge.length, AUDIT_RANGE_SIZE);
+            assert_eq!(blake3::keyed_hash(&challenge.key, range), challenge.expected);
+        }
+
+        // files smaller than the range are audited whole
+        let challenges = create_challenges(&data[..100], [1; 12], [2; 32], Some(3)).unwrap();
+        assert!(challenges.iter().all(|c| c.offset == 0 && c.length == 100));
+    }
+
+    #[tokio::test]
+    async fn stored_files_answer_their_challenges() {
+        let config = crate::config::init_test_config().await;
+        let peer_id = [4; 32];
+        config
+            .add_or_increment_peer_storage(peer_id, 1_000_000)
+            .await
+            .unwrap();
+
+        // store the packfile the same way as when receiving it from the peer
+        let packfile_id = [5; 12];
+        let data: Vec<u8> = (0..200_003u32).map(|i| u8::try_from(i % 251).unwrap()).collect();
+        let receiver = PeerDataReceiver::new(peer_id).await.unwrap();
+        receiver.save_packfile(packfile_id, &mut data.clone()).await.unwrap();
+
+        // the random offsets are not necessarily aligned with the obfuscation key
+        let mut challenges = create_challenges(&data, packfile_id, peer_id, None).unwrap();
+        for offset in 1..4 {
+            let mut challenge = challenges[0].clone();
+            challenge.offset = offset;
+            let range =
+                &data[usize::try_from(offset).unwrap()..][..usize::try_from(challenge.length).unwrap()];
+            challenge.expected = blake3::keyed_hash(&challenge.key, range).into();
+            challenges.push(challenge);
+        }
+
+        let mut folder = config.get_received_packfiles_folder().unwrap();
+        folder.push(hex::encode(peer_id));
+        let obfuscation_key = config.get_obfuscation_key().await.unwrap().to_le_bytes();
+        for challenge in challenges {
+            let request = AuditRequest {
+                file: FileInfo::Packfile(packfile_id),
+                key: challenge.key,
+                offset: challenge.offset,
+                length: challenge.length,
+            };
+            let proof = compute_proof(&folder, &request, obfuscation_key).await.unwrap();
+            assert_eq!(proof, challenge.expected);
+        }
+    }
+}
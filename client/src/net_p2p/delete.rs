@@ -0,0 +1,69 @@
+//! Implements the requesting side of deleting files we no longer need from peers.
+
+use std::time::Duration;
+
+use anyhow::bail;
+use futures_util::StreamExt;
+use shared::{
+    p2p_message::{DeleteRequest, RequestType},
+    types::{ClientId, TransportSessionNonce},
+};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::{
+    defaults::PACKFILE_ACK_TIMEOUT, log, net_p2p::transport::BackupTransportManager, net_server::requests,
+    CONFIG, P2P_CONN_REQUESTS,
+};
+
+/// Ask a peer to delete the given files. The request is carried out in the background once the
+/// peer connects, and its result is only logged.
+pub async fn request_delete(peer_id: ClientId, request: DeleteRequest) -> anyhow::Result<()> {
+    log!(
+        "[delete] requesting peer {} to delete {} packfiles and {} index files",
+        hex::encode(peer_id),
+        request.packfiles.len(),
+        request.index_files.len()
+    );
+
+    let nonce = P2P_CONN_REQUESTS
+        .get()
+        .unwrap()
+        .add_request(peer_id, RequestType::Delete(request))
+        .await?;
+    requests::p2p_connection_begin(peer_id, nonce).await?;
+
+    Ok(())
+}
+
+/// Waits for the peer to acknowledge our already sent delete request, then forgets that the peer
+/// stores the deleted packfiles.
+pub async fn wait_for_confirmation(
+    peer_id: ClientId,
+    nonce: TransportSessionNonce,
+    mut stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    request: DeleteRequest,
+) -> anyhow::Result<()> {
+    let data = match timeout(Duration::from_secs(PACKFILE_ACK_TIMEOUT), stream.next()).await? {
+        Some(Ok(Message::Binary(data))) => data,
+        Some(Ok(_)) => bail!("received invalid message type from peer"),
+        Some(Err(e)) => bail!("error while waiting for delete confirmation: {e}"),
+        None => bail!("peer closed connection before confirming delete"),
+    };
+
+    let mut messages_received = 0;
+    if BackupTransportManager::parse_incoming_ack(&data, nonce, peer_id, &mut messages_received)? != 0 {
+        bail!("peer acknowledged an unexpected message");
+    }
+
+    log!("[delete] peer {} confirmed deleting files", hex::encode(peer_id));
+    stream.close(None).await.ok();
+
+    let config = CONFIG.get().unwrap();
+    let freed = config.remove_packfile_locations(peer_id, &request.packfiles).await?;
+    config.peer_decrement_transmitted(peer_id, freed).await?;
+    config.remove_audit_challenges(peer_id, &request.packfiles).await?;
+    config.remove_index_locations(peer_id, &request.index_files).await?;
+
+    Ok(())
+}
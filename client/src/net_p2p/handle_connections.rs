@@ -20,7 +20,9 @@ use tokio_tungstenite::{
 use crate::{
     backup::{restore_send, send},
     log,
-    net_p2p::{get_listener_address, get_ws_config, received_files_writer, restore_files_writer},
+    net_p2p::{
+        audit, delete, get_listener_address, get_ws_config, received_files_writer, restore_files_writer,
+    },
     net_server::requests::p2p_connection_confirm,
     CONFIG, KEYS, P2P_CONN_REQUESTS,
 };
@@ -83,6 +85,31 @@ pub async fn accept_and_listen(incoming_req: IncomingP2PConnection) -> anyhow::R
             )
             .await?;
         }
+        // initiating peer wants to restore some packfiles from us
+        RequestType::RestoreSelected(request) => {
+            restore_send::restore_selected_data_to_peer(
+                incoming_req.source_client_id,
+                incoming_req.session_nonce,
+                stream,
+                request,
+            )
+            .await?;
+        }
+        // initiating peer wants us to delete some of their files
+        RequestType::Delete(request) => {
+            received_files_writer::handle_delete(
+                incoming_req.source_client_id,
+                incoming_req.session_nonce,
+                stream,
+                request,
+            )
+            .await?;
+        }
+        // initiating peer wants us to prove that we still store their data
+        RequestType::Audit(request) => {
+            audit::handle_audit(incoming_req.source_client_id, incoming_req.session_nonce, stream, request)
+                .await?;
+        }
         _ => bail!("request type not implemented"),
     }
 
@@ -113,7 +140,7 @@ pub async fn accept_and_connect(finalize_req: FinalizeP2PConnection) -> anyhow::
             sequence_number: 0,
             session_nonce: request.session_nonce,
         },
-        request: request.purpose,
+        request: request.purpose.clone(),
     })?;
 
     // sign the body
@@ -127,7 +154,7 @@ pub async fn accept_and_connect(finalize_req: FinalizeP2PConnection) -> anyhow::
             send::connection_established(finalize_req.destination_client_id, request.session_nonce, stream)
                 .await?;
         }
-        RequestType::RestoreAll => {
+        RequestType::RestoreAll | RequestType::RestoreSelected(_) => {
             restore_files_writer::handle_receiving(
                 finalize_req.destination_client_id,
                 request.session_nonce,
@@ -135,6 +162,24 @@ pub async fn accept_and_connect(finalize_req: FinalizeP2PConnection) -> anyhow::
             )
             .await?;
         }
+        RequestType::Delete(delete_request) => {
+            delete::wait_for_confirmation(
+                finalize_req.destination_client_id,
+                request.session_nonce,
+                stream,
+                delete_request,
+            )
+            .await?;
+        }
+        RequestType::Audit(audit_request) => {
+            audit::wait_for_proof(
+                finalize_req.destination_client_id,
+                request.session_nonce,
+                stream,
+                audit_request,
+            )
+            .await?;
+        }
         _ => bail!("request type not implemented"),
     }
 
@@ -191,7 +236,7 @@ async fn receive_request(
 }
 
 /// Validates the signature on an encapsulated message.
-fn validate_encapsulated_signature(
+pub fn validate_encapsulated_signature(
     source_pubkey: &[u8],
     encapsulated: &EncapsulatedMsg,
 ) -> anyhow::Result<()> {
@@ -5,6 +5,8 @@ use portpicker::pick_unused_port;
 use shared::p2p_message::MAX_ENCAPSULATED_BACKUP_CHUNK_SIZE;
 use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 
+pub mod audit;
+pub mod delete;
 pub mod handle_connections;
 pub mod p2p_connection_manager;
 pub mod receive;
@@ -20,10 +20,12 @@ pub trait Receiver {
     async fn save_index(&self, id: u32, data: &mut [u8]) -> anyhow::Result<()>;
     /// Save a packfile to disk.
     async fn save_packfile(&self, id: PackfileId, data: &mut [u8]) -> anyhow::Result<()>;
+    /// Save an erasure coded shard of a packfile to disk.
+    async fn save_shard(&self, id: PackfileId, index: u8, data: &mut [u8]) -> anyhow::Result<()>;
 }
 
 /// Generate an ack message for the given sequence number.
-fn ack_msg(nonce: TransportSessionNonce, seq: &mut u64, acknowledged: u64) -> anyhow::Result<Message> {
+pub fn ack_msg(nonce: TransportSessionNonce, seq: &mut u64, acknowledged: u64) -> anyhow::Result<Message> {
     let body = bincode::serialize(&AckBody {
         header: Header { sequence_number: *seq, session_nonce: nonce },
         acknowledged_sequence_number: acknowledged,
@@ -54,11 +56,12 @@ pub async fn handle_stream(
                 let (msg_num, file_info, mut data) =
                     validate_incoming_message(session_nonce, &source_pubkey, &mut data_msg_counter, &msg)?;
 
-                println!("[p2p] received file {file_info:?}");
+                log!("[p2p] received file {:?}", file_info);
 
                 match file_info {
                     FileInfo::Packfile(id) => receiver.save_packfile(id, &mut data).await?,
                     FileInfo::Index(id) => receiver.save_index(id, &mut data).await?,
+                    FileInfo::PackfileShard(id, index) => receiver.save_shard(id, index, &mut data).await?,
                 }
 
                 stream
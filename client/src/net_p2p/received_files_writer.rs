@@ -1,16 +1,21 @@
 //! Write received peer backup files to disk.
 
-use std::{fs, path::PathBuf};
+use std::{fs, io::ErrorKind, path::PathBuf};
 
 use anyhow::bail;
-use shared::types::{ClientId, PackfileId, TransportSessionNonce};
+use futures_util::SinkExt;
+use shared::{
+    p2p_message::DeleteRequest,
+    types::{ClientId, PackfileId, TransportSessionNonce},
+};
 use tokio::net::TcpStream;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
 use crate::{
-    backup::filesystem::file_utils::{get_index_path, get_packfile_path},
-    config::peers::PeerInfo,
-    defaults::{INDEX_FOLDER, PACKFILE_FOLDER, PEER_STORAGE_USAGE_SPREAD},
+    backup::filesystem::file_utils::{find_shards, get_index_path, get_packfile_path, get_shard_path},
+    config::peers::{PeerInfo, PeerState},
+    defaults::{INDEX_FOLDER, PACKFILE_FOLDER, PEER_STORAGE_USAGE_SPREAD, SHARD_FOLDER},
+    log,
     net_p2p::{obfuscate_data_impl, receive, receive::Receiver},
     CONFIG,
 };
@@ -25,6 +30,18 @@ pub struct PeerDataReceiver {
 impl Receiver for PeerDataReceiver {
     async fn save_index(&self, id: u32, data: &mut [u8]) -> anyhow::Result<()> {
         let path = get_index_path(&self.file_path, id);
+
+        // peers upgrading from versions that didn't track index delivery per peer send their index
+        // files again, so replace the existing ones instead of failing
+        if let Ok(metadata) = fs::metadata(&path) {
+            fs::remove_file(&path)?;
+            CONFIG
+                .get()
+                .unwrap()
+                .peer_decrement_received(self.peer_id, metadata.len())
+                .await?;
+        }
+
         self.save_file(path, data).await
     }
 
@@ -32,6 +49,11 @@ impl Receiver for PeerDataReceiver {
         let path = get_packfile_path(&self.file_path, id, true)?;
         self.save_file(path, data).await
     }
+
+    async fn save_shard(&self, id: PackfileId, index: u8, data: &mut [u8]) -> anyhow::Result<()> {
+        let path = get_shard_path(&self.file_path, id, index, true)?;
+        self.save_file(path, data).await
+    }
 }
 
 impl PeerDataReceiver {
@@ -45,6 +67,7 @@ impl PeerDataReceiver {
         fs::create_dir_all(&file_path)?;
         fs::create_dir_all(file_path.join(INDEX_FOLDER))?;
         fs::create_dir_all(file_path.join(PACKFILE_FOLDER))?;
+        fs::create_dir_all(file_path.join(SHARD_FOLDER))?;
 
         Ok(Self { file_path, peer_id, obfuscation_key })
     }
@@ -84,7 +107,13 @@ pub async fn handle_receiving(
     nonce: TransportSessionNonce,
     stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
 ) -> anyhow::Result<()> {
-    let peer = CONFIG.get().unwrap().get_peer_info(client_id).await?;
+    let config = CONFIG.get().unwrap();
+    let state = config.get_peer_state(client_id).await?.map(|info| info.state);
+    if state == Some(PeerState::Blocked) {
+        bail!("ignoring a request from a blocked peer {}", hex::encode(client_id));
+    }
+
+    let peer = config.get_peer_info(client_id).await?;
 
     match peer {
         Some(peer) if is_peer_allowed_to_send_data(&peer) => {
@@ -97,6 +126,50 @@ pub async fn handle_receiving(
     }
 }
 
+/// Deletes files that a peer no longer needs, crediting the freed space back to the peer, and
+/// acknowledges the request once done. Files that we don't have are skipped.
+pub async fn handle_delete(
+    client_id: ClientId,
+    nonce: TransportSessionNonce,
+    mut stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    request: DeleteRequest,
+) -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+    let mut file_path = config.get_received_packfiles_folder()?;
+    file_path.push(hex::encode(client_id));
+
+    let mut paths = Vec::new();
+    for id in &request.packfiles {
+        paths.push(get_packfile_path(&file_path, *id, false)?);
+        paths.extend(find_shards(&file_path, *id)?.into_iter().map(|(_, path)| path));
+    }
+    paths.extend(request.index_files.iter().map(|id| get_index_path(&file_path, *id)));
+
+    let mut bytes_freed = 0;
+    let mut files_deleted = 0;
+    for path in paths {
+        match fs::metadata(&path) {
+            Ok(metadata) => {
+                fs::remove_file(&path)?;
+                bytes_freed += metadata.len();
+                files_deleted += 1;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => bail!("error deleting file {path:?}: {e}"),
+        }
+    }
+
+    config.peer_decrement_received(client_id, bytes_freed).await?;
+    log!("[p2p] deleted {} files ({} bytes) of peer {}", files_deleted, bytes_freed, hex::encode(client_id));
+
+    // the request message had sequence number 0, acknowledge it so the peer knows we're done
+    let mut ack_counter = 0;
+    stream.send(receive::ack_msg(nonce, &mut ack_counter, 0)?).await?;
+    stream.close(None).await.ok();
+
+    Ok(())
+}
+
 /// Returns whether a certain peer is allowed to store more backup data.
 pub fn is_peer_allowed_to_send_data(peer: &PeerInfo) -> bool {
     // allow a buffer of a pre-defined constant for usage over negotiate storage
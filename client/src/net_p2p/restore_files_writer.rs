@@ -3,12 +3,13 @@
 use std::{fs, path::PathBuf};
 
 use shared::types::{ClientId, PackfileId, TransportSessionNonce};
-use tokio::net::TcpStream;
+use tokio::{net::TcpStream, sync::Mutex};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
 use crate::{
     backup::{
-        filesystem::file_utils::{get_index_path, get_packfile_path},
+        erasure,
+        filesystem::file_utils::{get_index_path, get_packfile_path, get_shard_path},
         RESTORE_ORCHESTRATOR,
     },
     defaults::{INDEX_FOLDER, PACKFILE_FOLDER},
@@ -16,6 +17,9 @@ use crate::{
     CONFIG,
 };
 
+/// Serializes saving shards and reconstructing packfiles from them.
+static SHARD_LOCK: Mutex<()> = Mutex::const_new(());
+
 pub struct RestoreReceiver {
     file_path: PathBuf,
     peer_id: ClientId,
@@ -24,13 +28,60 @@ pub struct RestoreReceiver {
 #[async_trait::async_trait]
 impl Receiver for RestoreReceiver {
     async fn save_index(&self, id: u32, data: &mut [u8]) -> anyhow::Result<()> {
+        RESTORE_ORCHESTRATOR.get().unwrap().peer_activity(self.peer_id).await;
         let path = get_index_path(&self.file_path, id);
         Self::save_file(path, data)
     }
 
     async fn save_packfile(&self, id: PackfileId, data: &mut [u8]) -> anyhow::Result<()> {
+        let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+        orchestrator.peer_received(self.peer_id, data.len() as u64).await;
+
+        // the packfile may be stored by multiple peers, it may even be unpacked and deleted already,
+        // it must not be overwritten while the unpacker reads it
+        if !orchestrator.claim_packfile(id).await {
+            return Ok(());
+        }
+
         let path = get_packfile_path(&self.file_path, id, true)?;
-        Self::save_file(path, data)
+        if let Err(e) = Self::save_file(path, data) {
+            orchestrator.release_packfile(id).await;
+            return Err(e);
+        }
+
+        orchestrator.packfile_saved(id).await
+    }
+
+    async fn save_shard(&self, id: PackfileId, index: u8, data: &mut [u8]) -> anyhow::Result<()> {
+        let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+        orchestrator.peer_received(self.peer_id, data.len() as u64).await;
+
+        // shards of a packfile arrive from different peers, only one of them can reconstruct it
+        let _lock = SHARD_LOCK.lock().await;
+
+        // the packfile could have already been received or reconstructed from other shards
+        if orchestrator.is_packfile_received(id).await {
+            return Ok(());
+        }
+
+        let path = get_shard_path(&self.file_path, id, index, true)?;
+        Self::save_file(path, data)?;
+
+        if !orchestrator.claim_packfile(id).await {
+            return Ok(());
+        }
+
+        match erasure::try_reconstruct(&self.file_path, id) {
+            Ok(true) => orchestrator.packfile_saved(id).await,
+            Ok(false) => {
+                orchestrator.release_packfile(id).await;
+                Ok(())
+            }
+            Err(e) => {
+                orchestrator.release_packfile(id).await;
+                Err(e)
+            }
+        }
     }
 }
 
@@ -61,14 +112,17 @@ pub async fn handle_receiving(
 ) -> anyhow::Result<()> {
     // the peer that sends us data is not going to be known if we are restoring from clean state
     let receiver = RestoreReceiver::new(client_id)?;
+    let orchestrator = RESTORE_ORCHESTRATOR.get().unwrap();
+    orchestrator.peer_activity(client_id).await;
 
+    // a failing peer doesn't stop the restore, the request is retried or the peer is skipped
     match receive::handle_stream(stream, nonce, client_id, receiver).await {
         Ok(_) => {
-            RESTORE_ORCHESTRATOR.get().unwrap().complete_peer(client_id).await;
+            orchestrator.complete_peer(client_id).await;
             Ok(())
         }
         Err(e) => {
-            RESTORE_ORCHESTRATOR.get().unwrap().set_finished(false, e.to_string());
+            orchestrator.fail_peer(client_id, e.to_string()).await;
             Err(e)
         }
     }
@@ -1,7 +1,7 @@
 //! Implements the sending part of the P2P transport protocol, including replay protection and
 //! acknowledgements.
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::bail;
 use ed25519_dalek::{PublicKey, Signature};
@@ -107,8 +107,9 @@ impl BackupTransportManager {
         }
     }
 
-    /// Sends a file and waits for an acknowledgement, or times out.
-    pub async fn send_data(&mut self, data: Vec<u8>, file_info: FileInfo) -> anyhow::Result<()> {
+    /// Sends a file and waits for an acknowledgement, or times out. Returns the time between sending
+    /// the file and receiving the acknowledgement.
+    pub async fn send_data(&mut self, data: Vec<u8>, file_info: FileInfo) -> anyhow::Result<Duration> {
         let body = EncapsulatedFileBody {
             header: Header {
                 sequence_number: self.msg_counter,
@@ -125,10 +126,11 @@ impl BackupTransportManager {
         let msg = bincode::serialize(&encapsulated)?;
 
         timeout(Duration::from_secs(PACKFILE_SEND_TIMEOUT), self.tx.send(Message::Binary(msg))).await??;
+        let sent = Instant::now();
         timeout(Duration::from_secs(PACKFILE_ACK_TIMEOUT), self.wait_for_ack(self.msg_counter)).await??;
 
         self.msg_counter += 1;
-        Ok(())
+        Ok(sent.elapsed())
     }
 
     /// Waits for an acknowledgement for a specific sequence number.
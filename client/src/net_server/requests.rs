@@ -7,9 +7,12 @@ use shared::{
     client_message::{
         BackupDone, BackupRequest, BackupRestoreRequest, BeginP2PConnectionRequest, ClientLoginAuth,
         ClientLoginRequest, ClientRegistrationAuth, ClientRegistrationRequest, ConfirmP2PConnectionRequest,
+        SnapshotForgetRequest, SnapshotListRequest,
     },
-    server_message::{BackupRestoreInfo, ClientLoginToken, ErrorType, ServerMessage},
-    types::{BlobHash, ChallengeNonce, ClientId, SessionToken, TransportSessionNonce},
+    server_message::{
+        BackupRestoreInfo, ClientLoginToken, ErrorType, ServerMessage, SnapshotInfo, SnapshotsForgotten,
+    },
+    types::{BlobHash, ChallengeNonce, ClientId, SessionToken, SnapshotId, TransportSessionNonce},
 };
 
 use crate::{config::Config, identity, key_manager::Signature, CONFIG};
@@ -208,6 +211,58 @@ pub async fn backup_restore() -> anyhow::Result<BackupRestoreInfo> {
     Ok(info)
 }
 
+/// Request the list of all our snapshots stored on the server, newest first.
+pub async fn snapshot_list() -> anyhow::Result<Vec<SnapshotInfo>> {
+    let snapshots = retry_with_login(|token| async move {
+        let client = reqwest::Client::new();
+        let response = client
+            .post(url("backups/snapshots"))
+            .json(&SnapshotListRequest { session_token: token })
+            .send()
+            .await?;
+
+        match response.json().await? {
+            ServerMessage::SnapshotList(list) => Ok(list.snapshots),
+            ServerMessage::Error(ErrorType::Unauthorized) => Err(ResponseError::Unauthorized),
+            ServerMessage::Error(e) => Err(ResponseError::Other(anyhow!("request failed: {e:?}"))),
+            _ => Err(ResponseError::Other(anyhow!("unexpected response"))),
+        }
+    })
+    .await?;
+
+    Ok(snapshots)
+}
+
+/// Ask the server to remove snapshot records, returning the records that were (or would be) removed.
+pub async fn snapshot_forget(
+    snapshots: Vec<SnapshotId>,
+    dry_run: bool,
+) -> anyhow::Result<SnapshotsForgotten> {
+    let snapshots = &snapshots;
+    let forgotten = retry_with_login(|token| async move {
+        let client = reqwest::Client::new();
+        let response = client
+            .post(url("backups/forget"))
+            .json(&SnapshotForgetRequest {
+                session_token: token,
+                snapshots: snapshots.clone(),
+                dry_run,
+            })
+            .send()
+            .await?;
+
+        match response.json().await? {
+            ServerMessage::SnapshotsForgotten(forgotten) => Ok(forgotten),
+            ServerMessage::Error(ErrorType::Unauthorized) => Err(ResponseError::Unauthorized),
+            ServerMessage::Error(e) => Err(ResponseError::Other(anyhow!("request failed: {e:?}"))),
+            _ => Err(ResponseError::Other(anyhow!("unexpected response"))),
+        }
+    })
+    .await?;
+
+    Ok(forgotten)
+}
+
 /// Retry a function that needs a session token, logging in if necessary.
 async fn retry_with_login<T, F>(func: impl Fn(SessionToken) -> F) -> anyhow::Result<T>
 where
@@ -1,10 +1,47 @@
-//! Implements the CLI for initial setup and restore.
+//! Implements the CLI for initial setup, restore and commands working with snapshots.
 
+use std::{path::PathBuf, process};
+
+use anyhow::{anyhow, bail};
 use bip39::Mnemonic;
+use cast::From;
 use dialoguer::{theme::ColorfulTheme, Input, Password, Select};
+use human_bytes::human_bytes;
 use owo_colors::OwoColorize;
-
-use crate::{identity, key_manager::RootSecret, KEYS};
+use shared::types::BlobHash;
+
+use crate::{
+    backup::{
+        browse, check,
+        check::CheckReport,
+        diff::{DiffChange, DiffEntry},
+        export::{export_snapshot, ExportOptions},
+        filesystem::TreeKind,
+    },
+    identity,
+    key_manager::RootSecret,
+    KEYS,
+};
+
+/// Describes the commands and their arguments.
+pub const USAGE: &str = "Usage:
+  client
+    Runs the client with the web user interface.
+  client export [--format tar|zip] [--snapshot HASH] [--path PATH] [--output FILE]
+    Writes the latest snapshot, or the given one, as an archive to the file or to the standard output.
+    The path selects a file or directory inside the snapshot, relative to the backup path.
+  client diff OLD_SNAPSHOT NEW_SNAPSHOT
+    Lists the files and directories added, removed or modified between two snapshots.
+  client check [--deep]
+    Checks that all data referenced by snapshots is stored with peers, the deep check also fetches
+    and verifies the contents of all files. Exits with an error if any problems are found.";
+
+/// A command that runs instead of the user interface and exits when done.
+pub enum Command {
+    Export(ExportOptions),
+    Diff { old_snapshot: BlobHash, new_snapshot: BlobHash },
+    Check { deep: bool },
+}
 
 /// Handle the first start guide in a CLI, which allows the user to either start fresh or restore from a mnemonic.
 pub async fn first_run_guide() {
@@ -79,3 +116,132 @@ async fn fresh_setup_guide() {
 pub fn print_server_url(url: impl Into<String>) {
     println!("{}: http://{}", "UI is running at this address".bold().green(), url.into());
 }
+
+/// Parse the command line arguments, excluding the program name. Returns `None` if there is no
+/// command and the user interface should be started.
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Option<Command>> {
+    let command = match args.next() {
+        Some(command) => command,
+        None => return Ok(None),
+    };
+
+    match command.as_str() {
+        "export" => Ok(Some(Command::Export(parse_export_args(args)?))),
+        "diff" => {
+            let mut snapshot = || -> anyhow::Result<BlobHash> {
+                browse::parse_snapshot_hash(&args.next().ok_or(anyhow!("missing snapshot hash"))?)
+            };
+            let (old_snapshot, new_snapshot) = (snapshot()?, snapshot()?);
+            if let Some(arg) = args.next() {
+                bail!("unknown argument {arg}");
+            }
+
+            Ok(Some(Command::Diff { old_snapshot, new_snapshot }))
+        }
+        "check" => {
+            let deep = match args.next().as_deref() {
+                None => false,
+                Some("--deep") => true,
+                Some(arg) => bail!("unknown argument {arg}"),
+            };
+            if let Some(arg) = args.next() {
+                bail!("unknown argument {arg}");
+            }
+
+            Ok(Some(Command::Check { deep }))
+        }
+        _ => bail!("unknown command {command}"),
+    }
+}
+
+/// Parse the arguments of the export command, following the command name.
+fn parse_export_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<ExportOptions> {
+    let mut options = ExportOptions::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(anyhow!("missing value for {arg}"));
+        match arg.as_str() {
+            "--format" => options.format = value()?.parse()?,
+            "--snapshot" => options.snapshot = Some(browse::parse_snapshot_hash(&value()?)?),
+            "--path" => options.path = Some(PathBuf::from(value()?)),
+            "--output" | "-o" => {
+                let output = value()?;
+                options.output = if output == "-" { None } else { Some(PathBuf::from(output)) };
+            }
+            _ => bail!("unknown argument {arg}"),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Run a command and exit, the progress is logged to the standard error output.
+pub async fn run_command(command: Command) -> ! {
+    let result = match command {
+        Command::Export(options) => export_snapshot(options).await,
+        Command::Diff { old_snapshot, new_snapshot } => browse::diff_snapshots(old_snapshot, new_snapshot)
+            .await
+            .map(|entries| print_diff(&entries)),
+        Command::Check { deep } => check::run(deep).await.and_then(|report| print_check_report(&report)),
+    };
+
+    match result {
+        Ok(()) => process::exit(0),
+        Err(e) => {
+            eprintln!("{}: {e}", "Command failed".bold().red());
+            process::exit(1);
+        }
+    }
+}
+
+/// Print the changes between two snapshots, one per line, followed by a summary.
+fn print_diff(entries: &[DiffEntry]) {
+    let mut total_delta = 0;
+    for entry in entries {
+        let mark = match entry.change {
+            DiffChange::Added => "+",
+            DiffChange::Removed => "-",
+            DiffChange::Modified => "M",
+        };
+
+        total_delta += entry.size_delta();
+        if entry.kind == TreeKind::Dir {
+            println!("{mark} {}/", entry.path.display());
+        } else {
+            println!("{mark} {} ({})", entry.path.display(), format_delta(entry.size_delta()));
+        }
+    }
+
+    let count = |change| entries.iter().filter(|e| e.change == change).count();
+    println!(
+        "{} added, {} removed, {} modified, {} in total",
+        count(DiffChange::Added),
+        count(DiffChange::Removed),
+        count(DiffChange::Modified),
+        format_delta(total_delta)
+    );
+}
+
+/// Print all problems found by a repository check with the affected files, followed by a summary.
+/// Returns an error if there were any problems.
+fn print_check_report(report: &CheckReport) -> anyhow::Result<()> {
+    for problem in &report.problems {
+        println!("{:?} {} is {}", problem.kind, hex::encode(problem.hash), problem.problem);
+        for affected in &problem.affected {
+            println!("  {} in snapshot {}", affected.path.display(), hex::encode(affected.snapshot));
+        }
+    }
+
+    println!("{}", report.summary());
+    if !report.problems.is_empty() {
+        bail!("the repository has {} damaged or missing blobs", report.problems.len());
+    }
+
+    Ok(())
+}
+
+/// Format a size difference in bytes with a sign.
+fn format_delta(delta: i128) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{sign}{}", human_bytes(f64::cast(delta.unsigned_abs())))
+}
@@ -0,0 +1,53 @@
+//! HTTP endpoint for downloading single files from snapshots.
+
+use std::{io, path::PathBuf};
+
+use futures_util::stream;
+use poem::{
+    http::{header, StatusCode},
+    web::Query,
+    Body, Error, Response,
+};
+use serde::Deserialize;
+
+use crate::backup::browse;
+
+#[derive(Deserialize)]
+pub struct DownloadParams {
+    snapshot: String,
+    /// The path of the file inside the snapshot, relative to the backup path.
+    path: PathBuf,
+}
+
+/// Send the contents of a file from a snapshot, fetching it from peers first if needed.
+#[poem::handler]
+pub async fn handler(Query(params): Query<DownloadParams>) -> poem::Result<Response> {
+    let snapshot_hash = browse::parse_snapshot_hash(&params.snapshot)
+        .map_err(|e| Error::from_string(e.to_string(), StatusCode::BAD_REQUEST))?;
+    let (tree, packer, guard) = browse::open_file(snapshot_hash, &params.path)
+        .await
+        .map_err(|e| Error::from_string(e.to_string(), StatusCode::NOT_FOUND))?;
+
+    // the chunks are decrypted one at a time while sending, so the file is never fully in memory,
+    // the fetch guard is held until the stream ends so the packfiles aren't deleted in the meantime
+    let state = (packer, tree.children.into_iter(), guard);
+    let chunks = stream::unfold(state, |(mut packer, mut chunks, guard)| async move {
+        let hash = chunks.next()?;
+        let chunk = match packer.get_blob(&hash).await {
+            Ok(Some(blob)) => Ok(blob.data),
+            Ok(None) => Err(io::Error::new(io::ErrorKind::NotFound, "chunk not found")),
+            Err(e) => Err(io::Error::other(e)),
+        };
+        Some((chunk, (packer, chunks, guard)))
+    });
+
+    let mut response = Response::builder().content_type("application/octet-stream").header(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", tree.name.replace('"', "")),
+    );
+    if let Some(size) = tree.metadata.size {
+        response = response.header(header::CONTENT_LENGTH, size);
+    }
+
+    Ok(response.body(Body::from_bytes_stream(chunks)))
+}
@@ -1,14 +1,15 @@
 //! Contains the backend for the UI.
 
 pub mod cli;
+mod download;
 pub mod ws;
 mod ws_dispatcher;
 pub mod ws_status_message;
 
-use poem::{endpoint::EmbeddedFilesEndpoint, listener::TcpListener, Route, Server};
+use poem::{endpoint::EmbeddedFilesEndpoint, get, listener::TcpListener, Route, Server};
 use rust_embed::RustEmbed;
 
-/// Serve the static files and register the WebSocket endpoint.
+/// Serve the static files and register the WebSocket and download endpoints.
 pub async fn run(bind_addr: String) {
     #[derive(RustEmbed)]
     #[folder = "static"]
@@ -16,7 +17,8 @@ pub async fn run(bind_addr: String) {
 
     let app = Route::new()
         .nest("/", EmbeddedFilesEndpoint::<Static>::new())
-        .at("/ws", ws::handler);
+        .at("/ws", ws::handler)
+        .at("/download", get(download::handler));
 
     let listener = TcpListener::bind(&bind_addr);
     let server = Server::new(listener);
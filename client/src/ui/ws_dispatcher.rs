@@ -1,6 +1,6 @@
 //! Dispatches incoming messages from the clients.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::bail;
 use futures_util::{stream::SplitStream, StreamExt};
@@ -8,8 +8,21 @@ use poem::web::websocket::{Message, WebSocketStream};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    backup::{request_restore, run},
-    ui::ws_status_message::Messenger,
+    backup::{
+        browse, check,
+        erasure::ErasureCoding,
+        prune, replicate,
+        restore::{request_restore, resume_restore, RestoreOptions},
+        retention,
+        retention::RetentionPolicy,
+        run,
+        send::Redundancy,
+    },
+    config::peers::PeerState,
+    defaults::MAX_REPLICATION_FACTOR,
+    log,
+    net_server::requests,
+    ui::ws_status_message::{Messenger, Peer, SearchResults, Snapshot, SnapshotDiff, SnapshotDirectory},
     CONFIG, KEYS, UI,
 };
 
@@ -19,13 +32,57 @@ pub enum ClientMessage {
     Config(Config),
     StartBackup,
     GetConfig,
-    StartRestore,
+    StartRestore(RestoreOptions),
+    ResumeRestore,
+    ForgetSnapshots {
+        dry_run: bool,
+    },
+    Prune {
+        dry_run: bool,
+    },
+    /// Check that the data of all snapshots is available, and with `deep` also that it's intact.
+    CheckRepository {
+        deep: bool,
+    },
+    ListSnapshots,
+    /// List a directory inside a snapshot, the path is relative to the backup path.
+    BrowseSnapshot {
+        snapshot: String,
+        path: PathBuf,
+    },
+    DiffSnapshots {
+        old_snapshot: String,
+        new_snapshot: String,
+    },
+    /// Find files and directories by name or glob pattern in all snapshots.
+    SearchSnapshots {
+        pattern: String,
+    },
+    ListPeers,
+    /// Stop storing our data on a peer and storing its data, the data it already stores is kept.
+    BlockPeer {
+        peer: String,
+    },
+    UnblockPeer {
+        peer: String,
+    },
+    /// Replicate the data stored by a peer to other peers, and then ask it to delete it.
+    EvacuatePeer {
+        peer: String,
+    },
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Config {
     pub client_id: String,
     pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    #[serde(default)]
+    pub replication_factor: Option<u32>,
+    /// Zero shards disable erasure coding.
+    #[serde(default)]
+    pub erasure_coding: Option<ErasureCoding>,
 }
 
 /// Dispatches messages from the WebSocket clients (the web UI) to the appropriate handlers.
@@ -58,7 +115,31 @@ async fn process_message(msg: &serde_json::Result<ClientMessage>) -> anyhow::Res
         Ok(ClientMessage::Config(conf)) => set_config(conf).await?,
         Ok(ClientMessage::StartBackup) => run().await?,
         Ok(ClientMessage::GetConfig) => send_config_message().await?,
-        Ok(ClientMessage::StartRestore) => request_restore().await?,
+        Ok(ClientMessage::StartRestore(options)) => request_restore(options.clone()).await?,
+        Ok(ClientMessage::ResumeRestore) => resume_restore().await?,
+        Ok(ClientMessage::ForgetSnapshots { dry_run }) => {
+            retention::forget(*dry_run).await?;
+        }
+        Ok(ClientMessage::Prune { dry_run }) => {
+            prune::run(*dry_run).await?;
+        }
+        Ok(ClientMessage::CheckRepository { deep }) => {
+            check::run(*deep).await?;
+        }
+        Ok(ClientMessage::ListSnapshots) => send_snapshot_list().await?,
+        Ok(ClientMessage::BrowseSnapshot { snapshot, path }) => {
+            send_snapshot_directory(snapshot, path).await?;
+        }
+        Ok(ClientMessage::DiffSnapshots { old_snapshot, new_snapshot }) => {
+            send_snapshot_diff(old_snapshot, new_snapshot).await?;
+        }
+        Ok(ClientMessage::SearchSnapshots { pattern }) => send_search_results(pattern).await?,
+        Ok(ClientMessage::ListPeers) => send_peer_list().await?,
+        Ok(ClientMessage::BlockPeer { peer }) => {
+            set_peer_state(peer, PeerState::Blocked, "blocked by the user").await?;
+        }
+        Ok(ClientMessage::UnblockPeer { peer }) => unblock_peer(peer).await?,
+        Ok(ClientMessage::EvacuatePeer { peer }) => evacuate_peer(peer).await?,
         Err(e) => bail!("invalid message from client: {e:?}"),
     }
 
@@ -73,6 +154,26 @@ async fn set_config(conf: &Config) -> anyhow::Result<()> {
         config.set_backup_path(path.clone()).await?;
     }
 
+    if let Some(policy) = conf.retention {
+        config.set_retention_policy(policy).await?;
+    }
+
+    if let Some(factor) = conf.replication_factor {
+        if !(1..=MAX_REPLICATION_FACTOR).contains(&factor) {
+            bail!("replication factor must be between 1 and {MAX_REPLICATION_FACTOR}");
+        }
+        config.set_replication_factor(factor).await?;
+    }
+
+    if let Some(coding) = conf.erasure_coding {
+        if coding == ErasureCoding::default() {
+            config.set_erasure_coding(None).await?;
+        } else {
+            coding.validate()?;
+            config.set_erasure_coding(Some(coding)).await?;
+        }
+    }
+
     Ok(())
 }
 
@@ -81,11 +182,150 @@ async fn send_config_message() -> anyhow::Result<()> {
     let config = CONFIG.get().unwrap();
     let client_id = Messenger::peer_id_display(&KEYS.get().unwrap().get_pubkey());
 
+    UI.get().unwrap().send_config(Config {
+        path: config.get_backup_path().await?,
+        client_id,
+        retention: Some(config.get_retention_policy().await?),
+        replication_factor: Some(config.get_replication_factor().await?),
+        erasure_coding: Some(config.get_erasure_coding().await?.unwrap_or_default()),
+    });
+
+    UI.get().unwrap().send_progress();
     UI.get()
         .unwrap()
-        .send_config(Config { path: config.get_backup_path().await?, client_id });
+        .send_interrupted_restore(config.get_interrupted_restore().await?);
 
-    UI.get().unwrap().send_progress();
+    Ok(())
+}
+
+/// Sends the list of snapshots to the client, along with the number of peers storing each of them.
+async fn send_snapshot_list() -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+    let target_redundancy = Redundancy::load().await?.target_redundancy();
+
+    let mut snapshots = Vec::new();
+    for snapshot in requests::snapshot_list().await? {
+        // a snapshot can only be restored if every packfile it might depend on is available, so
+        // it's only as redundant as the least redundant packfile sent before it was completed
+        let redundancy = config.get_min_packfile_replicas(snapshot.timestamp).await?;
+        snapshots.push(Snapshot {
+            hash: hex::encode(snapshot.snapshot_hash),
+            timestamp: snapshot.timestamp,
+            redundancy,
+            target_redundancy,
+        });
+    }
+
+    snapshots.sort_by_key(|s| -s.timestamp);
+    UI.get().unwrap().send_snapshots(snapshots);
+
+    Ok(())
+}
+
+/// Sends the contents of a directory inside a snapshot to the client.
+async fn send_snapshot_directory(snapshot: &str, path: &Path) -> anyhow::Result<()> {
+    let entries = browse::list_directory(browse::parse_snapshot_hash(snapshot)?, path).await?;
+
+    UI.get().unwrap().send_snapshot_directory(SnapshotDirectory {
+        snapshot: snapshot.to_string(),
+        path: path.to_path_buf(),
+        entries,
+    });
+
+    Ok(())
+}
+
+/// Sends the changes between two snapshots to the client.
+async fn send_snapshot_diff(old_snapshot: &str, new_snapshot: &str) -> anyhow::Result<()> {
+    let entries = browse::diff_snapshots(
+        browse::parse_snapshot_hash(old_snapshot)?,
+        browse::parse_snapshot_hash(new_snapshot)?,
+    )
+    .await?;
+
+    UI.get().unwrap().send_snapshot_diff(SnapshotDiff {
+        old_snapshot: old_snapshot.to_string(),
+        new_snapshot: new_snapshot.to_string(),
+        entries,
+    });
+
+    Ok(())
+}
+
+/// Sends the files and directories matching the pattern in any snapshot to the client.
+async fn send_search_results(pattern: &str) -> anyhow::Result<()> {
+    let (matches, truncated) = browse::search_snapshots(pattern).await?;
+
+    UI.get()
+        .unwrap()
+        .send_search_results(SearchResults { pattern: pattern.to_string(), matches, truncated });
+
+    Ok(())
+}
+
+/// Sends the list of all known peers to the client, with the storage exchanged with each of them.
+async fn send_peer_list() -> anyhow::Result<()> {
+    let config = CONFIG.get().unwrap();
+
+    let mut peers = Vec::new();
+    for info in config.get_peers(None).await? {
+        let state = config.get_peer_state(info.pubkey).await?;
+        let reliability = config.get_peer_reliability(info.pubkey).await?;
+        peers.push(Peer {
+            id: Messenger::peer_id_display(&info.pubkey),
+            state: state.as_ref().map_or(PeerState::Active, |state| state.state),
+            state_reason: state.map(|state| state.reason).filter(|reason| !reason.is_empty()),
+            health: reliability.health(),
+            score: reliability.score(),
+            negotiated: info.bytes_negotiated,
+            transmitted: info.bytes_transmitted,
+            received: info.bytes_received,
+            stored: config.get_peer_stored_size(info.pubkey).await?,
+            first_seen: info.first_seen,
+            last_seen: info.last_seen,
+        });
+    }
+
+    peers.sort_by_key(|peer| -peer.last_seen);
+    UI.get().unwrap().send_peers(peers);
+
+    Ok(())
+}
+
+/// Changes the state of a peer, and sends the updated list of peers to the client.
+async fn set_peer_state(peer: &str, state: PeerState, reason: &str) -> anyhow::Result<()> {
+    let peer_id = Messenger::parse_peer_id_display(peer)?;
+    CONFIG.get().unwrap().set_peer_state(peer_id, state, reason).await?;
+    log!("[peers] peer {} is now {:?}", peer, state);
+
+    send_peer_list().await
+}
+
+/// Makes a blocked peer active again, peers in other states can't be unblocked.
+async fn unblock_peer(peer: &str) -> anyhow::Result<()> {
+    let peer_id = Messenger::parse_peer_id_display(peer)?;
+    let state = CONFIG
+        .get()
+        .unwrap()
+        .get_peer_state(peer_id)
+        .await?
+        .map(|info| info.state);
+    if state != Some(PeerState::Blocked) {
+        bail!("peer {peer} is not blocked");
+    }
+
+    set_peer_state(peer, PeerState::Active, "").await
+}
+
+/// Marks a peer for evacuation, and starts replicating its data in the background.
+async fn evacuate_peer(peer: &str) -> anyhow::Result<()> {
+    set_peer_state(peer, PeerState::Evacuating, "evacuation requested by the user").await?;
+
+    tokio::spawn(async {
+        if let Err(e) = replicate::check_peers().await {
+            log!("[replicate] evacuating peers failed: {}", e);
+        }
+    });
 
     Ok(())
 }
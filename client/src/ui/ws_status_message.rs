@@ -1,12 +1,14 @@
 //! Handle sending status messages to the WebSocket clients.
 
 use std::{
-    collections::HashSet,
+    collections::HashMap,
+    path::PathBuf,
     str::from_utf8,
     sync::atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
     time::{SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::anyhow;
 use itertools::Itertools;
 use serde::Serialize;
 use shared::types::ClientId;
@@ -15,7 +17,15 @@ use tokio::sync::{
     Mutex,
 };
 
-use crate::{backup::BACKUP_ORCHESTRATOR, ui::ws_dispatcher::Config};
+use crate::{
+    backup::{
+        diff::DiffEntry, filesystem::TreeKind, restore::InterruptedRestore, search::SearchMatch,
+        BACKUP_ORCHESTRATOR,
+    },
+    config::{peers::PeerState, reliability::PeerHealth},
+    ui::ws_dispatcher::Config,
+    CONFIG,
+};
 
 /// Manage sending status messages to the WebSocket clients (the web user interface).
 #[derive(Debug)]
@@ -29,7 +39,7 @@ pub struct Messenger {
     backup_running: AtomicBool,
     restore_running: AtomicBool,
     pack_running: AtomicBool,
-    peers: Mutex<HashSet<ClientId>>,
+    peers: Mutex<HashMap<ClientId, PeerHealth>>,
 }
 
 #[derive(Clone, Serialize)]
@@ -38,10 +48,17 @@ pub enum StatusMessage {
     Message(String),
     Progress(Progress),
     Config(Config),
+    Snapshots(Vec<Snapshot>),
     BackupStarted,
     BackupFinished((bool, String)),
     RestoreStarted,
     RestoreFinished((bool, String)),
+    RestoreProgress(RestoreProgress),
+    InterruptedRestore(Option<InterruptedRestore>),
+    SnapshotDirectory(SnapshotDirectory),
+    SnapshotDiff(SnapshotDiff),
+    SearchResults(SearchResults),
+    Peers(Vec<Peer>),
     Panic(String),
 }
 
@@ -57,21 +74,108 @@ pub struct Progress {
     pack_running: bool,
     backup_running: bool,
     restore_running: bool,
-    peers: Option<Vec<String>>,
+    peers: Option<Vec<ContactedPeer>>,
+}
+
+/// A peer contacted during the backup, with its health when it was contacted.
+#[derive(Clone, Debug, Serialize)]
+pub struct ContactedPeer {
+    id: String,
+    health: PeerHealth,
 }
 
 #[derive(Clone, Debug, Default, Serialize)]
 pub struct BackupProgress {}
 
 #[derive(Clone, Debug, Default, Serialize)]
-pub struct RestoreProgress {}
+pub struct RestoreProgress {
+    pub peers: Vec<PeerRestoreProgress>,
+    pub bytes_received: u64,
+    /// Distinct packfiles received, out of the packfiles requested from all peers.
+    pub packfiles_received: u64,
+    pub packfiles_expected: u64,
+    /// Files restored or already present, out of all files to restore.
+    pub files_done: u64,
+    pub files_total: u64,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    /// The totals only grow until all directories of the snapshot have been walked.
+    pub total_known: bool,
+    pub eta_seconds: Option<u64>,
+}
 
 #[derive(Clone, Debug, Default, Serialize)]
+pub struct PeerRestoreProgress {
+    pub id: String,
+    pub status: String,
+    pub bytes_received: u64,
+    /// Packfiles and shards received from the peer, and the number it's known to store out of
+    /// those requested, zero if unknown.
+    pub packfiles_received: u64,
+    pub packfiles_expected: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Snapshot {
+    pub hash: String,
+    pub timestamp: i64,
+    /// The lowest number of peers storing any of the packfiles of the snapshot, if known. For erasure
+    /// coded packfiles, the number of peers that can be lost, plus one.
+    pub redundancy: Option<u32>,
+    pub target_redundancy: u32,
+}
+
+/// The contents of a directory inside a snapshot.
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotDirectory {
+    pub snapshot: String,
+    pub path: PathBuf,
+    pub entries: Vec<DirectoryEntry>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub kind: TreeKind,
+    pub size: Option<u64>,
+    /// The modification time, in seconds since the epoch.
+    pub mtime: Option<u64>,
+}
+
+/// The changes between two snapshots.
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotDiff {
+    pub old_snapshot: String,
+    pub new_snapshot: String,
+    pub entries: Vec<DiffEntry>,
+}
+
+/// Files and directories found across snapshots.
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchResults {
+    pub pattern: String,
+    pub matches: Vec<SearchMatch>,
+    /// Set if there were more matches than returned.
+    pub truncated: bool,
+}
+
+/// A peer, with the storage exchanged with it and its state.
+#[derive(Clone, Debug, Serialize)]
 pub struct Peer {
-    id: String,
-    transmitted: i64,
-    negotiated: i64,
-    connected: bool,
+    pub id: String,
+    pub state: PeerState,
+    /// Why the peer is in its state, if it's not active.
+    pub state_reason: Option<String>,
+    pub health: PeerHealth,
+    pub score: f64,
+    /// Storage negotiated with the peer, and the data exchanged with it.
+    pub negotiated: i64,
+    pub transmitted: i64,
+    pub received: i64,
+    /// The size of our packfiles and shards stored by the peer.
+    pub stored: u64,
+    pub first_seen: i64,
+    pub last_seen: i64,
 }
 
 impl Messenger {
@@ -100,7 +204,8 @@ impl Messenger {
     pub fn log(&self, msg: impl Into<String> + Clone) {
         // ignore sending errors because they are not very meaningful
         self.sender.send(StatusMessage::Message(msg.clone().into())).ok();
-        println!("[log] {}", msg.into());
+        // the standard output is kept free for exported archives
+        eprintln!("[log] {}", msg.into());
     }
 
     /// Send a panic (crash) message to the WebSocket clients.
@@ -110,8 +215,12 @@ impl Messenger {
 
     /// Add a peer to the list of peers that have been seen during the current backup.
     pub async fn progress_add_peer(&self, id: ClientId) {
-        let mut peers = self.peers.lock().await;
-        peers.insert(id);
+        let health = match CONFIG.get().unwrap().get_peer_reliability(id).await {
+            Ok(reliability) => reliability.health(),
+            Err(_) => PeerHealth::Fair,
+        };
+
+        self.peers.lock().await.insert(id, health);
     }
 
     /// Set the total number of files to be backed up.
@@ -132,7 +241,14 @@ impl Messenger {
 
         // this is a slightly more expensive operation, get updates from orchestrator at most every 250 ms
         if now - self.last_sent_peers.load(Relaxed) >= 250 {
-            peers = Some(self.peers.lock().await.iter().map(Self::peer_id_display).collect());
+            peers = Some(
+                self.peers
+                    .lock()
+                    .await
+                    .iter()
+                    .map(|(id, health)| ContactedPeer { id: Self::peer_id_display(id), health: *health })
+                    .collect(),
+            );
 
             self.last_sent_peers.store(now, Relaxed);
         }
@@ -171,6 +287,12 @@ impl Messenger {
             .join(":")
     }
 
+    /// Parse a peer id formatted by `peer_id_display`.
+    pub fn parse_peer_id_display(id: &str) -> anyhow::Result<ClientId> {
+        let bytes = hex::decode(id.replace(':', ""))?;
+        bytes[..].try_into().map_err(|_| anyhow!("invalid peer id {id}"))
+    }
+
     /// Send a progress update to the WebSocket clients.
     pub fn send_progress(&self) {
         if self.backup_running.load(Relaxed) {
@@ -233,6 +355,31 @@ impl Messenger {
         self.sender.send(StatusMessage::Config(config)).ok();
     }
 
+    /// Send the list of snapshots to the WebSocket clients.
+    pub fn send_snapshots(&self, snapshots: Vec<Snapshot>) {
+        self.sender.send(StatusMessage::Snapshots(snapshots)).ok();
+    }
+
+    /// Send the list of peers.
+    pub fn send_peers(&self, peers: Vec<Peer>) {
+        self.sender.send(StatusMessage::Peers(peers)).ok();
+    }
+
+    /// Send the changes between two snapshots.
+    pub fn send_snapshot_diff(&self, diff: SnapshotDiff) {
+        self.sender.send(StatusMessage::SnapshotDiff(diff)).ok();
+    }
+
+    /// Send the results of a search across snapshots.
+    pub fn send_search_results(&self, results: SearchResults) {
+        self.sender.send(StatusMessage::SearchResults(results)).ok();
+    }
+
+    /// Send the contents of a directory inside a snapshot.
+    pub fn send_snapshot_directory(&self, directory: SnapshotDirectory) {
+        self.sender.send(StatusMessage::SnapshotDirectory(directory)).ok();
+    }
+
     /// Set the pack running state.
     pub fn set_pack_running(&self, running: bool) {
         self.pack_running.store(running, Relaxed);
@@ -252,6 +399,16 @@ impl Messenger {
             .send(StatusMessage::RestoreFinished((success, msg.into())))
             .ok();
     }
+
+    /// Send the restore progress to the WebSocket clients.
+    pub fn send_restore_progress(&self, progress: RestoreProgress) {
+        self.sender.send(StatusMessage::RestoreProgress(progress)).ok();
+    }
+
+    /// Send the restore that can be resumed, if any, to the WebSocket clients.
+    pub fn send_interrupted_restore(&self, restore: Option<InterruptedRestore>) {
+        self.sender.send(StatusMessage::InterruptedRestore(restore)).ok();
+    }
 }
 
 #[macro_export]
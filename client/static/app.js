@@ -26,14 +26,45 @@ createApp({
             crash_message: "",
             restore_running: false,
             pack_running: false,
+            restore_path: "",
+            restore_destination: "",
+            restore_overwrite: "skip",
+            snapshots: [],
+            interrupted_restore: null,
+            restore_progress: null,
+            browser: null,
+            browser_loading: false,
+            diff: null,
+            diff_loading: false,
+            search_pattern: "",
+            search: null,
+            search_loading: false,
+            peer_list: [],
+            peer_list_loading: false,
             configuration: {
                 path: "",
-                client_id: ""
+                client_id: "",
+                replication_factor: 1,
+                erasure_coding: {
+                    data_shards: 0,
+                    parity_shards: 0
+                },
+                retention: {
+                    keep_last: 0,
+                    keep_daily: 0,
+                    keep_weekly: 0,
+                    keep_monthly: 0
+                }
             }
         }
     },
     computed: {
         percent() {
+            if (this.restore_running && this.restore_progress) {
+                if (this.restore_progress.bytes_total === 0) return 0;
+                return (this.restore_progress.bytes_done / this.restore_progress.bytes_total) * 100;
+            }
+
             if (this.total === 0) return 0;
 
             let base_files = (this.current / this.total);
@@ -53,6 +84,13 @@ createApp({
 
             return `${this.bytes_to_human(avg)}/s`
         },
+        restore_eta() {
+            let eta = this.restore_progress && this.restore_progress.eta_seconds;
+            if (eta === null || eta === undefined) return "estimating...";
+            if (eta < 60) return `${eta} s`;
+            if (eta < 3600) return `${Math.round(eta / 60)} min`;
+            return `${(eta / 3600).toFixed(1)} h`;
+        },
         data_to_send() {
             return this.bytes_to_human(Math.max(this.bytes_on_disk - this.bytes_transmitted), 0)
         }
@@ -71,6 +109,13 @@ createApp({
 
             return `${bytes.toFixed(unit > 2 ? 1 : 2)} ${units[unit]}`;
         },
+        health_class(health) {
+            return {
+                "text-bg-success": health === "Good",
+                "text-bg-warning": health === "Fair",
+                "text-bg-danger": health === "Poor"
+            };
+        },
         transfer_speed_bytes() {
             let timespan = Date.now() - this.bytes_sent_prev_time;
             let data_transferred = this.bytes_transmitted - this.bytes_sent_prev;
@@ -83,6 +128,86 @@ createApp({
                 }));
             }
         },
+        browse_snapshot(snapshot, path) {
+            if (this.socket) {
+                this.browser_loading = true;
+                this.socket.send(JSON.stringify({
+                    type: "BrowseSnapshot",
+                    data: {snapshot: snapshot, path: path}
+                }));
+            }
+        },
+        diff_snapshots(old_snapshot, new_snapshot) {
+            if (this.socket) {
+                this.diff_loading = true;
+                this.socket.send(JSON.stringify({
+                    type: "DiffSnapshots",
+                    data: {old_snapshot: old_snapshot, new_snapshot: new_snapshot}
+                }));
+            }
+        },
+        search_snapshots() {
+            if (this.socket && this.search_pattern.trim() !== "") {
+                this.search_loading = true;
+                this.socket.send(JSON.stringify({
+                    type: "SearchSnapshots",
+                    data: {pattern: this.search_pattern.trim()}
+                }));
+            }
+        },
+        size_delta(entry) {
+            let delta = (entry.new_size || 0) - (entry.old_size || 0);
+            return (delta < 0 ? "-" : "+") + this.bytes_to_human(Math.abs(delta));
+        },
+        browser_join(name) {
+            return this.browser.path ? `${this.browser.path}/${name}` : name;
+        },
+        browser_parent() {
+            let parts = this.browser.path.split("/");
+            parts.pop();
+            return parts.join("/");
+        },
+        download_url(name) {
+            let params = new URLSearchParams({snapshot: this.browser.snapshot, path: this.browser_join(name)});
+            return `/download?${params}`;
+        },
+        list_snapshots() {
+            if (this.socket) {
+                this.socket.send(JSON.stringify({
+                    type: "ListSnapshots"
+                }));
+            }
+        },
+        list_peers() {
+            if (this.socket) {
+                this.socket.send(JSON.stringify({
+                    type: "ListPeers"
+                }));
+                this.peer_list_loading = true;
+            }
+        },
+        set_peer_state(type, peer) {
+            if (this.socket) {
+                this.socket.send(JSON.stringify({
+                    type: type,
+                    data: {peer: peer}
+                }));
+                this.peer_list_loading = true;
+            }
+        },
+        block_peer(peer) {
+            if (confirm("No more data will be exchanged with this peer, the data it already stores is kept. Continue?")) {
+                this.set_peer_state("BlockPeer", peer);
+            }
+        },
+        unblock_peer(peer) {
+            this.set_peer_state("UnblockPeer", peer);
+        },
+        evacuate_peer(peer) {
+            if (confirm("The data stored by this peer will be replicated to other peers, and then deleted from it. Continue?")) {
+                this.set_peer_state("EvacuatePeer", peer);
+            }
+        },
         start_restore() {
             if (this.socket) {
                 if (this.configuration.path === "") {
@@ -94,8 +219,29 @@ createApp({
                     this.send_config();
                 }
 
+                let destination = this.restore_destination.trim() || this.configuration.path;
+                if ((this.restore_overwrite === "overwrite" || this.restore_overwrite === "overwrite_if_different")
+                    && !confirm(`Existing files in ${destination} may be overwritten by the restore. Continue?`)) {
+                    return;
+                }
+
                 this.socket.send(JSON.stringify({
-                    type: "StartRestore"
+                    type: "StartRestore",
+                    data: {
+                        path: this.restore_path.trim() || null,
+                        destination: this.restore_destination.trim() || null,
+                        overwrite: this.restore_overwrite
+                    }
+                }));
+
+                this.settings_editable = false;
+                this.starting = true;
+            }
+        },
+        resume_restore() {
+            if (this.socket) {
+                this.socket.send(JSON.stringify({
+                    type: "ResumeRestore"
                 }));
 
                 this.settings_editable = false;
@@ -110,6 +256,52 @@ createApp({
                 }));
             }
         },
+        forget_snapshots(dry_run) {
+            if (this.socket) {
+                if (this.settings_editable) {
+                    this.send_config();
+                }
+
+                if (!dry_run && !confirm("Snapshots not matching the retention policy will be permanently forgotten. Continue?")) {
+                    return;
+                }
+
+                this.socket.send(JSON.stringify({
+                    type: "ForgetSnapshots",
+                    data: { dry_run: dry_run }
+                }));
+            }
+        },
+        prune(dry_run) {
+            if (this.socket) {
+                if (!dry_run && !confirm("Packfiles not referenced by any snapshot will be deleted from peers. Continue?")) {
+                    return;
+                }
+
+                this.socket.send(JSON.stringify({
+                    type: "Prune",
+                    data: { dry_run: dry_run }
+                }));
+
+                this.settings_editable = false;
+                this.starting = true;
+            }
+        },
+        check_repository(deep) {
+            if (this.socket) {
+                if (deep && !confirm("The deep check fetches all backed up data from peers. Continue?")) {
+                    return;
+                }
+
+                this.socket.send(JSON.stringify({
+                    type: "CheckRepository",
+                    data: { deep: deep }
+                }));
+
+                this.settings_editable = false;
+                this.starting = true;
+            }
+        },
         start_backup() {
             if (this.socket) {
                 if (this.configuration.path === "") {
@@ -167,6 +359,13 @@ createApp({
                     this.logs += message["data"] + "\n";
                     let el = document.querySelector("#logs");
                     el.scrollTo(0, el.scrollHeight);
+
+                    // a failed request is only reported in the log
+                    if (message["data"].startsWith("error processing request")) {
+                        this.browser_loading = false;
+                        this.diff_loading = false;
+                        this.search_loading = false;
+                    }
                 } else if (message["type"] === "BackupFinished") {
                     this.last_success = message["data"][0];
                     this.finished_msg = message["data"][1];
@@ -177,6 +376,7 @@ createApp({
                     this.backup_running = false;
                     this.starting = false;
                     this.pack_running = false;
+                    this.list_snapshots();
                 } else if (message["type"] === "BackupStarted") {
                     this.bytes_transmitted = 0;
                     this.failed = 0;
@@ -189,10 +389,29 @@ createApp({
                     if (this.configuration.path) {
                         this.settings_editable = false;
                     }
+                } else if (message["type"] === "Snapshots") {
+                    this.snapshots = message["data"];
+                } else if (message["type"] === "SnapshotDirectory") {
+                    this.browser = message["data"];
+                    this.browser_loading = false;
+                } else if (message["type"] === "SnapshotDiff") {
+                    this.diff = message["data"];
+                    this.diff_loading = false;
+                } else if (message["type"] === "SearchResults") {
+                    this.search = message["data"];
+                    this.search_loading = false;
+                } else if (message["type"] === "Peers") {
+                    this.peer_list = message["data"];
+                    this.peer_list_loading = false;
+                } else if (message["type"] === "RestoreProgress") {
+                    this.restore_progress = message["data"];
+                } else if (message["type"] === "InterruptedRestore") {
+                    this.interrupted_restore = message["data"];
                 } else if (message["type"] === "Panic") {
                     this.crash_message = message["data"];
                     this.status = false;
                 } else if (message["type"] === "RestoreStarted") {
+                    this.restore_progress = null;
                     this.restore_running = true;
                     this.starting = false;
                 } else if (message["type"] === "RestoreFinished") {
@@ -212,6 +431,8 @@ createApp({
             this.socket.addEventListener('open', () => {
                 this.status = true;
                 this.get_config();
+                this.list_snapshots();
+                this.list_peers();
                 clearInterval(this.reconnctor);
             });
 
@@ -2,8 +2,14 @@
 
 use std::time::Duration;
 
-use shared::types::{BlobHash, ClientId};
-use sqlx::{postgres::PgPoolOptions, query, Executor, PgPool, Row};
+use shared::{
+    server_message::SnapshotInfo,
+    types::{BlobHash, ClientId, SnapshotId},
+};
+use sqlx::{
+    postgres::{PgPoolOptions, PgRow},
+    query, Executor, PgPool, Row,
+};
 
 use crate::handlers;
 
@@ -163,6 +169,60 @@ impl Database {
         Ok(())
     }
 
+    /// Get all snapshots of a certain client, newest first.
+    pub async fn get_client_snapshots(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Vec<SnapshotInfo>, handlers::Error> {
+        let rows = query(
+            "select id, snapshot_hash, extract(epoch from timestamp)::bigint from snapshots \
+                where client_pubkey = $1 order by timestamp desc",
+        )
+        .bind(client_id)
+        .fetch_all(&self.conn_pool)
+        .await?;
+
+        rows.iter().map(Self::row_to_snapshot_info).collect()
+    }
+
+    /// Remove the given snapshot records of a certain client, and return the records that matched.
+    /// If `dry_run` is set, nothing is removed, only the matching records are returned.
+    pub async fn forget_client_snapshots(
+        &self,
+        client_id: ClientId,
+        snapshots: &[SnapshotId],
+        dry_run: bool,
+    ) -> Result<Vec<SnapshotInfo>, handlers::Error> {
+        // only records belonging to the requesting client can ever be matched
+        let sql = if dry_run {
+            "select id, snapshot_hash, extract(epoch from timestamp)::bigint from snapshots \
+                where client_pubkey = $1 and id = any($2) order by timestamp desc"
+        } else {
+            "delete from snapshots where client_pubkey = $1 and id = any($2) \
+                returning id, snapshot_hash, extract(epoch from timestamp)::bigint"
+        };
+
+        let rows = query(sql)
+            .bind(client_id)
+            .bind(snapshots)
+            .fetch_all(&self.conn_pool)
+            .await?;
+
+        rows.iter().map(Self::row_to_snapshot_info).collect()
+    }
+
+    /// Converts a row with the snapshot id, hash and timestamp into a `SnapshotInfo`.
+    fn row_to_snapshot_info(row: &PgRow) -> Result<SnapshotInfo, handlers::Error> {
+        Ok(SnapshotInfo {
+            id: row.try_get(0)?,
+            snapshot_hash: row
+                .try_get::<Vec<u8>, usize>(1)?
+                .try_into()
+                .map_err(|_| handlers::Error::DatabaseTypeMismatch)?,
+            timestamp: row.try_get(2)?,
+        })
+    }
+
     /// Get the list of clients that have negotiated storage with a certain client.
     pub async fn get_client_negotiated_peers(
         &self,
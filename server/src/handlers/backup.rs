@@ -2,8 +2,8 @@
 
 use poem::{handler, web::Json};
 use shared::{
-    client_message::{BackupDone, BackupRestoreRequest},
-    server_message::{BackupRestoreInfo, ServerMessage},
+    client_message::{BackupDone, BackupRestoreRequest, SnapshotForgetRequest, SnapshotListRequest},
+    server_message::{BackupRestoreInfo, ServerMessage, SnapshotList, SnapshotsForgotten},
 };
 
 use crate::{handlers::Error, AUTH_MANAGER, DB};
@@ -48,3 +48,44 @@ pub async fn backup_restore(Json(request): Json<BackupRestoreRequest>) -> poem::
 
     Ok(Json(ServerMessage::BackupRestoreInfo(message)))
 }
+
+/// Handler for the snapshot list request.
+#[handler]
+pub async fn snapshot_list(Json(request): Json<SnapshotListRequest>) -> poem::Result<Json<ServerMessage>> {
+    let source_client_id = AUTH_MANAGER
+        .get()
+        .unwrap()
+        .get_session(request.session_token)
+        .ok_or(Error::Unauthorized)?;
+
+    let snapshots = DB.get().unwrap().get_client_snapshots(source_client_id).await?;
+
+    Ok(Json(ServerMessage::SnapshotList(SnapshotList { snapshots })))
+}
+
+/// Handler for the snapshot forget request.
+#[handler]
+pub async fn snapshot_forget(
+    Json(request): Json<SnapshotForgetRequest>,
+) -> poem::Result<Json<ServerMessage>> {
+    let source_client_id = AUTH_MANAGER
+        .get()
+        .unwrap()
+        .get_session(request.session_token)
+        .ok_or(Error::Unauthorized)?;
+
+    let snapshots = DB
+        .get()
+        .unwrap()
+        .forget_client_snapshots(source_client_id, &request.snapshots, request.dry_run)
+        .await?;
+
+    println!(
+        "[snapshots] {} {} snapshots of client {}",
+        if request.dry_run { "would forget" } else { "forgot" },
+        snapshots.len(),
+        hex::encode(source_client_id)
+    );
+
+    Ok(Json(ServerMessage::SnapshotsForgotten(SnapshotsForgotten { snapshots, dry_run: request.dry_run })))
+}
@@ -18,7 +18,7 @@ use crate::{
     client_auth_manager::ClientAuthManager,
     db::Database,
     handlers::{
-        backup::{backup_done, backup_restore},
+        backup::{backup_done, backup_restore, snapshot_forget, snapshot_list},
         backup_request::make_backup_request,
         login::{login_begin, login_complete},
         p2p_connection_request::{p2p_connection_begin, p2p_connection_confirm},
@@ -54,6 +54,8 @@ async fn main() {
         .at("/backups/request", make_backup_request)
         .at("/backups/done", backup_done)
         .at("/backups/restore", backup_restore)
+        .at("/backups/snapshots", snapshot_list)
+        .at("/backups/forget", snapshot_forget)
         .at("/p2p/connection/begin", p2p_connection_begin)
         .at("/p2p/connection/confirm", p2p_connection_confirm)
         .at("/ws", ws::handler);
@@ -2,7 +2,7 @@
 
 use serde::{Deserialize, Serialize};
 
-use crate::types::{BlobHash, ChallengeResponse, ClientId, SessionToken, TransportSessionNonce};
+use crate::types::{BlobHash, ChallengeResponse, ClientId, SessionToken, SnapshotId, TransportSessionNonce};
 
 /// The wrapper enum for all messages sent by clients.
 #[derive(Serialize, Deserialize)]
@@ -75,3 +75,17 @@ pub struct BackupDone {
     pub session_token: SessionToken,
     pub snapshot_hash: BlobHash,
 }
+
+/// The message sent by the client to request a list of all its snapshots.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotListRequest {
+    pub session_token: SessionToken,
+}
+
+/// The message sent by the client to remove snapshot records, optionally only as a dry run.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotForgetRequest {
+    pub session_token: SessionToken,
+    pub snapshots: Vec<SnapshotId>,
+    pub dry_run: bool,
+}
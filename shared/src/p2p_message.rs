@@ -31,11 +31,46 @@ pub struct EncapsulateRequestBody {
 }
 
 /// The request type for an initialization request message.
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[non_exhaustive]
 pub enum RequestType {
     Transport,
     RestoreAll,
+    RestoreSelected(RestoreSelectedRequest),
+    Delete(DeleteRequest),
+    Audit(AuditRequest),
+}
+
+/// Packfiles that the requesting peer wants to get back, optionally along with all index files.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RestoreSelectedRequest {
+    pub packfiles: Vec<PackfileId>,
+    pub include_index: bool,
+}
+
+/// Files that the requesting peer no longer needs, and that should be deleted.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeleteRequest {
+    pub packfiles: Vec<PackfileId>,
+    pub index_files: Vec<u32>,
+}
+
+/// A proof of storage challenge, asking the peer for a keyed hash of a byte range of a file it
+/// stores for us. The key is random and used only once, so the answer can't be computed in advance.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRequest {
+    pub file: FileInfo,
+    pub key: [u8; 32],
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// The body for an answer to an audit request, containing the standard header and the keyed hash,
+/// or `None` if the peer doesn't have the file.
+#[derive(Serialize, Deserialize)]
+pub struct AuditResponseBody {
+    pub header: Header,
+    pub proof: Option<[u8; 32]>,
 }
 
 /// The body for a file transport message, containing the standard header, file info, and file data.
@@ -47,10 +82,12 @@ pub struct EncapsulatedFileBody {
 }
 
 /// The type of file being transported, and its name.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub enum FileInfo {
     Packfile(PackfileId),
     Index(u32),
+    /// An erasure coded shard of a packfile, with the index of the shard.
+    PackfileShard(PackfileId, u8),
 }
 
 /// The body for an acknowledgement message, containing the standard header and the sequence number.
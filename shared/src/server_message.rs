@@ -2,7 +2,7 @@
 
 use serde::{Deserialize, Serialize};
 
-use crate::types::{BlobHash, ChallengeNonce, ClientId, SessionToken};
+use crate::types::{BlobHash, ChallengeNonce, ClientId, SessionToken, SnapshotId};
 
 /// The wrapper enum for all messages sent by the server over HTTP.
 #[derive(Serialize, Deserialize, Debug)]
@@ -13,6 +13,8 @@ pub enum ServerMessage {
     ClientLoginChallenge(ClientLoginChallenge),
     ClientLoginToken(ClientLoginToken),
     BackupRestoreInfo(BackupRestoreInfo),
+    SnapshotList(SnapshotList),
+    SnapshotsForgotten(SnapshotsForgotten),
 }
 
 /// The message sent by the server to as the second step of the client registration process.
@@ -40,6 +42,28 @@ pub struct BackupRestoreInfo {
     pub peers: Vec<ClientId>,
 }
 
+/// Information about a single snapshot record stored on the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotInfo {
+    pub id: SnapshotId,
+    pub snapshot_hash: BlobHash,
+    /// Unix timestamp of the time the backup was completed.
+    pub timestamp: i64,
+}
+
+/// The message sent by the server containing all snapshots of a client, newest first.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotList {
+    pub snapshots: Vec<SnapshotInfo>,
+}
+
+/// The message sent by the server containing the snapshots that were (or would be) removed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotsForgotten {
+    pub snapshots: Vec<SnapshotInfo>,
+    pub dry_run: bool,
+}
+
 /// Error types for server responses.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ErrorType {
@@ -30,6 +30,9 @@ pub const BLOB_NONCE_SIZE: usize = 12;
 /// The type for the hash of a blob.
 pub type BlobHash = [u8; 32];
 
+/// The type for the server-side identifier of a snapshot record.
+pub type SnapshotId = i64;
+
 /// The type for the hash of a packfile.
 pub type PackfileId = [u8; 12];
 